@@ -0,0 +1,60 @@
+//! Compares feeding a 64 KiB image to a `CommandDecoder` a byte at a time
+//! with `receive`, against handing over whole buffers with `read`, which
+//! copies runs without escape characters in one go.
+//!
+//! Run with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate tockloader_proto;
+
+use criterion::{Criterion, Throughput};
+use tockloader_proto::{pages, CommandDecoder};
+
+const IMAGE_LEN: usize = 64 * 1024;
+const PAGE_SIZE: usize = 512;
+
+/// A 64 KiB image, as `WritePage` frames, with an occasional 0xFC in the
+/// data.
+fn encoded_image() -> Vec<u8> {
+    let image: Vec<u8> = (0..IMAGE_LEN)
+        .map(|i| if i % 997 == 0 { 0xFC } else { (i % 251) as u8 })
+        .collect();
+    let mut bytes = Vec::new();
+    let mut frame = Vec::new();
+    for page in pages(&image, 0x0003_0000, PAGE_SIZE).unwrap() {
+        page.as_command().encode_into_vec(&mut frame).unwrap();
+        bytes.extend_from_slice(&frame);
+    }
+    bytes
+}
+
+fn decode(c: &mut Criterion) {
+    let bytes = encoded_image();
+    let mut group = c.benchmark_group("decode_64k_image");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("receive", |b| {
+        let mut p = CommandDecoder::new();
+        b.iter(|| {
+            let mut pages = 0;
+            for &ch in &bytes {
+                if let Ok(Some(_)) = p.receive(ch) {
+                    pages += 1;
+                }
+            }
+            assert_eq!(pages, IMAGE_LEN / PAGE_SIZE);
+        })
+    });
+    group.bench_function("read", |b| {
+        let mut p = CommandDecoder::new();
+        b.iter(|| {
+            let pages = core::cell::Cell::new(0);
+            p.read(&bytes, |_| pages.set(pages.get() + 1)).unwrap();
+            assert_eq!(pages.get(), IMAGE_LEN / PAGE_SIZE);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
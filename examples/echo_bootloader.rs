@@ -0,0 +1,210 @@
+//! A complete, if simple, bootloader, running against a simulated flash
+//! chip in RAM. It decodes commands with a `CommandDecoder`, hands them to
+//! some stub flash functions and encodes the replies with a
+//! `ResponseEncoder`. `main` then plays the part of tockloader, checking
+//! each reply as it goes.
+//!
+//! Run it with `cargo run --example echo_bootloader --features std`.
+
+extern crate tockloader_proto;
+
+use tockloader_proto::{Command, CommandDecoder, Error, Response, ResponseDecoder};
+
+/// Where our pretend flash starts.
+const FLASH_START: u32 = 0x0003_0000;
+/// How big a page is.
+const PAGE_SIZE: usize = 512;
+/// How many pages of flash we have.
+const NUM_PAGES: usize = 4;
+
+/// Some flash, which is really just RAM.
+struct Flash {
+    memory: Vec<u8>,
+}
+
+impl Flash {
+    fn new() -> Flash {
+        Flash {
+            memory: vec![0xFF; PAGE_SIZE * NUM_PAGES],
+        }
+    }
+
+    /// Turn an address into an offset into `memory`, if the range is all in
+    /// our flash.
+    fn offset(&self, address: u32, length: usize) -> Option<usize> {
+        let offset = address.checked_sub(FLASH_START)? as usize;
+        if offset + length <= self.memory.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn erase_page(&mut self, address: u32) -> Response<'static> {
+        match self.offset(address, PAGE_SIZE) {
+            Some(offset) if offset % PAGE_SIZE == 0 => {
+                for b in &mut self.memory[offset..offset + PAGE_SIZE] {
+                    *b = 0xFF;
+                }
+                Response::Ok
+            }
+            _ => Response::BadAddress,
+        }
+    }
+
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Response<'static> {
+        match self.offset(address, data.len()) {
+            Some(offset) if offset % PAGE_SIZE == 0 => {
+                self.memory[offset..offset + data.len()].copy_from_slice(data);
+                Response::Ok
+            }
+            _ => Response::BadAddress,
+        }
+    }
+
+    fn read_range(&self, address: u32, length: u16) -> Response<'_> {
+        match self.offset(address, length as usize) {
+            Some(offset) => Response::ReadRange {
+                data: &self.memory[offset..offset + length as usize],
+            },
+            None => Response::BadAddress,
+        }
+    }
+}
+
+/// The bootloader: bytes in, bytes out.
+struct Bootloader {
+    decoder: CommandDecoder,
+    flash: Flash,
+}
+
+impl Bootloader {
+    fn new() -> Bootloader {
+        Bootloader {
+            decoder: CommandDecoder::new(),
+            flash: Flash::new(),
+        }
+    }
+
+    /// Process some bytes from the UART, and give back any bytes we need to
+    /// send in reply.
+    fn process(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut reply = Vec::new();
+        for &ch in bytes {
+            let flash = &mut self.flash;
+            let response = match self.decoder.receive(ch) {
+                Ok(None) => continue,
+                Ok(Some(cmd)) => match cmd {
+                    Command::Ping => Response::Pong,
+                    Command::Info => Response::Info {
+                        info: b"echo bootloader",
+                    },
+                    // Nothing to reset, and no reply
+                    Command::Reset => continue,
+                    Command::ErasePage { address } => flash.erase_page(address),
+                    Command::WritePage { address, data } => flash.write_page(address, data),
+                    Command::ReadRange { address, length } => flash.read_range(address, length),
+                    _ => Response::Unknown,
+                },
+                Err(Error::Overflow) => Response::Overflow,
+                Err(Error::BadAddress) => Response::BadAddress,
+                Err(Error::UnsupportedBaud) => Response::ChangeBaudFail,
+                Err(Error::UnknownCommand) => Response::Unknown,
+                Err(_) => Response::BadArguments,
+            };
+            response.write_to(&mut reply).unwrap();
+        }
+        reply
+    }
+}
+
+/// Encode a command, as tockloader would.
+fn frame(cmd: &Command) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    cmd.write_to(&mut bytes).unwrap();
+    bytes
+}
+
+/// Decode the single response in `bytes`.
+fn decode<'d>(decoder: &'d mut ResponseDecoder, bytes: &[u8]) -> Response<'d> {
+    let (last, rest) = bytes.split_last().expect("no reply");
+    for &ch in rest {
+        assert_eq!(decoder.receive(ch), Ok(None));
+    }
+    decoder.receive(*last).unwrap().expect("incomplete reply")
+}
+
+/// Flash a page and read it back, checking each reply.
+fn walkthrough() {
+    let mut bootloader = Bootloader::new();
+    let mut host = ResponseDecoder::new();
+
+    let reply = bootloader.process(&frame(&Command::Ping));
+    assert_eq!(decode(&mut host, &reply), Response::Pong);
+
+    let reply = bootloader.process(&frame(&Command::Info));
+    assert_eq!(
+        decode(&mut host, &reply),
+        Response::Info {
+            info: b"echo bootloader",
+        }
+    );
+
+    // A reset gets no reply
+    assert!(bootloader.process(&frame(&Command::Reset)).is_empty());
+
+    let address = FLASH_START + PAGE_SIZE as u32;
+    let erase = Command::ErasePage {
+        address,
+    };
+    let reply = bootloader.process(&frame(&erase));
+    assert_eq!(decode(&mut host, &reply), Response::Ok);
+
+    // Include some escape characters, to check they survive the trip
+    let mut page = [0u8; PAGE_SIZE];
+    for (i, b) in page.iter_mut().enumerate() {
+        *b = (i % 253) as u8 + 3;
+    }
+    let write = Command::WritePage {
+        address,
+        data: &page,
+    };
+    let reply = bootloader.process(&frame(&write));
+    assert_eq!(decode(&mut host, &reply), Response::Ok);
+
+    let read = Command::ReadRange {
+        address,
+        length: PAGE_SIZE as u16,
+    };
+    let reply = bootloader.process(&frame(&read));
+    host.set_payload_len(PAGE_SIZE).unwrap();
+    assert_eq!(
+        decode(&mut host, &reply),
+        Response::ReadRange { data: &page[..] }
+    );
+
+    // Off the end of our flash
+    let erase = Command::ErasePage {
+        address: FLASH_START + (PAGE_SIZE * NUM_PAGES) as u32,
+    };
+    let reply = bootloader.process(&frame(&erase));
+    assert_eq!(decode(&mut host, &reply), Response::BadAddress);
+
+    // A GetAttr we don't support
+    let reply = bootloader.process(&frame(&Command::GetAttr { index: 0 }));
+    assert_eq!(decode(&mut host, &reply), Response::Unknown);
+
+    // A frame too short to be an ErasePage
+    let reply = bootloader.process(&[0x00, 0xFC, 0x06]);
+    assert_eq!(decode(&mut host, &reply), Response::BadArguments);
+}
+
+fn main() {
+    walkthrough();
+    println!("Bootloader walkthrough passed");
+}
+
+#[test]
+fn check_walkthrough() {
+    walkthrough();
+}
@@ -5,8 +5,12 @@
 //! protocol. This crate implements that protocol so
 //! that you can write future tockloader compatible bootloaders
 //! in Rust!
+//!
+//! There is no `unsafe` code in this crate, with any combination of
+//! features, and `#![forbid(unsafe_code)]` keeps it that way.
 
 #![no_std]
+#![forbid(unsafe_code)]
 
 // ****************************************************************************
 //
@@ -15,8 +19,22 @@
 // ****************************************************************************
 
 extern crate byteorder;
+#[cfg(feature = "std")]
+extern crate std;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(feature = "async")]
+extern crate futures_io;
+#[cfg(feature = "heapless")]
+extern crate heapless;
+#[cfg(feature = "zeroize")]
+extern crate zeroize;
+#[cfg(feature = "serialport")]
+extern crate serialport;
 
 use byteorder::{LittleEndian, ByteOrder};
+use core::borrow::Borrow;
+use core::convert::TryFrom;
 
 pub mod prelude {
     pub use super::Encoder;
@@ -41,7 +59,10 @@ pub enum Command<'a> {
     /// Get the Unique ID. Result is 8 bytes of unique ID (but I'm not sure
     /// what the result code should be).
     Id,
-    /// Reset all TX and RX buffers.
+    /// Reset all TX and RX buffers. This only resets the bootloader's
+    /// buffers, not the chip, so there is no mode byte - any payload is
+    /// ignored. A bootloader which needs to stay in the bootloader after a
+    /// chip reset has to arrange that itself (e.g. with an attribute).
     Reset,
     /// Erase a page. The RX buffer should contain the address of the start of
     /// the 512 byte page. Any non-page-aligned addresses will result in
@@ -58,16 +79,23 @@ pub enum Command<'a> {
     /// Write a page to ex flash. The RX buffer should contain the address of
     /// the start of the 256 byte page, followed by 256 bytes of page.
     WriteExPage { address: u32, data: &'a [u8] },
+    /// Write the start of a page, e.g. the tail of an image. The RX buffer
+    /// should contain the address of the start of the page, two bytes of
+    /// length, then that many (at most 512) bytes of data. This is not part
+    /// of the tockloader protocol, so only use it with bootloaders which
+    /// support it. Requires the `partial-page` feature.
+    #[cfg(feature = "partial-page")]
+    WritePagePartial { address: u32, len: u16, data: &'a [u8] },
     /// Get the length and CRC of the RX buffer. The response is two bytes of
     /// little endian length, followed by 4 bytes of crc32.
     CrcRxBuffer,
     /// Read a range from internal flash. The RX buffer should contain a 4
     /// byte address followed by 2 bytes of length. The response will be
-    /// length bytes long.
+    /// length bytes long. A length of zero is rejected with
+    /// `Error::BadArguments`, by both the encoder and the decoder.
     ReadRange { address: u32, length: u16 },
-    /// Read a range from external flash. The RX buffer should contain a 4
-    /// byte address followed by 2 bytes of length. The response will be
-    /// length bytes long.
+    /// Read a range from external flash. As for `ReadRange`, a length of
+    /// zero is rejected with `Error::BadArguments`.
     ExReadRange { address: u32, length: u16 },
     /// Write a payload attribute. The RX buffer should contain a one byte
     /// index, 8 bytes of key (null padded), one byte of value length, and
@@ -111,12 +139,96 @@ pub enum Command<'a> {
     /// the new baud rate. If the next command does not match this, the
     /// bootloader will revert to the old baud rate.
     ChangeBaud { mode: BaudMode, baud: u32 },
+    /// Set the address the bootloader will jump to when it starts the
+    /// application. The RX buffer should contain a 4 byte address. The
+    /// bootloader replies with RES_OK, or RES_BADADDR if it doesn't like the
+    /// address.
+    SetStartAddress { address: u32 },
+    /// Tell the bootloader which version of the protocol we speak. The RX
+    /// buffer should contain one byte of version. The bootloader replies
+    /// with `Response::Version` carrying its own version. This is not part
+    /// of the tockloader protocol - the `0x31` command byte is an extension,
+    /// so only use it with bootloaders which support it.
+    GetVersion { version: u8 },
+    /// A command byte we don't recognise. Only the command byte is kept -
+    /// whatever payload came before it is dropped with the frame. Only
+    /// produced with `UnknownPolicy::Report`.
+    Unknown { opcode: u8 },
+}
+
+/// An owned copy of a `Command`, for when it needs to outlive the
+/// `CommandDecoder` it came from. Requires the `std` feature.
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq, Clone)]
+pub enum CommandOwned {
+    Ping,
+    Info,
+    Id,
+    Reset,
+    ErasePage { address: u32 },
+    WritePage { address: u32, data: std::vec::Vec<u8> },
+    EraseExBlock { address: u32 },
+    WriteExPage { address: u32, data: std::vec::Vec<u8> },
+    #[cfg(feature = "partial-page")]
+    WritePagePartial { address: u32, len: u16, data: std::vec::Vec<u8> },
+    CrcRxBuffer,
+    ReadRange { address: u32, length: u16 },
+    ExReadRange { address: u32, length: u16 },
+    SetAttr {
+        index: u8,
+        key: std::vec::Vec<u8>,
+        value: std::vec::Vec<u8>,
+    },
+    GetAttr { index: u8 },
+    CrcIntFlash { address: u32, length: u32 },
+    CrcExtFlash { address: u32, length: u32 },
+    EraseExPage { address: u32 },
+    ExtFlashInit,
+    ClockOut,
+    WriteFlashUserPages { page1: u32, page2: u32 },
+    ChangeBaud { mode: BaudMode, baud: u32 },
+    SetStartAddress { address: u32 },
+    GetVersion { version: u8 },
+    /// As `Command::Unknown`, the payload is not kept.
+    Unknown { opcode: u8 },
+}
+
+/// Which sort of `Command` something is, without any of its fields. See
+/// `Command::kind` and `CommandDecoder::supported_commands`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CommandKind {
+    Ping,
+    Info,
+    Id,
+    Reset,
+    ErasePage,
+    WritePage,
+    EraseExBlock,
+    WriteExPage,
+    WritePagePartial,
+    CrcRxBuffer,
+    ReadRange,
+    ExReadRange,
+    SetAttr,
+    GetAttr,
+    CrcIntFlash,
+    CrcExtFlash,
+    EraseExPage,
+    ExtFlashInit,
+    ClockOut,
+    WriteFlashUserPages,
+    ChangeBaud,
+    SetStartAddress,
+    GetVersion,
+    Unknown,
 }
 
 /// Reponses supported by the protocol. A bootloader will encode these
 /// and a flash tool will decode them.
 #[derive(Debug, PartialEq)]
 pub enum Response<'a> {
+    /// The command didn't fit in the bootloader's receive buffer. Send this
+    /// when a `CommandDecoder` reports `Error::Overflow`.
     Overflow, // RES_OVERFLOW
     Pong, // RES_PONG
     BadAddress, // RES_BADADDR
@@ -134,9 +246,12 @@ pub enum Response<'a> {
     CrcExtFlash { crc: u32 }, // RES_CRCXF
     Info { info: &'a [u8] }, // RES_INFO
     ChangeBaudFail, // RES_CHANGE_BAUD_FAIL
+    /// The bootloader's protocol version, in reply to `Command::GetVersion`.
+    /// Also an extension.
+    Version { version: u8 }, // RES_VERSION
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Error {
     /// We got a command we didn't understand.
     UnknownCommand,
@@ -150,18 +265,218 @@ pub enum Error {
     SetLength,
     /// The buffer passed by the user wasn't large enough for the packet.
     BufferTooSmall,
+    /// The bytes ended before a complete frame was seen.
+    Incomplete,
+    /// In strict mode, more escapes in a row ended a frame without giving a
+    /// command than allowed, which suggests line noise.
+    TooManyEscapes,
+    /// In strict mode, a frame looked like the sender forgot to double an
+    /// escape character in the payload.
+    MissingEscape,
+    /// Reading from or writing to the underlying device failed. With `std`
+    /// this keeps the `io::ErrorKind`, so a timeout can be told from the
+    /// end of the stream.
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
+    /// Reading from or writing to the underlying device failed.
+    #[cfg(not(feature = "std"))]
+    Io,
+    /// A `ChangeBaud` asked for a baud rate not in the allowed list. A
+    /// bootloader should answer with `Response::ChangeBaudFail`.
+    UnsupportedBaud,
+    /// We got a command which this build of the crate can't decode, e.g. a
+    /// flash write without the `write` feature.
+    Unsupported,
+    /// The input ended straight after an escape character, so we never saw
+    /// the command byte.
+    DanglingEscape,
+    /// The payload was too long for the decoder's buffer, so the frame was
+    /// dropped. A bootloader should answer with `Response::Overflow`.
+    Overflow,
+    /// Too many bad frames in a row (see
+    /// `CommandDecoder::set_fault_threshold`), so the link is probably
+    /// broken. A bootloader might want to drop back to a safe state.
+    LinkFault,
+    /// In checksum mode, the CRC-16 at the end of the frame didn't match
+    /// the payload.
+    ChecksumMismatch,
+    /// A frame was longer than the limit set with
+    /// `CommandDecoder::set_max_frame_len`. The rest of it is ignored.
+    FrameTooLong,
+    /// An erase or write fell outside the flash given to
+    /// `CommandDecoder::set_flash_bounds`. A bootloader should answer with
+    /// `Response::BadAddress`. Also given by `pages` for an image which
+    /// would run past the end of the address space.
+    BadAddress,
+    /// We got a response we weren't waiting for, e.g. a second `Pong` in a
+    /// `PingSession`.
+    UnexpectedResponse,
+    /// A `WritePage` had an address but no data.
+    EmptyPage,
+    /// A `WritePage` didn't match the CRC given to
+    /// `CommandDecoder::expect_next_page_crc`.
+    PageCrcMismatch,
+    /// The command is one we can decode, but it isn't in the decoder's
+    /// `Profile`.
+    UnsupportedInProfile,
+    /// The stream ended part way through a frame, after `have` bytes of it
+    /// (counting any escape characters). See `CommandDecoder::end_of_stream`.
+    Truncated { have: usize },
+}
+
+/// What an `Unframer` found in the incoming bytes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FramedEvent {
+    /// A payload byte, with any escaping removed.
+    Literal(u8),
+    /// An escape followed by this command or response byte, which ends a
+    /// frame (commands) or starts one (responses).
+    Terminator(u8),
+}
+
+/// The `Unframer` removes the escaping from incoming bytes and tells you
+/// which are payload and which are command or response bytes.
+#[derive(Debug, Default, Clone)]
+pub struct Unframer {
+    escaped: bool,
 }
 
-/// The `ComandDecoder` takes bytes and gives you `Command`s.
+/// The `Framer` escapes outgoing bytes.
+#[derive(Debug, Default)]
+pub struct Framer;
+
+/// What `Encoder::write_counted` wrote.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EncodeStats {
+    /// Bytes written to the buffer, including escaping and framing.
+    pub framed_len: usize,
+    /// Payload bytes, before escaping.
+    pub payload_len: usize,
+    /// How many escape characters were added to the payload.
+    pub escape_count: usize,
+}
+
+/// The one or two bytes a `Framer` produced for a `FramedEvent`.
+#[derive(Debug)]
+pub struct FramedBytes {
+    bytes: [u8; 2],
+    len: usize,
+    pos: usize,
+}
+
+/// The `ComandDecoder` takes bytes and gives you `Command`s. Cloning one
+/// copies any part-received frame, so the clones carry on independently.
+#[derive(Debug, Clone)]
 pub struct CommandDecoder {
-    state: DecoderState,
-    buffer: [u8; 4224],
+    unframer: Unframer,
+    buffer: [u8; CMD_BUFFER_LEN],
     count: usize,
+    dirty: usize,
+    overflowed: bool,
+    completed: bool,
+    last_opcode: Option<u8>,
+    strict: bool,
+    max_escape_run: usize,
+    escape_run: usize,
+    page_size: Option<usize>,
+    allowed_bauds: Option<&'static [u32]>,
+    #[cfg(feature = "write")]
+    flash_bounds: Option<(u32, u32)>,
+    unknown_policy: UnknownPolicy,
+    profile: Profile,
+    tee: Option<fn(&[u8])>,
+    fault_threshold: Option<usize>,
+    bad_frames: usize,
+    max_frame_len: Option<usize>,
+    frame_len: usize,
+    discarding: bool,
+    #[cfg(feature = "seqnum")]
+    tag_len: usize,
+    #[cfg(feature = "crc")]
+    running_crc: u32,
+    #[cfg(feature = "crc")]
+    page_crc: Option<u32>,
+    #[cfg(feature = "crc")]
+    expected_page_crc: Option<u32>,
+    #[cfg(feature = "crc")]
+    checksum: bool,
+    #[cfg(feature = "trace")]
+    trace: TraceBuffer,
+    #[cfg(feature = "error-history")]
+    errors: ErrorHistory,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+}
+
+/// Borrows a `CommandDecoder` and resets it when dropped, so a partial
+/// frame can't leak from one session into the next. From
+/// `CommandDecoder::scoped`; use it just like the decoder.
+pub struct DecoderScope<'d> {
+    decoder: &'d mut CommandDecoder,
+}
+
+/// The part-received frame inside a `CommandDecoder`, so you can save it
+/// (e.g. before sleeping) and carry on later. See
+/// `CommandDecoder::into_parts` and `CommandDecoder::from_parts`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecoderParts {
+    /// The last byte was an escape character.
+    pub escaped: bool,
+    /// The payload received so far, with any escaping removed. Only the
+    /// first `len` bytes mean anything.
+    pub payload: [u8; CMD_BUFFER_LEN],
+    /// How many bytes of `payload` have been received.
+    pub len: usize,
+    /// The payload was too long, and some of it was dropped.
+    pub overflowed: bool,
+    /// How many bytes at the start of `payload` are a sequence number (see
+    /// `CommandDecoder::set_seqnum`).
+    pub tag_len: usize,
+    /// How many bytes of the frame have come off the wire, escapes and all,
+    /// for `CommandDecoder::set_max_frame_len`.
+    pub frame_len: usize,
+    /// The frame went over the maximum length, and the rest of it is being
+    /// skipped.
+    pub discarding: bool,
+}
+
+/// How many of each `Command` a `CommandDecoder` has decoded, plus how many
+/// errors it has reported. Requires the `stats` feature.
+#[cfg(feature = "stats")]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub ping: u32,
+    pub info: u32,
+    pub id: u32,
+    pub reset: u32,
+    pub erase_page: u32,
+    pub write_page: u32,
+    pub erase_ex_block: u32,
+    pub write_ex_page: u32,
+    #[cfg(feature = "partial-page")]
+    pub write_page_partial: u32,
+    pub crc_rx_buffer: u32,
+    pub read_range: u32,
+    pub ex_read_range: u32,
+    pub set_attr: u32,
+    pub get_attr: u32,
+    pub crc_int_flash: u32,
+    pub crc_ext_flash: u32,
+    pub erase_ex_page: u32,
+    pub ext_flash_init: u32,
+    pub clock_out: u32,
+    pub write_flash_user_pages: u32,
+    pub change_baud: u32,
+    pub set_start_address: u32,
+    pub get_version: u32,
+    pub unknown: u32,
+    pub errors: u32,
 }
 
 /// The `ResponseDecoder` takes bytes and gives you `Responses`s.
+#[derive(Debug, Clone)]
 pub struct ResponseDecoder {
-    state: DecoderState,
+    unframer: Unframer,
     buffer: [u8; 4224],
     count: usize,
     needed: Option<usize>,
@@ -174,6 +489,25 @@ pub struct CommandEncoder<'a> {
     sent_escape: bool,
 }
 
+/// A `Command` with the sequence number it was sent with. Requires the
+/// `seqnum` feature.
+#[cfg(feature = "seqnum")]
+#[derive(Debug, PartialEq)]
+pub struct Tagged<'a> {
+    pub seq: u8,
+    pub command: Command<'a>,
+}
+
+/// The `TaggedEncoder` takes a `Command` and a sequence number and gives you
+/// bytes, for a `CommandDecoder` with `set_seqnum(true)`. Requires the
+/// `seqnum` feature.
+#[cfg(feature = "seqnum")]
+pub struct TaggedEncoder<'a> {
+    seq: u8,
+    pos: usize,
+    inner: CommandEncoder<'a>,
+}
+
 /// The `ResponseEncoder` takes a `Response` and gives you bytes.
 pub struct ResponseEncoder<'a> {
     response: &'a Response<'a>,
@@ -181,6 +515,249 @@ pub struct ResponseEncoder<'a> {
     sent_escape: bool,
 }
 
+/// A bootloader's end of a serial link: reads `Command`s from a port and
+/// writes `Response`s back. The port is usually a `serialport::SerialPort`
+/// (see `SerialLink::open`), but anything that can `Read` and `Write` will
+/// do. Requires the `serialport` feature.
+#[cfg(feature = "serialport")]
+pub struct SerialLink<P> {
+    port: P,
+    decoder: CommandDecoder,
+}
+
+/// The `CommandStream` reads bytes from an `AsyncRead` and gives you a
+/// `Stream` of `CommandOwned`s. Requires the `async` feature.
+#[cfg(feature = "async")]
+pub struct CommandStream<R> {
+    reader: R,
+    decoder: CommandDecoder,
+    buffer: [u8; 64],
+    start: usize,
+    end: usize,
+}
+
+/// The commands in a run of bytes, from `CommandDecoder::receive_iter`.
+/// Each command borrows the decoder's buffer, so this can't be an
+/// `Iterator` - call `next_command` in a `while let` loop instead.
+pub struct Commands<'d, I> {
+    decoder: &'d mut CommandDecoder,
+    bytes: I,
+}
+
+/// The commands in a slice of bytes, from `CommandDecoder::drain`. As with
+/// `Commands`, a `WritePage` (or anything else with a payload) borrows the
+/// decoder's buffer, so only one command can be live at a time. Copy it
+/// into a `CommandOwned` if you need to keep it, or use
+/// `Commands::into_owned`.
+pub type Drain<'d, 'b> = Commands<'d, core::iter::Cloned<core::slice::Iter<'b, u8>>>;
+
+/// A simpler parser than a `CommandDecoder`, with a buffer of its own. It
+/// undoes the escaping, then hands each opcode and de-escaped payload to an
+/// `OpcodeDecoder` to turn into a command. Swap in your own `OpcodeDecoder`
+/// to mock or extend the command set. A `CommandDecoder` doesn't go through
+/// this, so none of its settings (strict mode, page sizes, sequence numbers,
+/// checksums and so on) apply here.
+#[derive(Debug, Clone)]
+pub struct FramedParser<D = TockloaderOpcodes> {
+    unframer: Unframer,
+    buffer: [u8; CMD_BUFFER_LEN],
+    count: usize,
+    overflowed: bool,
+    decoder: D,
+}
+
+/// The real tockloader commands, decoded from the payload the same way a
+/// `CommandDecoder` does, but without any of its checks. The default
+/// `OpcodeDecoder` for a `FramedParser`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TockloaderOpcodes;
+
+/// A copy of a decoded command's payload, without any sequence number or
+/// checksum, which doesn't borrow from the `CommandDecoder` and so can be
+/// passed through a queue. Use `as_command` to get the `Command` back.
+/// It holds a whole `COMMAND_BUFFER_LEN` payload, so each one is over
+/// 4 KiB with the `write` feature. Requires the `heapless` feature.
+#[cfg(feature = "heapless")]
+#[derive(Debug, Clone)]
+pub struct CommandBuf {
+    opcode: u8,
+    payload: [u8; CMD_BUFFER_LEN],
+    len: usize,
+}
+
+/// Splits a value too long for one attribute into `Command::SetAttr`s for
+/// consecutive attribute slots. Create one with `AttrChunks::new`.
+pub struct AttrChunks<'a> {
+    index: u8,
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+/// Reassembles a value split with `AttrChunks` from the values of the
+/// `Response::GetAttr`s for each slot, in slot order.
+pub struct AttrAssembler<'a> {
+    buffer: &'a mut [u8],
+    count: usize,
+}
+
+/// Collects the `Response::GetAttr`s for a set of attribute slots, e.g.
+/// after sending the commands from `get_attrs`, and keeps them by index.
+#[derive(Debug, Clone)]
+pub struct AttrTable {
+    slots: [Option<Attr>; ATTR_SLOTS],
+}
+
+/// One attribute, as stored in an `AttrTable`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attr {
+    key: [u8; KEY_LEN],
+    value: [u8; MAX_ATTR_LEN],
+    len: usize,
+}
+
+/// Checks a bootloader is alive: send the bytes from `request`, then feed
+/// whatever comes back to `feed_response` until it says the `Pong` has
+/// arrived.
+#[derive(Debug, Clone)]
+pub struct PingSession {
+    decoder: ResponseDecoder,
+    ponged: bool,
+}
+
+/// How a `PingSession` is getting on.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PingStatus {
+    /// Still waiting for the `Pong`.
+    Waiting,
+    /// Got the `Pong`.
+    Ready,
+}
+
+/// Asks a bootloader for its information string: send the bytes from
+/// `request`, then feed whatever comes back to `feed_response` until it
+/// gives you a `BootloaderInfo`.
+#[derive(Debug, Clone)]
+pub struct InfoSession {
+    decoder: ResponseDecoder,
+    done: bool,
+}
+
+/// A copy of the information string from a `Response::Info`, as returned
+/// by `InfoSession::feed_response`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BootloaderInfo {
+    info: [u8; MAX_INFO_LEN],
+    len: usize,
+}
+
+/// Why `flash_image` failed. Requires the `std` and `crc` features.
+#[cfg(all(feature = "std", feature = "crc"))]
+#[derive(Debug)]
+pub enum FlashError {
+    /// Reading from or writing to the device failed.
+    Io(std::io::Error),
+    /// We couldn't encode a command or decode a response.
+    Protocol(Error),
+    /// The device didn't answer `Response::Ok` to the `WritePage` for the
+    /// page at `address`.
+    Rejected { address: u32 },
+    /// The image was written, but reading back its CRC didn't match.
+    Verify(CrcMismatch),
+}
+
+/// Returned by `verify_crc` when the device's CRC doesn't match ours.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CrcMismatch {
+    /// The CRC we calculated.
+    pub local: u32,
+    /// The CRC the device sent, or `None` if the response wasn't a CRC.
+    pub remote: Option<u32>,
+}
+
+/// What a `CommandDecoder` does when a frame ends with a command byte it
+/// doesn't recognise. See `CommandDecoder::set_unknown_policy`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnknownPolicy {
+    /// Return `Ok(None)` and keep the payload, as if the escape and command
+    /// byte were never seen. This is the default.
+    Ignore,
+    /// Return `Error::UnknownCommand`.
+    Error,
+    /// Return `Command::Unknown`, so the caller can decide.
+    Report,
+}
+
+/// Which set of commands a bootloader implements. A `CommandDecoder` gives
+/// `Error::UnsupportedInProfile` for anything outside its profile. See
+/// `CommandDecoder::set_profile`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Profile {
+    /// Just enough to identify the bootloader and read from it: no writes,
+    /// erases or settings.
+    Minimal,
+    /// The minimal commands, plus those tockloader uses to program internal
+    /// flash.
+    Standard,
+    /// Everything we can decode. This is the default.
+    Full,
+}
+
+/// One byte seen by a `CommandDecoder`, as recorded in its trace. See
+/// `CommandDecoder::trace`. Requires the `trace` feature.
+#[cfg(feature = "trace")]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TraceEntry {
+    /// Whether the decoder was holding an escape character when the byte
+    /// arrived.
+    pub escaped: bool,
+    /// The byte itself.
+    pub byte: u8,
+    /// What the decoder did with it.
+    pub action: TraceAction,
+}
+
+/// An error from a `CommandDecoder`, as kept in its history. See
+/// `CommandDecoder::recent_errors`. Requires the `error-history` feature.
+#[cfg(feature = "error-history")]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ErrorRecord {
+    /// The error itself.
+    pub error: Error,
+    /// The command byte of the frame, if we got that far.
+    pub opcode: Option<u8>,
+    /// How many bytes into the frame the error was spotted, counting the
+    /// byte which caused it.
+    pub offset: usize,
+}
+
+/// What a `CommandDecoder` did with a byte. Requires the `trace` feature.
+#[cfg(feature = "trace")]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceAction {
+    /// Held on to an escape character, to see what comes next.
+    Escape,
+    /// Added a payload byte to the buffer.
+    Literal,
+    /// Ended a frame, which decoded to a command.
+    Command,
+    /// Ended a frame which was ignored, or skipped the byte entirely.
+    Ignored,
+    /// Gave an error.
+    Error,
+}
+
+/// Where the escape handling in a `CommandDecoder` is up to. See
+/// `CommandDecoder::state`. Requires the `test-internals` feature.
+#[cfg(feature = "test-internals")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecoderState {
+    /// Loading payload bytes.
+    Loading,
+    /// The last byte was an escape character, and the next one decides
+    /// what it meant.
+    Escape,
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum BaudMode {
     Set, // 0x01
@@ -193,7 +770,22 @@ pub enum BaudMode {
 //
 // ****************************************************************************
 
-// None
+/// The size of the buffer inside a `CommandDecoder`, which limits the
+/// longest payload it can decode. See `CommandDecoder::with_buffer`.
+pub const COMMAND_BUFFER_LEN: usize = CMD_BUFFER_LEN;
+
+/// The usual UART baud rates, for use with
+/// `CommandDecoder::set_allowed_bauds`.
+pub const STANDARD_BAUDS: &[u32] = &[
+    9600,
+    19200,
+    38400,
+    57600,
+    115200,
+    230400,
+    460800,
+    921600,
+];
 
 // ****************************************************************************
 //
@@ -201,11 +793,23 @@ pub enum BaudMode {
 //
 // ****************************************************************************
 
-enum DecoderState {
-    Loading,
-    Escape,
+/// The most recent `N` items pushed, dropping the oldest once full.
+#[cfg(any(feature = "trace", feature = "error-history"))]
+#[derive(Debug, Clone)]
+struct Ring<T, const N: usize> {
+    items: [T; N],
+    next: usize,
+    len: usize,
 }
 
+/// The most recent `ERROR_HISTORY_LEN` errors from a `CommandDecoder`.
+#[cfg(feature = "error-history")]
+type ErrorHistory = Ring<ErrorRecord, ERROR_HISTORY_LEN>;
+
+/// The most recent `TRACE_LEN` bytes a `CommandDecoder` has seen.
+#[cfg(feature = "trace")]
+type TraceBuffer = Ring<TraceEntry, TRACE_LEN>;
+
 // ****************************************************************************
 //
 // Private Data
@@ -234,6 +838,12 @@ const CMD_XFINIT: u8 = 0x18;
 const CMD_CLKOUT: u8 = 0x19;
 const CMD_WUSER: u8 = 0x20;
 const CMD_CHANGE_BAUD: u8 = 0x21;
+const CMD_SET_START_ADDRESS: u8 = 0x23;
+/// Not a tockloader command - see `Command::GetVersion`
+const CMD_GET_VERSION: u8 = 0x31;
+/// Not a tockloader command - see `Command::WritePagePartial`
+#[cfg(feature = "partial-page")]
+const CMD_WPAGE_PARTIAL: u8 = 0x30;
 
 const RES_OVERFLOW: u8 = 0x10;
 const RES_PONG: u8 = 0x11;
@@ -252,13 +862,127 @@ const RES_CRCIF: u8 = 0x23;
 const RES_CRCXF: u8 = 0x24;
 const RES_INFO: u8 = 0x25;
 const RES_CHANGE_BAUD_FAIL: u8 = 0x26;
+/// Not a tockloader response - see `Response::Version`
+const RES_VERSION: u8 = 0x31;
 
 const MAX_INDEX: u8 = 16;
+/// Attribute indices have to be less than this. The encoder's `MAX_INDEX`
+/// check has always let one more through.
+const ATTR_SLOTS: usize = 16;
 const KEY_LEN: usize = 8;
 const MAX_ATTR_LEN: usize = 55;
 const INT_PAGE_SIZE: usize = 512;
 const EXT_PAGE_SIZE: usize = 256;
 const MAX_INFO_LEN: usize = 192;
+const DEFAULT_MAX_ESCAPE_RUN: usize = 3;
+
+/// The commands this build can decode
+const SUPPORTED_COMMANDS: &[CommandKind] = &[
+    CommandKind::Ping,
+    CommandKind::Info,
+    CommandKind::Id,
+    CommandKind::Reset,
+    #[cfg(feature = "write")]
+    CommandKind::ErasePage,
+    #[cfg(feature = "write")]
+    CommandKind::WritePage,
+    #[cfg(feature = "write")]
+    CommandKind::EraseExBlock,
+    #[cfg(feature = "write")]
+    CommandKind::WriteExPage,
+    #[cfg(feature = "partial-page")]
+    CommandKind::WritePagePartial,
+    CommandKind::CrcRxBuffer,
+    CommandKind::ReadRange,
+    CommandKind::ExReadRange,
+    CommandKind::SetAttr,
+    CommandKind::GetAttr,
+    CommandKind::CrcIntFlash,
+    CommandKind::CrcExtFlash,
+    #[cfg(feature = "write")]
+    CommandKind::EraseExPage,
+    CommandKind::ExtFlashInit,
+    CommandKind::ClockOut,
+    #[cfg(feature = "write")]
+    CommandKind::WriteFlashUserPages,
+    CommandKind::ChangeBaud,
+    CommandKind::SetStartAddress,
+    CommandKind::GetVersion,
+];
+/// The commands in `Profile::Minimal`
+const MINIMAL_COMMANDS: &[CommandKind] = &[
+    CommandKind::Ping,
+    CommandKind::Info,
+    CommandKind::Id,
+    CommandKind::Reset,
+    CommandKind::CrcRxBuffer,
+    CommandKind::ReadRange,
+    CommandKind::GetAttr,
+    CommandKind::CrcIntFlash,
+    CommandKind::GetVersion,
+];
+/// The commands in `Profile::Standard`
+const STANDARD_COMMANDS: &[CommandKind] = &[
+    CommandKind::Ping,
+    CommandKind::Info,
+    CommandKind::Id,
+    CommandKind::Reset,
+    #[cfg(feature = "write")]
+    CommandKind::ErasePage,
+    #[cfg(feature = "write")]
+    CommandKind::WritePage,
+    CommandKind::CrcRxBuffer,
+    CommandKind::ReadRange,
+    CommandKind::SetAttr,
+    CommandKind::GetAttr,
+    CommandKind::CrcIntFlash,
+    CommandKind::ChangeBaud,
+    CommandKind::SetStartAddress,
+    CommandKind::GetVersion,
+];
+#[cfg(feature = "trace")]
+const TRACE_LEN: usize = 32;
+#[cfg(feature = "error-history")]
+const ERROR_HISTORY_LEN: usize = 8;
+
+/// Of the commands which don't write a page, `SetAttr` has the longest payload
+const MAX_SHORT_PAYLOAD: usize = 1 + KEY_LEN + 1 + MAX_ATTR_LEN;
+
+/// The reflected CRC-32 polynomial
+#[cfg(feature = "crc")]
+const CRC32_POLY: u32 = 0xEDB8_8320;
+#[cfg(feature = "crc")]
+const CRC32_INIT: u32 = 0xFFFF_FFFF;
+/// CRC-16/CCITT-FALSE, for checksum mode
+#[cfg(feature = "crc")]
+const CRC16_POLY: u16 = 0x1021;
+#[cfg(feature = "crc")]
+const CRC16_INIT: u16 = 0xFFFF;
+#[cfg(feature = "crc")]
+const CHECKSUM_LEN: usize = 2;
+
+/// Command byte and payload length, in front of a log record's payload
+const LOG_HEADER_LEN: usize = 3;
+
+/// A sequence number, when they're turned on, goes in front of the payload
+const MAX_TAG_LEN: usize = 1;
+
+/// A checksum, when checksum mode is on, goes after the payload
+#[cfg(feature = "crc")]
+const MAX_CHECKSUM_LEN: usize = CHECKSUM_LEN;
+#[cfg(not(feature = "crc"))]
+const MAX_CHECKSUM_LEN: usize = 0;
+
+#[cfg(feature = "write")]
+const CMD_BUFFER_LEN: usize = 4224;
+#[cfg(not(feature = "write"))]
+const CMD_BUFFER_LEN: usize = MAX_TAG_LEN + MAX_SHORT_PAYLOAD;
+
+// The buffer holds payload bytes after unescaping, so a page of nothing but
+// 0xFC (1024 bytes on the wire) still only needs the page plus its 4 byte
+// address, along with any sequence number and checksum.
+#[cfg(feature = "write")]
+const _: () = assert!(CMD_BUFFER_LEN >= MAX_TAG_LEN + INT_PAGE_SIZE + 4 + MAX_CHECKSUM_LEN);
 
 // ****************************************************************************
 //
@@ -266,2080 +990,7542 @@ const MAX_INFO_LEN: usize = 192;
 //
 // ****************************************************************************
 
+/// Turns an opcode and its de-escaped payload into a command, for a
+/// `FramedParser`.
+pub trait OpcodeDecoder {
+    /// What we decode to. May borrow the payload.
+    type Command<'b>;
+
+    /// Decode a frame. Return `Ok(None)` to ignore it.
+    fn decode<'b>(&mut self, opcode: u8, payload: &'b [u8]) -> Result<Option<Self::Command<'b>>, Error>;
+}
+
+impl OpcodeDecoder for TockloaderOpcodes {
+    type Command<'b> = Command<'b>;
+
+    fn decode<'b>(&mut self, opcode: u8, payload: &'b [u8]) -> Result<Option<Command<'b>>, Error> {
+        decode_command(opcode, payload)
+    }
+}
+
 pub trait Encoder: Iterator<Item = u8> {
     fn reset(&mut self);
 
     /// Encode to bytes, storing them in the given buffer.
     /// Returns the amount of buffer space used.
     fn write(&mut self, buffer: &mut [u8]) -> usize {
-        for i in 0..buffer.len() {
+        for (i, slot) in buffer.iter_mut().enumerate() {
             if let Some(ch) = self.next() {
                 // Copy over byte
-                buffer[i] = ch;
+                *slot = ch;
             } else {
                 // We're finished outputting bytes
                 return i;
             }
         }
         // Got to the end - whole buffer used
-        return buffer.len();
+        buffer.len()
+    }
+
+    /// Like `write`, but also says how much of the output was payload and
+    /// how much was escaping. The counts only cover this call, so give it a
+    /// buffer big enough for the whole frame.
+    fn write_counted(&mut self, buffer: &mut [u8]) -> EncodeStats {
+        let mut stats = EncodeStats::default();
+        let mut escaped = false;
+        for slot in buffer.iter_mut() {
+            let ch = match self.next() {
+                Some(ch) => ch,
+                None => break,
+            };
+            *slot = ch;
+            stats.framed_len += 1;
+            if escaped {
+                // Either an escaped payload byte or the command byte
+                if ch == ESCAPE_CHAR {
+                    stats.escape_count += 1;
+                    stats.payload_len += 1;
+                }
+                escaped = false;
+            } else if ch == ESCAPE_CHAR {
+                escaped = true;
+            } else {
+                stats.payload_len += 1;
+            }
+        }
+        stats
     }
 }
 
-impl CommandDecoder {
-    /// Create a new `CommandDecoder`.
-    ///
-    /// The decoder is fed bytes with the `receive` method.
-    pub fn new() -> CommandDecoder {
-        CommandDecoder {
-            state: DecoderState::Loading,
-            buffer: [0u8; 4224],
-            count: 0,
+impl<'a> Command<'a> {
+    /// Which sort of command this is.
+    pub fn kind(&self) -> CommandKind {
+        match *self {
+            Command::Ping => CommandKind::Ping,
+            Command::Info => CommandKind::Info,
+            Command::Id => CommandKind::Id,
+            Command::Reset => CommandKind::Reset,
+            Command::ErasePage { .. } => CommandKind::ErasePage,
+            Command::WritePage { .. } => CommandKind::WritePage,
+            Command::EraseExBlock { .. } => CommandKind::EraseExBlock,
+            Command::WriteExPage { .. } => CommandKind::WriteExPage,
+            #[cfg(feature = "partial-page")]
+            Command::WritePagePartial { .. } => CommandKind::WritePagePartial,
+            Command::CrcRxBuffer => CommandKind::CrcRxBuffer,
+            Command::ReadRange { .. } => CommandKind::ReadRange,
+            Command::ExReadRange { .. } => CommandKind::ExReadRange,
+            Command::SetAttr { .. } => CommandKind::SetAttr,
+            Command::GetAttr { .. } => CommandKind::GetAttr,
+            Command::CrcIntFlash { .. } => CommandKind::CrcIntFlash,
+            Command::CrcExtFlash { .. } => CommandKind::CrcExtFlash,
+            Command::EraseExPage { .. } => CommandKind::EraseExPage,
+            Command::ExtFlashInit => CommandKind::ExtFlashInit,
+            Command::ClockOut => CommandKind::ClockOut,
+            Command::WriteFlashUserPages { .. } => CommandKind::WriteFlashUserPages,
+            Command::ChangeBaud { .. } => CommandKind::ChangeBaud,
+            Command::SetStartAddress { .. } => CommandKind::SetStartAddress,
+            Command::GetVersion { .. } => CommandKind::GetVersion,
+            Command::Unknown { .. } => CommandKind::Unknown,
         }
     }
 
-    /// Decode a whole buffers worth of bytes.
-    ///
-    /// Due to lifetime problems, the decoded `Command`s are sent via `callback` rather
-    /// than being returned.
-    pub fn read<F>(&mut self, buffer: &[u8], callback: F) -> Result<(), Error>
-    where
-        F: Fn(&Command),
-    {
-        for ch in buffer {
-            match self.receive(*ch) {
-                Err(e) => return Err(e),
-                Ok(None) => {}
-                Ok(Some(ref cmd)) => callback(cmd),
-            }
+    /// The flash address this command carries, if it has one.
+    pub fn address(&self) -> Option<u32> {
+        match *self {
+            Command::ErasePage { address }
+            | Command::WritePage { address, .. }
+            | Command::EraseExBlock { address }
+            | Command::WriteExPage { address, .. }
+            | Command::ReadRange { address, .. }
+            | Command::ExReadRange { address, .. }
+            | Command::CrcIntFlash { address, .. }
+            | Command::CrcExtFlash { address, .. }
+            | Command::EraseExPage { address }
+            | Command::SetStartAddress { address } => Some(address),
+            #[cfg(feature = "partial-page")]
+            Command::WritePagePartial { address, .. } => Some(address),
+            _ => None,
         }
-        Ok(())
     }
 
-    /// Empty the RX buffer.
-    pub fn reset(&mut self) {
-        self.count = 0;
+    /// The address from `address` as the four bytes on the wire
+    /// (little-endian), e.g. for logging a frame exactly as it arrived.
+    pub fn address_bytes(&self) -> Option<[u8; 4]> {
+        self.address().map(u32::to_le_bytes)
     }
 
-    /// Process incoming bytes.
-    ///
-    /// The decoder is fed bytes with the `receive` method. If not enough
-    /// bytes have been seen, this function returns `None`. Once enough bytes
-    /// have been seen, it returns `Ok(Some(Command))` containing the decoded
-    /// Command. It returns `Err` if it doesn't like the byte received.
-    pub fn receive(&mut self, ch: u8) -> Result<Option<Command>, Error> {
-        match self.state {
-            DecoderState::Loading => self.handle_loading(ch),
-            DecoderState::Escape => self.handle_escape(ch),
+    /// The address from `address`, as a `usize` for comparing with flash
+    /// sizes and offsets. Gives `Error::BadAddress` if the address doesn't
+    /// fit, which can only happen on 16-bit targets.
+    pub fn address_usize(&self) -> Result<Option<usize>, Error> {
+        match self.address() {
+            Some(address) => usize::try_from(address)
+                .map(Some)
+                .map_err(|_| Error::BadAddress),
+            None => Ok(None),
         }
     }
 
-    fn load_char(&mut self, ch: u8) {
-        if self.count < self.buffer.len() {
-            self.buffer[self.count] = ch;
-            self.count = self.count + 1;
+    /// Carry on a `ReadRange` or `ExReadRange` after `consumed` bytes of it
+    /// have arrived, giving the read for the rest. Returns `None` if there's
+    /// nothing left to read, or if this isn't a read.
+    pub fn continue_read(&self, consumed: u16) -> Option<Command<'a>> {
+        let (address, length) = match *self {
+            Command::ReadRange { address, length } | Command::ExReadRange { address, length } => {
+                (address, length)
+            }
+            _ => return None,
+        };
+        let length = length.checked_sub(consumed).filter(|&n| n > 0)?;
+        let address = address.checked_add(consumed as u32)?;
+        match *self {
+            Command::ReadRange { .. } => Some(Command::ReadRange { address, length }),
+            _ => Some(Command::ExReadRange { address, length }),
         }
     }
 
-    fn handle_loading(&mut self, ch: u8) -> Result<Option<Command>, Error> {
-        if ch == ESCAPE_CHAR {
-            self.state = DecoderState::Escape;
-        } else {
-            self.load_char(ch);
+    /// Store this command in `out` as a log record, for tools that keep
+    /// logs of commands. This isn't the wire format: a record is the command
+    /// byte, then the payload length as a little-endian `u16`, then the
+    /// payload without any escaping. Returns the length of the record, or
+    /// `Error::BufferTooSmall` if it won't fit.
+    pub fn to_log_record(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut unframer = Unframer::new();
+        let mut len = LOG_HEADER_LEN;
+        for ch in CommandEncoder::new(self)? {
+            match unframer.push(ch) {
+                None => {}
+                Some(FramedEvent::Literal(ch)) => {
+                    *out.get_mut(len).ok_or(Error::BufferTooSmall)? = ch;
+                    len += 1;
+                }
+                Some(FramedEvent::Terminator(ch)) => {
+                    let header = out.get_mut(0..LOG_HEADER_LEN).ok_or(Error::BufferTooSmall)?;
+                    header[0] = ch;
+                    LittleEndian::write_u16(&mut header[1..], (len - LOG_HEADER_LEN) as u16);
+                }
+            }
         }
-        Ok(None)
+        Ok(len)
     }
 
-    fn handle_escape(&mut self, ch: u8) -> Result<Option<Command>, Error> {
-        self.state = DecoderState::Loading;
-        let result: Result<Option<Command>, Error> = match ch {
-            ESCAPE_CHAR => {
-                // Double escape means just load an escape
-                self.load_char(ch);
-                Ok(None)
-            }
-            CMD_PING => Ok(Some(Command::Ping)),
-            CMD_INFO => Ok(Some(Command::Info)),
-            CMD_ID => Ok(Some(Command::Id)),
-            CMD_RESET => Ok(Some(Command::Reset)),
-            CMD_EPAGE => {
-                let num_expected_bytes: usize = 4;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    Ok(Some(Command::ErasePage { address }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+    /// Get back a command stored by `to_log_record`. Returns
+    /// `Error::BadArguments` if the record is cut short.
+    pub fn from_log_record(record: &[u8]) -> Result<Command<'_>, Error> {
+        if record.len() < LOG_HEADER_LEN {
+            return Err(Error::BadArguments);
+        }
+        let opcode = record[0];
+        let len = LittleEndian::read_u16(&record[1..LOG_HEADER_LEN]) as usize;
+        let payload = record
+            .get(LOG_HEADER_LEN..LOG_HEADER_LEN + len)
+            .ok_or(Error::BadArguments)?;
+        Command::from_parts(opcode, payload)
+    }
+
+    /// Build a command from an opcode and its payload, for transports which
+    /// deliver them separately and already de-escaped. None of the framing,
+    /// or the `CommandDecoder` settings, apply. An opcode we don't recognise
+    /// gives `Command::Unknown`.
+    pub fn from_parts(opcode: u8, payload: &[u8]) -> Result<Command<'_>, Error> {
+        match decode_command(opcode, payload)? {
+            Some(cmd) => Ok(cmd),
+            None => Ok(Command::Unknown { opcode }),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Command<'a> {
+    /// Encode this command into `buf`, replacing whatever was there. Reuse
+    /// the same `Vec` when encoding lots of commands, to save allocating a
+    /// new one each time.
+    pub fn encode_into_vec(&self, buf: &mut std::vec::Vec<u8>) -> Result<(), Error> {
+        let encoder = CommandEncoder::new(self)?;
+        buf.clear();
+        buf.extend(encoder);
+        Ok(())
+    }
+
+    /// Encode this command and write the framed bytes to `w`, such as a
+    /// serial port or a file.
+    pub fn write_to<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let mut encoder = CommandEncoder::new(self).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad command arguments")
+        })?;
+        let mut buffer = [0u8; 64];
+        loop {
+            let used = encoder.write(&mut buffer);
+            if used == 0 {
+                return Ok(());
             }
-            CMD_WPAGE => {
-                // let num_expected_bytes: usize = INT_PAGE_SIZE + 4;
-                // if self.count == num_expected_bytes {
-                    let payload = &self.buffer[0..self.count];
-                    let address = LittleEndian::read_u32(&payload[0..4]);
-                    Ok(Some(Command::WritePage {
-                        address,
-                        data: &payload[4..],
-                    }))
-                // } else {
-                //     Err(Error::BadArguments)
-                // }
-            }
-            CMD_XEBLOCK => {
-                let num_expected_bytes: usize = 4;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    Ok(Some(Command::EraseExBlock { address }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            w.write_all(&buffer[0..used])?;
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Response<'a> {
+    /// Encode this response and write the framed bytes to `w`, such as a
+    /// serial port or a file.
+    pub fn write_to<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let mut encoder = ResponseEncoder::new(self).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad response arguments")
+        })?;
+        let mut buffer = [0u8; 64];
+        loop {
+            let used = encoder.write(&mut buffer);
+            if used == 0 {
+                return Ok(());
             }
-            CMD_XWPAGE => {
-                let num_expected_bytes: usize = EXT_PAGE_SIZE + 4;
-                if self.count == num_expected_bytes {
-                    let payload = &self.buffer[0..num_expected_bytes];
-                    let address = LittleEndian::read_u32(&payload[0..4]);
-                    Ok(Some(Command::WriteExPage {
-                        address,
-                        data: &payload[4..num_expected_bytes],
-                    }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            w.write_all(&buffer[0..used])?;
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, 'b> From<&'b Command<'a>> for CommandOwned {
+    fn from(command: &'b Command<'a>) -> CommandOwned {
+        match *command {
+            Command::Ping => CommandOwned::Ping,
+            Command::Info => CommandOwned::Info,
+            Command::Id => CommandOwned::Id,
+            Command::Reset => CommandOwned::Reset,
+            Command::ErasePage { address } => CommandOwned::ErasePage { address },
+            Command::WritePage { address, data } => CommandOwned::WritePage {
+                address,
+                data: data.to_vec(),
+            },
+            Command::EraseExBlock { address } => CommandOwned::EraseExBlock { address },
+            Command::WriteExPage { address, data } => CommandOwned::WriteExPage {
+                address,
+                data: data.to_vec(),
+            },
+            #[cfg(feature = "partial-page")]
+            Command::WritePagePartial { address, len, data } => CommandOwned::WritePagePartial {
+                address,
+                len,
+                data: data.to_vec(),
+            },
+            Command::CrcRxBuffer => CommandOwned::CrcRxBuffer,
+            Command::ReadRange { address, length } => CommandOwned::ReadRange { address, length },
+            Command::ExReadRange { address, length } => {
+                CommandOwned::ExReadRange { address, length }
             }
-            CMD_CRCRX => Ok(Some(Command::CrcRxBuffer)),
-            CMD_RRANGE => {
-                let num_expected_bytes: usize = 6;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u16(&self.buffer[4..6]);
-                    Ok(Some(Command::ReadRange { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            Command::SetAttr { index, key, value } => CommandOwned::SetAttr {
+                index,
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            Command::GetAttr { index } => CommandOwned::GetAttr { index },
+            Command::CrcIntFlash { address, length } => {
+                CommandOwned::CrcIntFlash { address, length }
             }
-            CMD_XRRANGE => {
-                let num_expected_bytes: usize = 6;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u16(&self.buffer[4..6]);
-                    Ok(Some(Command::ExReadRange { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            Command::CrcExtFlash { address, length } => {
+                CommandOwned::CrcExtFlash { address, length }
             }
-            CMD_SATTR => {
-                let num_expected_bytes: usize = 10;
-                if self.count >= num_expected_bytes {
-                    let index = self.buffer[0];
-                    let key = &self.buffer[1..9];
-                    let length = self.buffer[9] as usize;
-                    if self.count == (num_expected_bytes + length) {
-                        let value = &self.buffer[10..10 + length];
-                        Ok(Some(Command::SetAttr { index, key, value }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                } else {
-                    Err(Error::BadArguments)
-                }
+            Command::EraseExPage { address } => CommandOwned::EraseExPage { address },
+            Command::ExtFlashInit => CommandOwned::ExtFlashInit,
+            Command::ClockOut => CommandOwned::ClockOut,
+            Command::WriteFlashUserPages { page1, page2 } => {
+                CommandOwned::WriteFlashUserPages { page1, page2 }
             }
-            CMD_GATTR => {
-                let num_expected_bytes: usize = 1;
-                if self.count == num_expected_bytes {
-                    let index = self.buffer[0];
-                    Ok(Some(Command::GetAttr { index }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            Command::ChangeBaud { mode, baud } => CommandOwned::ChangeBaud { mode, baud },
+            Command::SetStartAddress { address } => CommandOwned::SetStartAddress { address },
+            Command::GetVersion { version } => CommandOwned::GetVersion { version },
+            Command::Unknown { opcode } => CommandOwned::Unknown { opcode },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl CommandOwned {
+    /// Borrow this as a `Command`, e.g. to encode it.
+    pub fn as_command(&self) -> Command<'_> {
+        match *self {
+            CommandOwned::Ping => Command::Ping,
+            CommandOwned::Info => Command::Info,
+            CommandOwned::Id => Command::Id,
+            CommandOwned::Reset => Command::Reset,
+            CommandOwned::ErasePage { address } => Command::ErasePage { address },
+            CommandOwned::WritePage { address, ref data } => Command::WritePage { address, data },
+            CommandOwned::EraseExBlock { address } => Command::EraseExBlock { address },
+            CommandOwned::WriteExPage { address, ref data } => {
+                Command::WriteExPage { address, data }
             }
-            CMD_CRCIF => {
-                let num_expected_bytes: usize = 8;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u32(&self.buffer[4..8]);
-                    Ok(Some(Command::CrcIntFlash { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            #[cfg(feature = "partial-page")]
+            CommandOwned::WritePagePartial { address, len, ref data } => {
+                Command::WritePagePartial { address, len, data }
             }
-            CMD_CRCEF => {
-                let num_expected_bytes: usize = 8;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let length = LittleEndian::read_u32(&self.buffer[4..8]);
-                    Ok(Some(Command::CrcExtFlash { address, length }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            CommandOwned::CrcRxBuffer => Command::CrcRxBuffer,
+            CommandOwned::ReadRange { address, length } => Command::ReadRange { address, length },
+            CommandOwned::ExReadRange { address, length } => {
+                Command::ExReadRange { address, length }
             }
-            CMD_XEPAGE => {
-                let num_expected_bytes: usize = 4;
-                if self.count == num_expected_bytes {
-                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
-                    Ok(Some(Command::EraseExPage { address }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            CommandOwned::SetAttr {
+                index,
+                ref key,
+                ref value,
+            } => Command::SetAttr { index, key, value },
+            CommandOwned::GetAttr { index } => Command::GetAttr { index },
+            CommandOwned::CrcIntFlash { address, length } => {
+                Command::CrcIntFlash { address, length }
             }
-            CMD_XFINIT => Ok(Some(Command::ExtFlashInit)),
-            CMD_CLKOUT => Ok(Some(Command::ClockOut)),
-            CMD_WUSER => {
-                let num_expected_bytes: usize = 8;
-                if self.count == num_expected_bytes {
-                    let page1 = LittleEndian::read_u32(&self.buffer[0..4]);
-                    let page2 = LittleEndian::read_u32(&self.buffer[4..8]);
-                    Ok(Some(Command::WriteFlashUserPages { page1, page2 }))
-                } else {
-                    Err(Error::BadArguments)
-                }
+            CommandOwned::CrcExtFlash { address, length } => {
+                Command::CrcExtFlash { address, length }
             }
-            CMD_CHANGE_BAUD => {
-                let num_expected_bytes: usize = 5;
-                if self.count == num_expected_bytes {
-                    let mode = self.buffer[0];
-                    let baud = LittleEndian::read_u32(&self.buffer[1..5]);
-                    match mode {
-                        0x01 => Ok(Some(Command::ChangeBaud {
-                            mode: BaudMode::Set,
-                            baud,
-                        })),
-                        0x02 => Ok(Some(Command::ChangeBaud {
-                            mode: BaudMode::Verify,
-                            baud,
-                        })),
-                        _ => Err(Error::BadArguments),
-
-                    }
-                } else {
-                    Err(Error::BadArguments)
-                }
+            CommandOwned::EraseExPage { address } => Command::EraseExPage { address },
+            CommandOwned::ExtFlashInit => Command::ExtFlashInit,
+            CommandOwned::ClockOut => Command::ClockOut,
+            CommandOwned::WriteFlashUserPages { page1, page2 } => {
+                Command::WriteFlashUserPages { page1, page2 }
             }
-            _ => Ok(None),
-        };
-        // A command or error signifies the end of the buffer
-        if let Ok(Some(_)) = result {
-            self.count = 0;
-        } else if let Err(_) = result {
-            self.count = 0;
+            CommandOwned::ChangeBaud { mode, baud } => Command::ChangeBaud { mode, baud },
+            CommandOwned::SetStartAddress { address } => Command::SetStartAddress { address },
+            CommandOwned::GetVersion { version } => Command::GetVersion { version },
+            CommandOwned::Unknown { opcode } => Command::Unknown { opcode },
         }
-        result
     }
 }
 
-impl ResponseDecoder {
-    /// Create a new `ResponseDecoder`.
-    ///
-    /// The decoder is fed bytes with the `receive` method.
-    pub fn new() -> ResponseDecoder {
-        ResponseDecoder {
-            state: DecoderState::Loading,
-            buffer: [0u8; 4224],
-            count: 0,
-            needed: None,
-        }
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
     }
+}
 
-    /// Decode a whole buffers worth of bytes.
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Find the frame boundaries in some captured bytes, without decoding
+/// anything. Yields the index of the escape character and the command (or
+/// response) byte following it, for each one. Doubled escape characters are
+/// payload, and so are skipped.
+pub fn frame_boundaries(bytes: &[u8]) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let mut unframer = Unframer::new();
+    bytes
+        .iter()
+        .enumerate()
+        .filter_map(move |(i, &ch)| match unframer.push(ch) {
+            Some(FramedEvent::Terminator(opcode)) => Some((i - 1, opcode)),
+            _ => None,
+        })
+}
+
+/// The `GetAttr` commands for reading every attribute slot in `indices`,
+/// e.g. `get_attrs(0..16)`. Put the responses in an `AttrTable`. Fails if
+/// the range goes past the last slot.
+pub fn get_attrs(
+    indices: core::ops::Range<u8>,
+) -> Result<impl Iterator<Item = Command<'static>>, Error> {
+    if indices.end as usize > ATTR_SLOTS {
+        return Err(Error::BadArguments);
+    }
+    Ok(indices.map(|index| Command::GetAttr { index }))
+}
+
+/// Split a firmware image into `WritePage` commands, starting at
+/// `base_addr`. The last page is padded with zeroes if the image doesn't
+/// fill it. The pages have to be copied to do the padding, so you get
+/// `CommandOwned`s. Gives `Error::BadArguments` unless `page_size` is 512,
+/// the only size of `WritePage` a `CommandEncoder` will send, and
+/// `Error::BadAddress` if the last page would start past the end of the
+/// 32-bit address space, rather than wrapping round to address zero.
+/// Requires the `std` feature.
+#[cfg(feature = "std")]
+pub fn pages(
+    image: &[u8],
+    base_addr: u32,
+    page_size: usize,
+) -> Result<impl Iterator<Item = CommandOwned> + '_, Error> {
+    if page_size != INT_PAGE_SIZE {
+        return Err(Error::BadArguments);
+    }
+    let last_page = image.len().saturating_sub(1) / page_size;
+    last_page
+        .checked_mul(page_size)
+        .and_then(|offset| u32::try_from(offset).ok())
+        .and_then(|offset| base_addr.checked_add(offset))
+        .ok_or(Error::BadAddress)?;
+    Ok(image.chunks(page_size).enumerate().map(move |(i, chunk)| {
+        let mut data = chunk.to_vec();
+        data.resize(page_size, 0);
+        CommandOwned::WritePage {
+            address: base_addr + (i * page_size) as u32,
+            data,
+        }
+    }))
+}
+
+/// Write `image` to internal flash at `base`, the way tockloader does:
+/// send a `WritePage` for each page (see `pages`), waiting for
+/// `Response::Ok` each time, then ask for the CRC of the pages written and
+/// check it. Commands go to `sink` and responses come from `source`,
+/// which are usually both the same serial port. Requires the `std` and
+/// `crc` features.
+#[cfg(all(feature = "std", feature = "crc"))]
+pub fn flash_image<W, R>(
+    image: &[u8],
+    base: u32,
+    sink: &mut W,
+    source: &mut R,
+) -> Result<(), FlashError>
+where
+    W: std::io::Write,
+    R: std::io::Read,
+{
+    let mut decoder = ResponseDecoder::new();
+    let mut written = std::vec::Vec::with_capacity(image.len() + INT_PAGE_SIZE);
+    for page in pages(image, base, INT_PAGE_SIZE)? {
+        let cmd = page.as_command();
+        cmd.write_to(sink)?;
+        sink.flush()?;
+        let ok = read_response(&mut decoder, source, |r| *r == Response::Ok)?;
+        if let Command::WritePage { address, data } = cmd {
+            if !ok {
+                return Err(FlashError::Rejected { address });
+            }
+            written.extend_from_slice(data);
+        }
+    }
+    let cmd = Command::CrcIntFlash {
+        address: base,
+        length: written.len() as u32,
+    };
+    cmd.write_to(sink)?;
+    sink.flush()?;
+    let local = crc32(&written);
+    read_response(&mut decoder, source, |r| verify_crc(local, r))?.map_err(FlashError::Verify)
+}
+
+/// Work out the CRC-16/CCITT-FALSE of `data`, which goes on the end of
+/// each frame in checksum mode (see `CommandDecoder::set_checksum`).
+/// Requires the `crc` feature.
+#[cfg(feature = "crc")]
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(CRC16_INIT, |crc, &b| crc16_update(crc, b))
+}
+
+/// Work out the CRC-32 of `data`, as used by zlib, Ethernet and so on.
+/// Requires the `crc` feature.
+#[cfg(feature = "crc")]
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(CRC32_INIT, |crc, &b| crc32_update(crc, b))
+}
+
+/// Check the CRC in a `Response::CrcIntFlash` or `Response::CrcExtFlash`
+/// against the one we calculated locally, e.g. after flashing an image.
+pub fn verify_crc(local: u32, response: &Response) -> Result<(), CrcMismatch> {
+    let remote = match *response {
+        Response::CrcIntFlash { crc } | Response::CrcExtFlash { crc } => crc,
+        _ => return Err(CrcMismatch { local, remote: None }),
+    };
+    if remote == local {
+        Ok(())
+    } else {
+        Err(CrcMismatch {
+            local,
+            remote: Some(remote),
+        })
+    }
+}
+
+impl core::fmt::Display for CrcMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.remote {
+            Some(remote) => write!(
+                f,
+                "CRC mismatch: expected 0x{:08x}, device sent 0x{:08x}",
+                self.local, remote
+            ),
+            None => write!(f, "expected CRC 0x{:08x}, device didn't send one", self.local),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CrcMismatch {}
+
+#[cfg(all(feature = "std", feature = "crc"))]
+impl core::fmt::Display for FlashError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            FlashError::Io(ref e) => write!(f, "I/O error: {}", e),
+            FlashError::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            FlashError::Rejected { address } => {
+                write!(f, "device rejected the page at 0x{:08x}", address)
+            }
+            FlashError::Verify(ref e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(all(feature = "std", feature = "crc"))]
+impl std::error::Error for FlashError {}
+
+#[cfg(all(feature = "std", feature = "crc"))]
+impl From<std::io::Error> for FlashError {
+    fn from(e: std::io::Error) -> FlashError {
+        FlashError::Io(e)
+    }
+}
+
+#[cfg(all(feature = "std", feature = "crc"))]
+impl From<Error> for FlashError {
+    fn from(e: Error) -> FlashError {
+        FlashError::Protocol(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        match e {
+            Error::Io(kind) => std::io::Error::from(kind),
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+impl Unframer {
+    /// Create a new `Unframer`.
+    pub fn new() -> Unframer {
+        Unframer { escaped: false }
+    }
+
+    /// Forget about any escape character we were holding on to.
+    pub fn reset(&mut self) {
+        self.escaped = false;
+    }
+
+    /// Process an incoming byte. Returns `None` if the byte was an escape
+    /// character and we need to see the next byte to know what it means.
+    pub fn push(&mut self, ch: u8) -> Option<FramedEvent> {
+        if self.escaped {
+            self.escaped = false;
+            if ch == ESCAPE_CHAR {
+                // Double escape means just load an escape
+                Some(FramedEvent::Literal(ch))
+            } else {
+                Some(FramedEvent::Terminator(ch))
+            }
+        } else if ch == ESCAPE_CHAR {
+            self.escaped = true;
+            None
+        } else {
+            Some(FramedEvent::Literal(ch))
+        }
+    }
+}
+
+impl Framer {
+    /// Create a new `Framer`.
+    pub fn new() -> Framer {
+        Framer
+    }
+
+    /// Get the bytes to send for `event`.
+    pub fn push(&mut self, event: FramedEvent) -> FramedBytes {
+        let (bytes, len) = match event {
+            FramedEvent::Literal(ESCAPE_CHAR) => ([ESCAPE_CHAR, ESCAPE_CHAR], 2),
+            FramedEvent::Literal(ch) => ([ch, 0], 1),
+            FramedEvent::Terminator(ch) => ([ESCAPE_CHAR, ch], 2),
+        };
+        FramedBytes { bytes, len, pos: 0 }
+    }
+}
+
+impl FramedBytes {
+    /// All of the bytes, whether or not they have been iterated over.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[0..self.len]
+    }
+}
+
+impl Iterator for FramedBytes {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.pos < self.len {
+            self.pos += 1;
+            Some(self.bytes[self.pos - 1])
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Command<'a> {
+    type Error = Error;
+
+    /// Decode exactly one framed command (payload, escape, command byte).
     ///
-    /// Due to lifetime problems, the decoded `Response`s are sent via `callback` rather
+    /// The returned `Command` borrows from `frame`, so the payload cannot
+    /// contain any escaped `0xFC` bytes - use a `CommandDecoder` for those.
+    fn try_from(frame: &'a [u8]) -> Result<Command<'a>, Error> {
+        let len = frame.len();
+        if len < 2 || frame[len - 2] != ESCAPE_CHAR {
+            return Err(Error::Incomplete);
+        }
+        let payload = &frame[0..len - 2];
+        if payload.contains(&ESCAPE_CHAR) {
+            return Err(Error::BadArguments);
+        }
+        match frame[len - 1] {
+            ESCAPE_CHAR => Err(Error::Incomplete),
+            opcode => match decode_command(opcode, payload)? {
+                Some(cmd) => Ok(cmd),
+                None => Err(Error::UnknownCommand),
+            },
+        }
+    }
+}
+
+impl CommandDecoder {
+    /// Create a new `CommandDecoder`.
+    ///
+    /// The decoder is fed bytes with the `receive` method.
+    pub fn new() -> CommandDecoder {
+        CommandDecoder::with_buffer([0u8; CMD_BUFFER_LEN])
+    }
+
+    /// Create a new `CommandDecoder` using `buffer`, whatever it contains,
+    /// rather than zeroing a new one. The decoder never reads a byte of the
+    /// buffer it hasn't written, so the contents don't matter. This saves
+    /// clearing `COMMAND_BUFFER_LEN` bytes if you already have a buffer to
+    /// hand over.
+    pub fn with_buffer(buffer: [u8; CMD_BUFFER_LEN]) -> CommandDecoder {
+        CommandDecoder {
+            unframer: Unframer::new(),
+            buffer,
+            count: 0,
+            dirty: 0,
+            overflowed: false,
+            completed: false,
+            last_opcode: None,
+            strict: false,
+            max_escape_run: DEFAULT_MAX_ESCAPE_RUN,
+            escape_run: 0,
+            page_size: None,
+            allowed_bauds: None,
+            #[cfg(feature = "write")]
+            flash_bounds: None,
+            unknown_policy: UnknownPolicy::Ignore,
+            profile: Profile::Full,
+            tee: None,
+            fault_threshold: None,
+            bad_frames: 0,
+            max_frame_len: None,
+            frame_len: 0,
+            discarding: false,
+            #[cfg(feature = "seqnum")]
+            tag_len: 0,
+            #[cfg(feature = "crc")]
+            running_crc: CRC32_INIT,
+            #[cfg(feature = "crc")]
+            page_crc: None,
+            #[cfg(feature = "crc")]
+            expected_page_crc: None,
+            #[cfg(feature = "crc")]
+            checksum: false,
+            #[cfg(feature = "trace")]
+            trace: TraceBuffer::empty(),
+            #[cfg(feature = "error-history")]
+            errors: ErrorHistory::empty(),
+            #[cfg(feature = "stats")]
+            stats: Stats::default(),
+        }
+    }
+
+    /// Rebuild a `CommandDecoder` from the state saved with `into_parts`.
+    /// Only the part-received frame is restored, along with whether it has
+    /// a sequence number - other settings like strict mode are back to
+    /// their defaults, so apply them again.
+    pub fn from_parts(parts: DecoderParts) -> CommandDecoder {
+        let mut decoder = CommandDecoder::new();
+        decoder.unframer.escaped = parts.escaped;
+        decoder.buffer = parts.payload;
+        decoder.count = parts.len.min(CMD_BUFFER_LEN);
+        decoder.dirty = CMD_BUFFER_LEN;
+        #[cfg(feature = "seqnum")]
+        {
+            decoder.tag_len = parts.tag_len.min(MAX_TAG_LEN);
+        }
+        #[cfg(feature = "crc")]
+        {
+            // As load_char would have worked it out
+            decoder.running_crc = decoder.buffer[0..decoder.count]
+                .iter()
+                .skip(4 + decoder.tag_len())
+                .fold(CRC32_INIT, |crc, &b| crc32_update(crc, b));
+        }
+        decoder.overflowed = parts.overflowed;
+        decoder.frame_len = parts.frame_len;
+        decoder.discarding = parts.discarding;
+        decoder
+    }
+
+    /// Take out the part-received frame, so it can be saved and given to
+    /// `from_parts` later.
+    pub fn into_parts(self) -> DecoderParts {
+        DecoderParts {
+            escaped: self.unframer.escaped,
+            payload: self.buffer,
+            len: self.count,
+            overflowed: self.overflowed,
+            tag_len: self.tag_len(),
+            frame_len: self.frame_len,
+            discarding: self.discarding,
+        }
+    }
+
+    /// Create a new `CommandDecoder` which only accepts the commands in
+    /// `profile`. See `set_profile`.
+    pub fn new_with_profile(profile: Profile) -> CommandDecoder {
+        let mut decoder = CommandDecoder::new();
+        decoder.set_profile(profile);
+        decoder
+    }
+
+    /// Create a new `CommandDecoder` which only accepts a `WritePage` if it
+    /// contains exactly `page_size` bytes of data. A plain `new` decoder
+    /// accepts pages of any length.
+    ///
+    /// Fails if the page won't fit in the decoder's buffer, leaving room for
+    /// a sequence number and checksum in case they're turned on later.
+    pub fn new_with_page_size(page_size: usize) -> Result<CommandDecoder, Error> {
+        let mut decoder = CommandDecoder::new();
+        page_size
+            .checked_add(MAX_TAG_LEN + 4 + MAX_CHECKSUM_LEN)
+            .filter(|&n| n <= decoder.buffer.len())
+            .ok_or(Error::BufferTooSmall)?;
+        decoder.page_size = Some(page_size);
+        Ok(decoder)
+    }
+
+    /// The number of commands decoded so far, by type.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Enable or disable strict mode. In strict mode the decoder reports
+    /// suspicious, but technically valid, byte sequences as errors.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Set how many escapes in a row may end a frame without giving a
+    /// command (e.g. with an unknown command byte) in strict mode before
+    /// `Error::TooManyEscapes` is returned. A doubled escape is a `0xFC` in
+    /// the payload, not an escape transition, so it doesn't count.
+    pub fn set_max_escape_run(&mut self, max: usize) {
+        self.max_escape_run = max;
+    }
+
+    /// Return `Error::LinkFault` instead of the usual error once
+    /// `threshold` frames in a row have been bad, and for every bad frame
+    /// after that until a good command arrives. `None` (the default) turns
+    /// this off.
+    pub fn set_fault_threshold(&mut self, threshold: Option<usize>) {
+        self.fault_threshold = threshold;
+        self.bad_frames = 0;
+    }
+
+    /// Limit how many bytes a frame can take on the wire, counting escape
+    /// characters and the command byte, to cap the time spent on any one
+    /// frame. A longer frame gives `Error::FrameTooLong` as soon as the
+    /// limit is passed, and the rest of it is ignored. `None` (the default)
+    /// only limits frames to the size of the buffer.
+    pub fn set_max_frame_len(&mut self, max: Option<usize>) {
+        self.max_frame_len = max;
+    }
+
+    /// Check `ErasePage`, `WritePage` and `WritePagePartial` against the
+    /// internal flash, given as `(base, size)`, and give `Error::BadAddress`
+    /// for any which don't fit inside it. An erase needs room for a whole
+    /// page. `None` (the default) doesn't check addresses at all. Requires
+    /// the `write` feature.
+    #[cfg(feature = "write")]
+    pub fn set_flash_bounds(&mut self, bounds: Option<(u32, u32)>) {
+        self.flash_bounds = bounds;
+    }
+
+    /// Decode a whole buffers worth of bytes.
+    ///
+    /// Due to lifetime problems, the decoded `Command`s are sent via `callback` rather
     /// than being returned.
+    ///
+    /// This is quicker than calling `receive` for each byte, as runs of
+    /// payload without escape characters are copied in one go.
     pub fn read<F>(&mut self, buffer: &[u8], callback: F) -> Result<(), Error>
     where
-        F: Fn(&Response),
+        F: Fn(&Command),
     {
-        for ch in buffer {
-            match self.receive(*ch) {
+        let mut i = 0;
+        while i < buffer.len() {
+            if self.can_load_run() {
+                let run = buffer[i..]
+                    .iter()
+                    .position(|&ch| ch == ESCAPE_CHAR)
+                    .unwrap_or(buffer.len() - i);
+                if run > 0 {
+                    self.load_run(&buffer[i..i + run]);
+                    i += run;
+                    continue;
+                }
+            }
+            match self.receive(buffer[i]) {
                 Err(e) => return Err(e),
                 Ok(None) => {}
                 Ok(Some(ref cmd)) => callback(cmd),
             }
+            i += 1;
         }
         Ok(())
     }
 
-    /// Empty the RX buffer.
+    /// The free part of our buffer, for a UART's DMA to write received bytes
+    /// into without copying them. Once it has written some, call
+    /// `dma_advance` before doing anything else with the decoder. Requires
+    /// the `dma` feature.
+    ///
+    /// The bytes go in exactly as they came off the wire, escapes and all.
+    /// `dma_advance` de-escapes them in place, and a frame's payload always
+    /// takes no more room than its escaped form, so nothing unprocessed is
+    /// overwritten. A big frame with lots of escapes may take several fills
+    /// to arrive, as the free space only grows back as the bytes are
+    /// processed.
+    ///
+    /// A payload which fills the buffer leaves no room for the rest of the
+    /// frame to arrive, so it is dropped and the frame gives
+    /// `Error::Overflow`, as if it had been one byte longer.
+    #[cfg(feature = "dma")]
+    pub fn dma_buffer(&mut self) -> &mut [u8] {
+        if self.count == self.buffer.len() {
+            self.overflowed = true;
+            self.count = 0;
+        }
+        &mut self.buffer[self.count..]
+    }
+
+    /// Process the `n` bytes written at the start of `dma_buffer`, calling
+    /// `callback` with each command. Gives `Error::BufferTooSmall` if `n`
+    /// is bigger than `dma_buffer` was. Otherwise, stops at the first error
+    /// and returns it, and the rest of the bytes are dropped. Requires the
+    /// `dma` feature.
+    #[cfg(feature = "dma")]
+    pub fn dma_advance<F>(&mut self, n: usize, mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(&Command),
+    {
+        let mut next = self.count;
+        let end = next.checked_add(n).filter(|&end| end <= self.buffer.len());
+        let end = end.ok_or(Error::BufferTooSmall)?;
+        // So a reset wipes them too
+        self.dirty = self.dirty.max(end);
+        while next < end {
+            // We only ever write at `count`, which never gets past `next`
+            debug_assert!(self.count <= next);
+            let ch = self.buffer[next];
+            next += 1;
+            match self.receive(ch) {
+                Err(e) => return Err(e),
+                Ok(None) => {}
+                Ok(Some(ref cmd)) => callback(cmd),
+            }
+        }
+        Ok(())
+    }
+
+    /// Empty the RX buffer. Any bytes received since the last reset are
+    /// overwritten with zeroes, so firmware or keys don't linger in RAM -
+    /// call this once you've finished with a decoded command to wipe it.
+    /// With the `zeroize` feature this uses the `zeroize` crate, so the
+    /// compiler can't optimise the writes away.
     pub fn reset(&mut self) {
+        wipe(&mut self.buffer[0..self.dirty]);
+        self.dirty = 0;
+        self.count = 0;
+        self.overflowed = false;
+    }
+
+    /// The commands this decoder can give you, e.g. for describing the
+    /// bootloader in an `Info` response. Without the `write` feature the
+    /// flash writing and erasing commands are missing, and
+    /// `WritePagePartial` needs the `partial-page` feature. `Unknown` is
+    /// never listed, whatever the `UnknownPolicy`. Only the commands in our
+    /// `Profile` are listed.
+    pub fn supported_commands(&self) -> &'static [CommandKind] {
+        self.profile.commands()
+    }
+
+    /// Borrow the decoder for a session. When the returned guard is
+    /// dropped, any partial frame (and pending escape) is thrown away, as
+    /// if you'd called `flush`.
+    pub fn scoped(&mut self) -> DecoderScope<'_> {
+        DecoderScope { decoder: self }
+    }
+
+    /// Did the bytes so far stop part way through a frame? Check this after
+    /// `read` to tell a slice which ended cleanly from a truncated one.
+    /// Unlike `flush`, this leaves the partial frame alone, so you can carry
+    /// on feeding bytes if there are more to come.
+    pub fn is_mid_frame(&self) -> bool {
+        self.unframer.escaped || self.count > 0 || self.overflowed
+    }
+
+    /// Call this when there are no more bytes to come. Reports
+    /// `Error::DanglingEscape` if the last byte was a lone escape character,
+    /// or `Error::Incomplete` if a partial payload was left over. Either
+    /// way, the decoder is then ready for a fresh stream.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let result = if self.unframer.escaped {
+            Err(Error::DanglingEscape)
+        } else if self.count > 0 || self.overflowed {
+            Err(Error::Incomplete)
+        } else {
+            Ok(())
+        };
+        self.unframer.reset();
+        self.reset();
+        self.frame_len = 0;
+        self.discarding = false;
+        result
+    }
+
+    /// Like `flush`, but says how much of a partial frame was left over, as
+    /// `Error::Truncated`. Call this when the transport closes. A command
+    /// which ended right at the end of the stream has already come out of
+    /// `receive`, so this is `Ok(())`.
+    pub fn end_of_stream(&mut self) -> Result<(), Error> {
+        let have = self.frame_len;
+        match self.flush() {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Error::Truncated { have }),
+        }
+    }
+
+    /// How many more payload bytes we expect before the command byte, if we
+    /// know.
+    ///
+    /// The command byte comes last, so until then we can only guess. Once
+    /// the payload is too long to be anything other than a page write, this
+    /// is the number of bytes left to complete a `WritePage` (of the
+    /// configured page size). A `WriteExPage` or `WritePagePartial` will
+    /// finish sooner. Otherwise (and always without the `write` feature)
+    /// this is `None`.
+    pub fn bytes_remaining(&self) -> Option<usize> {
+        #[cfg(feature = "write")]
+        {
+            let tag_len = self.tag_len();
+            if self.count > MAX_SHORT_PAYLOAD + tag_len {
+                let frame_len = tag_len + self.expected_page_size() + 4 + self.checksum_len();
+                return Some(frame_len.saturating_sub(self.count));
+            }
+        }
+        None
+    }
+
+    /// Throw away the payload received so far, e.g. because you've already
+    /// decided to reject the command it belongs to. The next command byte
+    /// will only see the payload bytes received after this call. Any
+    /// pending escape character is kept.
+    pub fn abort_payload(&mut self) {
         self.count = 0;
+        self.overflowed = false;
+    }
+
+    /// Render the bytes buffered so far for the current frame as hex, for
+    /// logging. The text is written into `out` (two characters per byte) and
+    /// the used portion is returned. If `out` is too small, the dump is
+    /// truncated.
+    pub fn frame_hex<'b>(&self, out: &'b mut [u8]) -> &'b str {
+        write_hex(&self.buffer[0..self.count], out)
+    }
+
+    /// Like `receive`, but errors just give `None`, for code written before
+    /// `receive` reported them. The decoder still recovers from the error
+    /// in the same way, so you only lose the reason the frame was bad.
+    pub fn receive_command(&mut self, ch: u8) -> Option<Command<'_>> {
+        self.receive(ch).unwrap_or(None)
     }
 
     /// Process incoming bytes.
     ///
     /// The decoder is fed bytes with the `receive` method. If not enough
     /// bytes have been seen, this function returns `None`. Once enough bytes
-    /// have been seen, it returns `Some(Response)` containing the
-    /// decoded Response.
-    pub fn receive(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        match self.state {
-            DecoderState::Loading => self.handle_loading(ch),
-            DecoderState::Escape => self.handle_escape(ch),
+    /// have been seen, it returns `Ok(Some(Command))` containing the decoded
+    /// Command. It returns `Err` if it doesn't like the byte received.
+    ///
+    /// After an `Err` the partial frame has been dropped and the decoder is
+    /// ready for the next one, so you can send an error response and carry
+    /// on feeding bytes.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        self.completed = false;
+        if let Some(tee) = self.tee {
+            tee(&[ch]);
         }
-    }
-
-    /// Set the expected length of an unbounded message. This
-    /// depends entirely on the last command you sent.
-    pub fn set_payload_len(&mut self, length: usize) -> Result<(), Error> {
-        match self.needed {
-            Some(_) => Err(Error::SetLength),
+        #[cfg(feature = "trace")]
+        let escaped = self.unframer.escaped;
+        let event = self.unframer.push(ch);
+        if self.discarding {
+            // Skip the rest of a frame which was too long
+            if let Some(FramedEvent::Terminator(_)) = event {
+                self.discarding = false;
+                self.frame_len = 0;
+            }
+            #[cfg(feature = "trace")]
+            self.trace.record(escaped, ch, TraceAction::Ignored);
+            return Ok(None);
+        }
+        self.frame_len = self.frame_len.saturating_add(1);
+        if self.max_frame_len.is_some_and(|n| self.frame_len > n) {
+            #[cfg(feature = "error-history")]
+            self.errors.push(ErrorRecord {
+                error: Error::FrameTooLong,
+                opcode: None,
+                offset: self.frame_len,
+            });
+            self.discarding = !matches!(event, Some(FramedEvent::Terminator(_)));
+            self.frame_len = 0;
+            self.reset();
+            #[cfg(feature = "stats")]
+            self.stats.record(&Err(Error::FrameTooLong));
+            #[cfg(feature = "trace")]
+            self.trace.record(escaped, ch, TraceAction::Error);
+            return Err(Error::FrameTooLong);
+        }
+        match event {
             None => {
-                self.needed = Some(length + 1);
-                Ok(())
+                debug_assert!(self.unframer.escaped, "escape not entered");
+                #[cfg(feature = "trace")]
+                self.trace.record(escaped, ch, TraceAction::Escape);
+                Ok(None)
+            }
+            Some(FramedEvent::Literal(ch)) => {
+                debug_assert!(!self.unframer.escaped);
+                self.load_char(ch);
+                #[cfg(feature = "trace")]
+                self.trace.record(escaped, ch, TraceAction::Literal);
+                Ok(None)
+            }
+            Some(FramedEvent::Terminator(ch)) => {
+                debug_assert!(!self.unframer.escaped, "terminator left us escaped");
+                self.handle_command(ch)
             }
         }
     }
 
-    fn load_char(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        if self.count < self.buffer.len() {
-            self.buffer[self.count] = ch;
-            self.count = self.count + 1;
+    /// Expect every frame to start with a one byte sequence number, before
+    /// the usual payload. Use `receive_tagged` to see it. This is not part
+    /// of the tockloader protocol, so only turn it on if both ends support
+    /// it. Requires the `seqnum` feature.
+    #[cfg(feature = "seqnum")]
+    pub fn set_seqnum(&mut self, enabled: bool) {
+        self.tag_len = if enabled { 1 } else { 0 };
+    }
+
+    /// Expect every frame to end with a CRC-16 (see `crc16`) of everything
+    /// before it, little-endian, just before the escape and command byte.
+    /// It's checked and stripped before the command is decoded, and a bad
+    /// one gives `Error::ChecksumMismatch`. This is not part of the
+    /// tockloader protocol, so only turn it on if both ends support it.
+    /// Requires the `crc` feature.
+    #[cfg(feature = "crc")]
+    pub fn set_checksum(&mut self, enabled: bool) {
+        self.checksum = enabled;
+    }
+
+    /// The same as `receive`, but also gives you the command's sequence
+    /// number. Returns `Error::Unsupported` unless `set_seqnum` has turned
+    /// sequence numbers on. Requires the `seqnum` feature.
+    #[cfg(feature = "seqnum")]
+    pub fn receive_tagged(&mut self, ch: u8) -> Result<Option<Tagged<'_>>, Error> {
+        if self.tag_len == 0 {
+            return Err(Error::Unsupported);
         }
-        if self.needed == Some(self.count) {
-            let result = match self.buffer[0] {
-                RES_CRCRX => {
-                    let length = LittleEndian::read_u16(&self.buffer[1..3]);
-                    let crc = LittleEndian::read_u32(&self.buffer[3..7]);
-                    Ok(Some(Response::CrcRxBuffer { length, crc }))
-                }
-                RES_RRANGE => {
-                    let data = &self.buffer[1..self.count];
-                    Ok(Some(Response::ReadRange { data }))
-                }
-                RES_XRRANGE => {
-                    let data = &self.buffer[1..self.count];
-                    Ok(Some(Response::ExReadRange { data }))
-                }
-                RES_GATTR => {
-                    let key = &self.buffer[1..9];
-                    let length = self.buffer[9] as usize;
-                    if (9 + length) <= self.count {
-                        let value = &self.buffer[10..(10 + length)];
-                        Ok(Some(Response::GetAttr { key, value }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                }
-                RES_CRCIF => {
-                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
-                    Ok(Some(Response::CrcIntFlash { crc }))
-                }
-                RES_CRCXF => {
-                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
-                    Ok(Some(Response::CrcExtFlash { crc }))
-                }
-                RES_INFO => {
-                    let length: usize = self.buffer[1] as usize;
-                    if length + 1 < self.count {
-                        let info = &self.buffer[2..length + 2];
-                        Ok(Some(Response::Info { info }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                }
-                _ => Err(Error::UnknownCommand),
-            };
-            self.needed = None;
-            self.count = 0;
-            result
-        } else {
-            Ok(None)
+        // The command borrows us, so look now - but only at bytes of this
+        // frame. Any command has its tag in there.
+        let seq = self.buffer[0..self.count].first().copied();
+        match self.receive(ch)? {
+            Some(command) => Ok(seq.map(|seq| Tagged { seq, command })),
+            None => Ok(None),
         }
     }
 
-    fn handle_loading(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        if ch == ESCAPE_CHAR {
-            self.state = DecoderState::Escape;
-            Ok(None)
+    /// Did the last byte given to `receive` complete a command? Handy when
+    /// the command went to a callback rather than being returned to you.
+    pub fn just_completed(&self) -> bool {
+        self.completed
+    }
+
+    /// The last few bytes received and what became of them, oldest first,
+    /// for working out what went wrong after the fact. Only the most recent
+    /// 32 are kept. Requires the `trace` feature.
+    #[cfg(feature = "trace")]
+    pub fn trace(&self) -> impl Iterator<Item = TraceEntry> + '_ {
+        self.trace.iter()
+    }
+
+    /// The last few errors, oldest first, with the command byte and how far
+    /// into the frame each one happened, for diagnosing a flaky link in the
+    /// field. Only the most recent 8 are kept. Requires the `error-history`
+    /// feature.
+    #[cfg(feature = "error-history")]
+    pub fn recent_errors(&self) -> impl Iterator<Item = ErrorRecord> + '_ {
+        self.errors.iter()
+    }
+
+    /// Where the escape handling is up to, for white-box tests. Requires the
+    /// `test-internals` feature.
+    #[cfg(feature = "test-internals")]
+    pub fn state(&self) -> DecoderState {
+        if self.unframer.escaped {
+            DecoderState::Escape
         } else {
-            self.load_char(ch)
+            DecoderState::Loading
         }
     }
 
-    fn handle_escape(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        self.state = DecoderState::Loading;
-        match ch {
-            ESCAPE_CHAR => {
-                // Double escape means just load an escape
-                self.load_char(ch)
-            }
-            RES_PONG => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Pong))
-            }
-            RES_OVERFLOW => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Overflow))
-            }
-            RES_BADADDR => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::BadAddress))
-            }
-            RES_INTERROR => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::InternalError))
-            }
-            RES_BADARGS => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::BadArguments))
-            }
-            RES_OK => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Ok))
-            }
-            RES_UNKNOWN => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Unknown))
-            }
-            RES_XFTIMEOUT => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::ExtFlashTimeout))
-            }
-            RES_XFEPE => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::ExtFlashPageError))
-            }
-            RES_CHANGE_BAUD_FAIL => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::ChangeBaudFail))
-            }
-            RES_CRCRX => {
-                self.set_payload_len(6)?;
-                self.load_char(ch)?;
-                Ok(None)
-            }
-            RES_RRANGE => {
-                if self.needed.is_none() {
-                    Err(Error::UnsetLength)
-                } else {
-                    self.load_char(ch)?;
-                    Ok(None)
+    /// How many payload bytes are in the buffer, for white-box tests.
+    /// Requires the `test-internals` feature.
+    #[cfg(feature = "test-internals")]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The command byte which ended the last frame, whether it decoded,
+    /// failed or was ignored. Handy for working out which command an error
+    /// belongs to. `None` until the first frame ends.
+    pub fn last_opcode(&self) -> Option<u8> {
+        self.last_opcode
+    }
+
+    /// The same as `receive`, but takes anything which borrows as a `u8`,
+    /// so you can pass `&u8`s straight from `bytes.iter()`.
+    pub fn receive_ref<B: Borrow<u8>>(&mut self, ch: B) -> Result<Option<Command<'_>>, Error> {
+        self.receive(*ch.borrow())
+    }
+
+    /// Decode the frame at the start of `input` without copying it, so any
+    /// data in the `Command` points into `input` rather than our buffer
+    /// (e.g. a DMA buffer). Returns how many bytes of `input` were used,
+    /// which is zero if `input` doesn't start with a complete frame.
+    ///
+    /// This doesn't touch any frame part-received with `receive`. A frame
+    /// whose payload contains an escaped `0xFC` can't be decoded in place
+    /// and gives `Error::Unsupported` - feed those bytes to `receive`
+    /// instead. An unknown command byte gives `Error::UnknownCommand`. Any
+    /// sequence number and checksum are checked and stripped, as for
+    /// `receive`.
+    pub fn receive_in_place<'b>(
+        &mut self,
+        input: &'b [u8],
+    ) -> (usize, Result<Option<Command<'b>>, Error>) {
+        let mut i = 0;
+        while i + 1 < input.len() {
+            if input[i] == ESCAPE_CHAR {
+                if input[i + 1] == ESCAPE_CHAR {
+                    return (0, Err(Error::Unsupported));
                 }
-            }
-            RES_XRRANGE => {
-                if self.needed.is_none() {
-                    Err(Error::UnsetLength)
-                } else {
-                    self.load_char(ch)?;
-                    Ok(None)
+                let frame = &input[0..i];
+                let result = match self
+                    .frame_payload(frame)
+                    .and_then(|range| self.decode_payload(input[i + 1], &frame[range]))
+                {
+                    Ok(None) => Err(Error::UnknownCommand),
+                    r => r,
+                };
+                #[cfg(feature = "stats")]
+                self.stats.record(&result);
+                #[cfg(feature = "error-history")]
+                self.errors.record(&result, Some(input[i + 1]), i + 2);
+                if let Some(tee) = self.tee {
+                    tee(&input[0..i + 2]);
                 }
+                return (i + 2, result);
             }
-            RES_GATTR => {
-                self.set_payload_len(1 + KEY_LEN + MAX_ATTR_LEN)?;
-                self.load_char(ch)?;
-                Ok(None)
-            }
-            RES_CRCIF => {
-                self.set_payload_len(4)?;
-                self.load_char(ch)?;
-                Ok(None)
-            }
-            RES_CRCXF => {
-                self.set_payload_len(4)?;
-                self.load_char(ch)?;
-                Ok(None)
-            }
-            RES_INFO => {
-                // length + data
-                self.set_payload_len(1 + MAX_INFO_LEN)?;
-                self.load_char(ch)?;
-                Ok(None)
-            }
-            _ => Ok(None),
+            i += 1;
         }
+        (0, Ok(None))
     }
-}
 
-impl<'a> CommandEncoder<'a> {
-    /// Create a new `CommandEncoder`.
+    /// Process an incoming byte, as with `receive`, but put any decoded
+    /// command on the queue `q` instead of returning it. This is meant for
+    /// a UART RX interrupt handler handing commands to a lower priority
+    /// task: it doesn't allocate, block or touch anything but `self` and
+    /// `q`, and does a bounded amount of work per byte (plus one frame copy
+    /// when a command completes).
     ///
-    /// The encoder takes a reference to a `Command` to encode. The `next` method
-    /// will then supply the encoded bytes one at a time.
-    pub fn new(command: &'a Command) -> Result<CommandEncoder<'a>, Error> {
-        // We have to accept slices rather than arrays, so bounds check them
-        // all now to save surprises later.
-        match command {
-            &Command::WritePage { address: _, data } => {
-                if data.len() != INT_PAGE_SIZE {
-                    return Err(Error::BadArguments);
-                }
-            }
-            &Command::WriteExPage { address: _, data } => {
-                if data.len() != EXT_PAGE_SIZE {
-                    return Err(Error::BadArguments);
-                }
-            }
-            &Command::SetAttr { index, key, value } => {
-                if index > MAX_INDEX {
-                    return Err(Error::BadArguments);
-                }
-                if key.len() != KEY_LEN {
-                    return Err(Error::BadArguments);
-                }
-                if value.len() > MAX_ATTR_LEN {
-                    return Err(Error::BadArguments);
-                }
-            }
-            _ => {}
+    /// Returns `true` if a command was queued. If `q` is full, the command
+    /// is dropped and you get `Error::BufferTooSmall`. Requires the
+    /// `heapless` feature.
+    ///
+    /// Each slot in the queue is a `CommandBuf`, big enough for any
+    /// payload, so with the `write` feature a queue of `N` takes over
+    /// `N * 4` KiB of RAM. Keep `N` small.
+    #[cfg(feature = "heapless")]
+    pub fn receive_to_queue<const N: usize>(
+        &mut self,
+        ch: u8,
+        q: &mut heapless::spsc::Producer<'_, CommandBuf, N>,
+    ) -> Result<bool, Error> {
+        // Only find the payload when this byte could end the frame
+        let terminator = self.unframer.escaped && ch != ESCAPE_CHAR;
+        let range = if terminator {
+            self.payload_range()
+        } else {
+            Ok(0..0)
         };
-        Ok(CommandEncoder {
-            command: command,
-            count: 0,
-            sent_escape: false,
-        })
+        if self.receive(ch)?.is_none() {
+            return Ok(false);
+        }
+        // The command came from this range, so it can't be an error now
+        let range = range?;
+        let mut buf = CommandBuf {
+            opcode: ch,
+            payload: [0u8; CMD_BUFFER_LEN],
+            len: range.len(),
+        };
+        buf.payload[0..range.len()].copy_from_slice(&self.buffer[range]);
+        match q.enqueue(buf) {
+            Ok(()) => Ok(true),
+            Err(_) => Err(Error::BufferTooSmall),
+        }
     }
 
-    /// Reset the `CommandEncoder`, so that next time you call `self.next()`
-    /// you get the first byte again.
-    pub fn reset(&mut self) {
-        self.count = 0;
-        self.sent_escape = false;
+    /// Choose what happens when a frame ends with a command byte we don't
+    /// recognise. The default is `UnknownPolicy::Ignore`.
+    pub fn set_unknown_policy(&mut self, policy: UnknownPolicy) {
+        self.unknown_policy = policy;
     }
 
-    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
-        if byte == ESCAPE_CHAR {
-            if self.sent_escape {
-                self.sent_escape = false;
-                (1, Some(ESCAPE_CHAR))
-            } else {
-                self.sent_escape = true;
-                (0, Some(ESCAPE_CHAR))
-            }
-        } else {
-            self.sent_escape = false;
-            (1, Some(byte))
-        }
+    /// Only accept the commands in `profile`, giving
+    /// `Error::UnsupportedInProfile` for the rest, whatever their payload.
+    /// The default is `Profile::Full`.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
     }
 
-    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            _ => (0, None),
-        }
+    /// Pass every byte we're given to `tee`, exactly as it came off the
+    /// wire, escapes and all - e.g. to log the traffic going through a
+    /// proxy. Bad frames are passed on too, just as they arrived. It may be
+    /// called with one byte or many at a time; join the pieces together to
+    /// get the stream as it was sent. Pass `None` to stop. This doesn't
+    /// change what is decoded.
+    ///
+    /// This takes a plain `fn` rather than a closure so the decoder can
+    /// hold it with no allocator, no lifetime and no type parameter, and
+    /// stays `Clone` and `Debug`. A tee that needs somewhere to put the
+    /// bytes can use a `static`, e.g. a ring buffer behind a critical
+    /// section.
+    pub fn set_tee(&mut self, tee: Option<fn(&[u8])>) {
+        self.tee = tee;
     }
 
-    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            2 => self.render_byte((value >> 16) as u8),
-            3 => self.render_byte((value >> 24) as u8),
-            _ => (0, None),
-        }
+    /// Only accept a `ChangeBaud` for one of these baud rates (for example,
+    /// `STANDARD_BAUDS`). Anything else gives `Error::UnsupportedBaud`.
+    /// Pass `None` to accept any baud rate, which is the default.
+    pub fn set_allowed_bauds(&mut self, bauds: Option<&'static [u32]>) {
+        self.allowed_bauds = bauds;
     }
 
-    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
-        if (idx < data.len()) && (idx < page_size) {
-            self.render_byte(data[idx])
-        } else if idx < page_size {
-            self.render_byte(0x00) // pad short data with nulls
-        } else {
-            (0, None)
+    /// Read bytes from `r` until a command has been decoded.
+    ///
+    /// Unknown command bytes are skipped over, as with `receive`. Running
+    /// out of bytes gives an `UnexpectedEof` error, and decoding problems
+    /// give an `InvalidData` error wrapping our `Error`.
+    #[cfg(feature = "std")]
+    pub fn read_from(&mut self, r: &mut dyn std::io::Read) -> std::io::Result<Command<'_>> {
+        let bytes = core::iter::from_fn(|| {
+            let mut byte = [0u8; 1];
+            Some(r.read_exact(&mut byte).map(|()| byte[0]))
+        });
+        match self.feed_until_command(bytes)? {
+            Some(cmd) => Ok(cmd),
+            None => Err(Error::UnknownCommand.into()),
         }
     }
 
-    fn render_basic_cmd(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
-        match count {
-            0 => (1, Some(ESCAPE_CHAR)), // Escape
-            1 => (1, Some(cmd)), // Command
-            _ => (0, None),
+    /// Decode the bytes from `bytes` as you ask for them, one command at a
+    /// time, without copying anything.
+    pub fn receive_iter<I>(&mut self, bytes: I) -> Commands<'_, I>
+    where
+        I: Iterator<Item = u8>,
+    {
+        Commands {
+            decoder: self,
+            bytes,
         }
     }
 
-    fn render_erasepage_cmd(&mut self, address: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_EPAGE),
-        }
+    /// Decode the commands in `bytes`, one at a time. See `Drain` for why
+    /// each command has to be dropped before you ask for the next. A partial
+    /// frame at the end stays in the decoder for next time.
+    pub fn drain<'b>(&mut self, bytes: &'b [u8]) -> Drain<'_, 'b> {
+        self.receive_iter(bytes.iter().cloned())
     }
 
-    fn render_writepage_cmd(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            4...515 => self.render_buffer(count - 4, INT_PAGE_SIZE, data),
-            _ => self.render_basic_cmd(count - 516, CMD_WPAGE),
-        }
+    /// Feed in bytes written as hex, like `"FC 01"`, for tests and for
+    /// tools which read hex dumps. Whitespace is ignored. Stops at the first
+    /// command (or error) and returns it, ignoring any hex after that.
+    /// Returns `Ok(None)` if the hex ran out first, and
+    /// `Error::BadArguments` if it isn't valid hex. Requires the `hex`
+    /// feature.
+    #[cfg(feature = "hex")]
+    pub fn feed_hex(&mut self, hex: &str) -> Result<Option<Command<'_>>, Error> {
+        let mut digits = hex.bytes().filter(|c| !c.is_ascii_whitespace());
+        let bytes = core::iter::from_fn(move || {
+            let high = digits.next()?;
+            Some(hex_digit(high).and_then(|high| {
+                let low = hex_digit(digits.next().ok_or(Error::BadArguments)?)?;
+                Ok((high << 4) | low)
+            }))
+        });
+        self.feed_until_command(bytes)
     }
 
-    fn render_eraseexblock(&mut self, address: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_XEBLOCK),
-        }
+    /// Feed in bytes carried as a Latin-1 string, as some test scripts do.
+    /// Each char gives the byte in its low eight bits. Like `feed_hex`, stops
+    /// at the first command (or error) and returns it, ignoring the rest of
+    /// the string. Requires the `latin1` feature.
+    #[cfg(feature = "latin1")]
+    pub fn feed_bytes_lossy(&mut self, s: &str) -> Result<Option<Command<'_>>, Error> {
+        self.feed_until_command(s.chars().map(|c| Ok::<u8, Error>(c as u32 as u8)))
     }
 
-    fn render_writeexpage(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            4...259 => self.render_buffer(count - 4, EXT_PAGE_SIZE, data),
-            _ => self.render_basic_cmd(count - (EXT_PAGE_SIZE + 4), CMD_XWPAGE),
+    /// Decode all the complete frames in `bytes`. Also returns the index of
+    /// the first byte after the last complete frame, so you can carry any
+    /// partial frame over to the next call. The partial frame is dropped
+    /// from the decoder, so don't feed it in again by other means.
+    #[cfg(feature = "std")]
+    pub fn parse_all(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<(std::vec::Vec<CommandOwned>, usize), Error> {
+        let mut commands = std::vec::Vec::new();
+        let mut used = 0;
+        for (i, ch) in bytes.iter().enumerate() {
+            let terminator = self.unframer.escaped && *ch != ESCAPE_CHAR;
+            match self.receive(*ch) {
+                Ok(Some(ref cmd)) => commands.push(CommandOwned::from(cmd)),
+                Ok(None) => {}
+                Err(e) => return Err(e),
+            }
+            if terminator {
+                used = i + 1;
+            }
         }
+        self.unframer.reset();
+        self.reset();
+        Ok((commands, used))
     }
 
-    fn render_readrange(&mut self, address: u32, length: u16) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            4...5 => self.render_u16(count - 4, length),
-            _ => self.render_basic_cmd(count - 6, CMD_RRANGE),
-        }
+    /// The CRC-32 of the data in the last `WritePage` decoded, worked out as
+    /// the bytes arrived, so you can check the page without a second pass.
+    /// Compare it with `crc32` of whatever you read back. `None` until a
+    /// `WritePage` has been decoded. Requires the `crc` feature.
+    #[cfg(feature = "crc")]
+    pub fn current_page_crc(&self) -> Option<u32> {
+        self.page_crc
     }
 
-    fn render_exreadrange(&mut self, address: u32, length: u16) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            4...5 => self.render_u16(count - 4, length),
-            _ => self.render_basic_cmd(count - 6, CMD_XRRANGE),
-        }
+    /// Check the next `WritePage` against `crc`, the CRC-32 of the data we
+    /// expect in it, and give `Error::PageCrcMismatch` instead of the
+    /// command if it doesn't match. The expectation is used up by the next
+    /// `WritePage` that decodes, matching or not, but stays put through
+    /// any bad frames before that. Only applies to pages from `receive`
+    /// (and the methods which use it), not `receive_in_place`. Requires the
+    /// `crc` feature.
+    #[cfg(feature = "crc")]
+    pub fn expect_next_page_crc(&mut self, crc: u32) {
+        self.expected_page_crc = Some(crc);
     }
 
-    fn render_setattr(&mut self, index: u8, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        let max_len = if value.len() > MAX_ATTR_LEN {
-            MAX_ATTR_LEN
-        } else {
-            value.len()
-        };
-        match count {
-            0 => self.render_byte(index),
-            1...8 => self.render_buffer(count - 1, KEY_LEN, key),
-            9 => self.render_byte(max_len as u8),
-            x if (max_len > 0) && (x < (max_len + 10)) => {
-                self.render_buffer(x - 10, max_len, value)
+    /// Feed `bytes` through `receive` until one gives a command or an
+    /// error, and return that. Gives `Ok(None)` if `bytes` runs out first,
+    /// and passes any error from `bytes` itself straight back.
+    fn feed_until_command<I, E>(&mut self, mut bytes: I) -> Result<Option<Command<'_>>, E>
+    where
+        I: Iterator<Item = Result<u8, E>>,
+        E: From<Error>,
+    {
+        let ch = loop {
+            let ch = match bytes.next() {
+                Some(ch) => ch?,
+                None => return Ok(None),
+            };
+            if self.unframer.escaped && ch != ESCAPE_CHAR && self.completes_frame(ch) {
+                break ch;
             }
-            _ => self.render_basic_cmd(count - (10 + max_len), CMD_SATTR),
-        }
+            self.receive(ch)?;
+        };
+        // Decode outside the loop, so the borrow of our buffer can escape
+        Ok(self.receive(ch)?)
     }
 
-    fn render_getattr(&mut self, index: u8) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0 => self.render_byte(index),
-            _ => self.render_basic_cmd(count - 1, CMD_GATTR),
+    /// Would command byte `ch` give a command or an error, rather than be
+    /// ignored?
+    fn completes_frame(&self, ch: u8) -> bool {
+        if self.discarding {
+            return false;
+        }
+        if self.max_frame_len.is_some_and(|n| self.frame_len >= n) {
+            return true;
+        }
+        match self.payload_range() {
+            Ok(range) => !matches!(self.decode_payload(ch, &self.buffer[range]), Ok(None)),
+            Err(_) => true,
         }
     }
 
-    fn render_crcintflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            4...7 => self.render_u32(count - 4, length),
-            _ => self.render_basic_cmd(count - 8, CMD_CRCIF),
+    /// Where the payload is in the buffer, after any sequence number and
+    /// before any checksum. Fails if the frame is too short for those or
+    /// the checksum is wrong.
+    fn payload_range(&self) -> Result<core::ops::Range<usize>, Error> {
+        if self.overflowed {
+            return Err(Error::Overflow);
         }
+        self.frame_payload(&self.buffer[0..self.count])
     }
 
-    fn render_crcextflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            4...7 => self.render_u32(count - 4, length),
-            _ => self.render_basic_cmd(count - 8, CMD_CRCEF),
+    /// Where the payload is in a de-escaped `frame`, as for
+    /// `payload_range`.
+    fn frame_payload(&self, frame: &[u8]) -> Result<core::ops::Range<usize>, Error> {
+        let start = self.tag_len();
+        let end = frame
+            .len()
+            .checked_sub(self.checksum_len())
+            .filter(|&end| end >= start)
+            .ok_or(Error::BadArguments)?;
+        #[cfg(feature = "crc")]
+        {
+            if self.checksum && crc16(&frame[0..end]) != LittleEndian::read_u16(&frame[end..]) {
+                return Err(Error::ChecksumMismatch);
+            }
         }
+        Ok(start..end)
     }
 
-    fn render_eraseexpage(&mut self, address: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...3 => self.render_u32(count, address),
-            _ => self.render_basic_cmd(count - 4, CMD_XEPAGE),
+    /// How many bytes of checksum come after the payload.
+    #[cfg(feature = "crc")]
+    fn checksum_len(&self) -> usize {
+        if self.checksum {
+            CHECKSUM_LEN
+        } else {
+            0
         }
     }
 
-    fn render_writeflashuserpages(&mut self, page1: u32, page2: u32) -> (usize, Option<u8>) {
+    #[cfg(not(feature = "crc"))]
+    fn checksum_len(&self) -> usize {
+        0
+    }
+
+    /// How many bytes of tag come before the payload.
+    #[cfg(feature = "seqnum")]
+    fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+
+    #[cfg(not(feature = "seqnum"))]
+    fn tag_len(&self) -> usize {
+        0
+    }
+
+    #[cfg(feature = "write")]
+    fn expected_page_size(&self) -> usize {
+        self.page_size.unwrap_or(INT_PAGE_SIZE)
+    }
+
+    fn load_char(&mut self, ch: u8) {
+        if self.overflowed {
+            // The frame is already lost, so don't fill the buffer up again
+        } else if self.count < self.buffer.len() {
+            self.buffer[self.count] = ch;
+            self.count += 1;
+            self.dirty = self.dirty.max(self.count);
+            #[cfg(feature = "crc")]
+            {
+                // Everything after a page address could be page data
+                if self.count == 1 {
+                    self.running_crc = CRC32_INIT;
+                } else if self.count > 4 + self.tag_len() {
+                    self.running_crc = crc32_update(self.running_crc, ch);
+                }
+            }
+        } else {
+            // Drop what we have, so there's room (e.g. for `dma_buffer`) to
+            // see the rest of the frame go by
+            self.overflowed = true;
+            self.count = 0;
+        }
+        debug_assert!(self.count <= self.buffer.len() && self.count <= self.dirty);
+    }
+
+    /// Can `load_run` stand in for `receive` right now? Not if we're
+    /// escaped, or something needs to see every byte.
+    fn can_load_run(&self) -> bool {
+        !self.unframer.escaped
+            && !self.discarding
+            && self.max_frame_len.is_none()
+            && !cfg!(feature = "trace")
+    }
+
+    /// Does what `receive` would for a run of bytes with no escape
+    /// characters in, when `can_load_run` says so.
+    fn load_run(&mut self, run: &[u8]) {
+        self.completed = false;
+        if let Some(tee) = self.tee {
+            tee(run);
+        }
+        self.frame_len = self.frame_len.saturating_add(run.len());
+        if self.overflowed {
+            return;
+        }
+        let start = self.count;
+        let n = run.len().min(self.buffer.len() - start);
+        self.buffer[start..start + n].copy_from_slice(&run[..n]);
+        self.count += n;
+        self.dirty = self.dirty.max(self.count);
+        #[cfg(feature = "crc")]
+        {
+            // As in load_char
+            for (i, &ch) in run[..n].iter().enumerate() {
+                let count = start + i + 1;
+                if count == 1 {
+                    self.running_crc = CRC32_INIT;
+                } else if count > 4 + self.tag_len() {
+                    self.running_crc = crc32_update(self.running_crc, ch);
+                }
+            }
+        }
+        if n < run.len() {
+            // As in load_char
+            self.overflowed = true;
+            self.count = 0;
+        }
+    }
+
+    fn handle_command(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        self.last_opcode = Some(ch);
+        #[cfg(feature = "error-history")]
+        let offset = self.frame_len;
+        let result = match self.payload_range() {
+            Ok(range) => self.decode_payload(ch, &self.buffer[range]),
+            Err(e) => Err(e),
+        };
+        #[cfg(feature = "crc")]
+        let result = if let Ok(Some(Command::WritePage { data, .. })) = result {
+            // The running CRC has taken in the checksum bytes too
+            let crc = if self.checksum {
+                crc32(data)
+            } else {
+                !self.running_crc
+            };
+            self.page_crc = Some(crc);
+            match self.expected_page_crc.take() {
+                Some(expected) if expected != crc => Err(Error::PageCrcMismatch),
+                _ => result,
+            }
+        } else {
+            result
+        };
+        // Count the escapes which ended a frame without giving a command.
+        // A doubled escape is a literal 0xFC, so never gets this far.
+        let result = match result {
+            Ok(None) => {
+                self.escape_run = self.escape_run.saturating_add(1);
+                if self.strict && self.escape_run > self.max_escape_run {
+                    Err(Error::TooManyEscapes)
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => {
+                self.escape_run = 0;
+                result
+            }
+        };
+        #[cfg(feature = "stats")]
+        self.stats.record(&result);
+        #[cfg(feature = "error-history")]
+        self.errors.record(&result, Some(ch), offset);
+        let result = match result {
+            Ok(Some(_)) => {
+                self.bad_frames = 0;
+                result
+            }
+            Err(_) => {
+                self.bad_frames = self.bad_frames.saturating_add(1);
+                if self.fault_threshold.is_some_and(|n| self.bad_frames >= n) {
+                    Err(Error::LinkFault)
+                } else {
+                    result
+                }
+            }
+            Ok(None) => result,
+        };
+        #[cfg(feature = "trace")]
+        self.trace.record(
+            true,
+            ch,
+            match result {
+                Ok(Some(_)) => TraceAction::Command,
+                Ok(None) => TraceAction::Ignored,
+                Err(_) => TraceAction::Error,
+            },
+        );
+        // A command or error signifies the end of the buffer. An ignored
+        // frame carries on, so its bytes still count towards the next one.
+        if let Ok(Some(_)) = result {
+            self.completed = true;
+            self.count = 0;
+            self.frame_len = 0;
+            self.overflowed = false;
+        } else if result.is_err() {
+            self.count = 0;
+            self.frame_len = 0;
+            self.overflowed = false;
+        }
+        // Only an ignored frame may leave anything in the buffer
+        debug_assert!(self.count == 0 || matches!(result, Ok(None)));
+        result
+    }
+
+    /// Decode a de-escaped payload, applying our settings.
+    fn decode_payload<'b>(&self, ch: u8, payload: &'b [u8]) -> Result<Option<Command<'b>>, Error> {
+        // Check the profile first, so a bad payload doesn't hide the reason
+        if command_kind(ch).is_some_and(|kind| !self.profile.allows(kind)) {
+            return Err(Error::UnsupportedInProfile);
+        }
+        match ch {
+            #[cfg(feature = "write")]
+            CMD_WPAGE if payload.len() == 4 => Err(Error::EmptyPage),
+            #[cfg(feature = "write")]
+            CMD_WPAGE if self.strict && payload.len() != self.expected_page_size() + 4 => {
+                // A short page means a lone escape ended the frame early
+                Err(Error::MissingEscape)
+            }
+            #[cfg(feature = "write")]
+            CMD_WPAGE if self.page_size.is_some_and(|n| payload.len() != n + 4) => {
+                Err(Error::BadArguments)
+            }
+            _ => match decode_command(ch, payload) {
+                // A lone escape in the middle of a payload, followed by data
+                Ok(None) if self.strict && !payload.is_empty() => Err(Error::MissingEscape),
+                Ok(Some(Command::ChangeBaud { baud, .. }))
+                    if self.allowed_bauds.is_some_and(|b| !b.contains(&baud)) => {
+                    Err(Error::UnsupportedBaud)
+                }
+                Ok(None) => match self.unknown_policy {
+                    UnknownPolicy::Ignore => Ok(None),
+                    UnknownPolicy::Error => Err(Error::UnknownCommand),
+                    UnknownPolicy::Report => Ok(Some(Command::Unknown { opcode: ch })),
+                },
+                #[cfg(feature = "write")]
+                Ok(Some(Command::ErasePage { address }))
+                    if !self.in_flash(address, self.expected_page_size()) => {
+                    Err(Error::BadAddress)
+                }
+                #[cfg(feature = "write")]
+                Ok(Some(Command::WritePage { address, data }))
+                    if !self.in_flash(address, data.len()) => {
+                    Err(Error::BadAddress)
+                }
+                #[cfg(feature = "partial-page")]
+                Ok(Some(Command::WritePagePartial { address, data, .. }))
+                    if !self.in_flash(address, data.len()) => {
+                    Err(Error::BadAddress)
+                }
+                r => r,
+            },
+        }
+    }
+
+    /// Do `len` bytes from `address` fit in the flash bounds, if we have
+    /// any?
+    #[cfg(feature = "write")]
+    fn in_flash(&self, address: u32, len: usize) -> bool {
+        match self.flash_bounds {
+            Some((base, size)) => {
+                let start = u64::from(address);
+                let base = u64::from(base);
+                start >= base && start + len as u64 <= base + u64::from(size)
+            }
+            None => true,
+        }
+    }
+}
+
+impl<'d> core::ops::Deref for DecoderScope<'d> {
+    type Target = CommandDecoder;
+
+    fn deref(&self) -> &CommandDecoder {
+        self.decoder
+    }
+}
+
+impl<'d> core::ops::DerefMut for DecoderScope<'d> {
+    fn deref_mut(&mut self) -> &mut CommandDecoder {
+        self.decoder
+    }
+}
+
+impl<'d> Drop for DecoderScope<'d> {
+    fn drop(&mut self) {
+        // Whatever was left over doesn't matter any more
+        let _ = self.decoder.flush();
+    }
+}
+
+impl Default for CommandDecoder {
+    fn default() -> CommandDecoder {
+        CommandDecoder::new()
+    }
+}
+
+impl ResponseDecoder {
+    /// Create a new `ResponseDecoder`.
+    ///
+    /// The decoder is fed bytes with the `receive` method.
+    pub fn new() -> ResponseDecoder {
+        ResponseDecoder {
+            unframer: Unframer::new(),
+            buffer: [0u8; 4224],
+            count: 0,
+            needed: None,
+        }
+    }
+
+    /// Decode a whole buffers worth of bytes.
+    ///
+    /// Due to lifetime problems, the decoded `Response`s are sent via `callback` rather
+    /// than being returned.
+    pub fn read<F>(&mut self, buffer: &[u8], callback: F) -> Result<(), Error>
+    where
+        F: Fn(&Response),
+    {
+        for ch in buffer {
+            match self.receive(*ch) {
+                Err(e) => return Err(e),
+                Ok(None) => {}
+                Ok(Some(ref cmd)) => callback(cmd),
+            }
+        }
+        Ok(())
+    }
+
+    /// Empty the RX buffer.
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    /// Render the bytes buffered so far for the current frame as hex, for
+    /// logging. See `CommandDecoder::frame_hex`.
+    pub fn frame_hex<'b>(&self, out: &'b mut [u8]) -> &'b str {
+        write_hex(&self.buffer[0..self.count], out)
+    }
+
+    /// Process incoming bytes.
+    ///
+    /// The decoder is fed bytes with the `receive` method. If not enough
+    /// bytes have been seen, this function returns `None`. Once enough bytes
+    /// have been seen, it returns `Some(Response)` containing the
+    /// decoded Response.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        match self.unframer.push(ch) {
+            None => Ok(None),
+            Some(FramedEvent::Literal(ch)) => self.load_char(ch),
+            Some(FramedEvent::Terminator(ch)) => self.handle_response(ch),
+        }
+    }
+
+    /// Set the expected length of an unbounded message. This
+    /// depends entirely on the last command you sent.
+    pub fn set_payload_len(&mut self, length: usize) -> Result<(), Error> {
+        match self.needed {
+            Some(_) => Err(Error::SetLength),
+            None => {
+                self.needed = Some(length + 1);
+                Ok(())
+            }
+        }
+    }
+
+    fn load_char(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        if self.count < self.buffer.len() {
+            self.buffer[self.count] = ch;
+            self.count += 1;
+        }
+        if self.needed == Some(self.count) {
+            let result = match self.buffer[0] {
+                RES_CRCRX => {
+                    let length = LittleEndian::read_u16(&self.buffer[1..3]);
+                    let crc = LittleEndian::read_u32(&self.buffer[3..7]);
+                    Ok(Some(Response::CrcRxBuffer { length, crc }))
+                }
+                RES_RRANGE => {
+                    let data = &self.buffer[1..self.count];
+                    Ok(Some(Response::ReadRange { data }))
+                }
+                RES_XRRANGE => {
+                    let data = &self.buffer[1..self.count];
+                    Ok(Some(Response::ExReadRange { data }))
+                }
+                RES_GATTR => {
+                    let key = &self.buffer[1..9];
+                    let length = self.buffer[9] as usize;
+                    if (9 + length) <= self.count {
+                        let value = &self.buffer[10..(10 + length)];
+                        Ok(Some(Response::GetAttr { key, value }))
+                    } else {
+                        Err(Error::BadArguments)
+                    }
+                }
+                RES_CRCIF => {
+                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
+                    Ok(Some(Response::CrcIntFlash { crc }))
+                }
+                RES_CRCXF => {
+                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
+                    Ok(Some(Response::CrcExtFlash { crc }))
+                }
+                RES_VERSION => Ok(Some(Response::Version {
+                    version: self.buffer[1],
+                })),
+                RES_INFO => {
+                    let length: usize = self.buffer[1] as usize;
+                    if length + 1 < self.count {
+                        let info = &self.buffer[2..length + 2];
+                        Ok(Some(Response::Info { info }))
+                    } else {
+                        Err(Error::BadArguments)
+                    }
+                }
+                _ => Err(Error::UnknownCommand),
+            };
+            self.needed = None;
+            self.count = 0;
+            result
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn handle_response(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        match ch {
+            RES_PONG => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::Pong))
+            }
+            RES_OVERFLOW => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::Overflow))
+            }
+            RES_BADADDR => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::BadAddress))
+            }
+            RES_INTERROR => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::InternalError))
+            }
+            RES_BADARGS => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::BadArguments))
+            }
+            RES_OK => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::Ok))
+            }
+            RES_UNKNOWN => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::Unknown))
+            }
+            RES_XFTIMEOUT => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::ExtFlashTimeout))
+            }
+            RES_XFEPE => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::ExtFlashPageError))
+            }
+            RES_CHANGE_BAUD_FAIL => {
+                self.count = 0;
+                self.needed = None;
+                Ok(Some(Response::ChangeBaudFail))
+            }
+            RES_CRCRX => {
+                self.set_payload_len(6)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_RRANGE => {
+                if self.needed.is_none() {
+                    Err(Error::UnsetLength)
+                } else {
+                    self.load_char(ch)?;
+                    Ok(None)
+                }
+            }
+            RES_XRRANGE => {
+                if self.needed.is_none() {
+                    Err(Error::UnsetLength)
+                } else {
+                    self.load_char(ch)?;
+                    Ok(None)
+                }
+            }
+            RES_GATTR => {
+                self.set_payload_len(1 + KEY_LEN + MAX_ATTR_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_CRCIF => {
+                self.set_payload_len(4)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_CRCXF => {
+                self.set_payload_len(4)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_VERSION => {
+                self.set_payload_len(1)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            RES_INFO => {
+                // length + data
+                self.set_payload_len(1 + MAX_INFO_LEN)?;
+                self.load_char(ch)?;
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Default for ResponseDecoder {
+    fn default() -> ResponseDecoder {
+        ResponseDecoder::new()
+    }
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    fn record(&mut self, result: &Result<Option<Command>, Error>) {
+        let counter = match *result {
+            Ok(None) => return,
+            Err(_) => &mut self.errors,
+            Ok(Some(ref cmd)) => match *cmd {
+                Command::Ping => &mut self.ping,
+                Command::Info => &mut self.info,
+                Command::Id => &mut self.id,
+                Command::Reset => &mut self.reset,
+                Command::ErasePage { .. } => &mut self.erase_page,
+                Command::WritePage { .. } => &mut self.write_page,
+                Command::EraseExBlock { .. } => &mut self.erase_ex_block,
+                Command::WriteExPage { .. } => &mut self.write_ex_page,
+                #[cfg(feature = "partial-page")]
+                Command::WritePagePartial { .. } => &mut self.write_page_partial,
+                Command::CrcRxBuffer => &mut self.crc_rx_buffer,
+                Command::ReadRange { .. } => &mut self.read_range,
+                Command::ExReadRange { .. } => &mut self.ex_read_range,
+                Command::SetAttr { .. } => &mut self.set_attr,
+                Command::GetAttr { .. } => &mut self.get_attr,
+                Command::CrcIntFlash { .. } => &mut self.crc_int_flash,
+                Command::CrcExtFlash { .. } => &mut self.crc_ext_flash,
+                Command::EraseExPage { .. } => &mut self.erase_ex_page,
+                Command::ExtFlashInit => &mut self.ext_flash_init,
+                Command::ClockOut => &mut self.clock_out,
+                Command::WriteFlashUserPages { .. } => &mut self.write_flash_user_pages,
+                Command::ChangeBaud { .. } => &mut self.change_baud,
+                Command::SetStartAddress { .. } => &mut self.set_start_address,
+                Command::GetVersion { .. } => &mut self.get_version,
+                Command::Unknown { .. } => &mut self.unknown,
+            },
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+#[cfg(feature = "heapless")]
+impl CommandBuf {
+    /// Get the command this frame holds. The payload already got through
+    /// the decoder, settings and all, so it decodes the same way again.
+    /// The `Command::Unknown` fallback is only there so this can't fail.
+    pub fn as_command(&self) -> Command<'_> {
+        let opcode = self.opcode;
+        Command::from_parts(opcode, &self.payload[0..self.len])
+            .unwrap_or(Command::Unknown { opcode })
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl SerialLink<std::boxed::Box<dyn serialport::SerialPort>> {
+    /// Open the serial port at `path` (e.g. `/dev/ttyACM0` or `COM3`).
+    pub fn open(path: &str, baud: u32) -> serialport::Result<Self> {
+        let port = serialport::new(path, baud).open()?;
+        Ok(SerialLink::new(port))
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl<P> SerialLink<P>
+where
+    P: std::io::Read + std::io::Write,
+{
+    /// Use an already open port.
+    pub fn new(port: P) -> SerialLink<P> {
+        SerialLink {
+            port,
+            decoder: CommandDecoder::new(),
+        }
+    }
+
+    /// Wait for the next command. If the port has a timeout and it expires,
+    /// you get an error of kind `TimedOut`, and can just call this again.
+    pub fn receive(&mut self) -> std::io::Result<Command<'_>> {
+        self.decoder.read_from(&mut self.port)
+    }
+
+    /// Send a response, and flush it out of the port.
+    pub fn send(&mut self, response: &Response) -> std::io::Result<()> {
+        response.write_to(&mut self.port)?;
+        self.port.flush()
+    }
+
+    /// The decoder, so you can change its settings.
+    pub fn decoder_mut(&mut self) -> &mut CommandDecoder {
+        &mut self.decoder
+    }
+
+    /// Give back the port.
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R> CommandStream<R>
+where
+    R: futures_io::AsyncRead + Unpin,
+{
+    /// Create a new `CommandStream` reading from `reader`.
+    pub fn new(reader: R) -> CommandStream<R> {
+        CommandStream {
+            reader,
+            decoder: CommandDecoder::new(),
+            buffer: [0u8; 64],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Give back the reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R> futures_core::Stream for CommandStream<R>
+where
+    R: futures_io::AsyncRead + Unpin,
+{
+    type Item = Result<CommandOwned, Error>;
+
+    /// Supply the next decoded command. If the reader runs out part way
+    /// through a frame you get the error from `CommandDecoder::flush`, then
+    /// the end of the stream.
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Option<Self::Item>> {
+        use core::task::Poll;
+        let this = self.get_mut();
+        loop {
+            while this.start < this.end {
+                let ch = this.buffer[this.start];
+                this.start += 1;
+                match this.decoder.receive(ch) {
+                    Ok(None) => {}
+                    Ok(Some(ref cmd)) => return Poll::Ready(Some(Ok(CommandOwned::from(cmd)))),
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+            let reader = core::pin::Pin::new(&mut this.reader);
+            match reader.poll_read(cx, &mut this.buffer) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(Error::Io(e.kind())))),
+                Poll::Ready(Ok(0)) => {
+                    return match this.decoder.flush() {
+                        Ok(()) => Poll::Ready(None),
+                        Err(e) => Poll::Ready(Some(Err(e))),
+                    };
+                }
+                Poll::Ready(Ok(n)) => {
+                    this.start = 0;
+                    this.end = n;
+                }
+            }
+        }
+    }
+}
+
+impl<'d, I> Commands<'d, I>
+where
+    I: Iterator<Item = u8>,
+{
+    /// Feed in bytes until a command is decoded or there's an error.
+    /// Returns `None` once the bytes run out. The command is only valid
+    /// until you call this again.
+    pub fn next_command(&mut self) -> Option<Result<Command<'_>, Error>> {
+        self.decoder
+            .feed_until_command(self.bytes.by_ref().map(Ok::<u8, Error>))
+            .transpose()
+    }
+
+    /// Turn this into a real `Iterator`, by copying each command out of the
+    /// decoder's buffer.
+    #[cfg(feature = "std")]
+    pub fn into_owned(mut self) -> impl Iterator<Item = Result<CommandOwned, Error>> + 'd
+    where
+        I: 'd,
+    {
+        core::iter::from_fn(move || {
+            self.next_command()
+                .map(|r| r.map(|cmd| CommandOwned::from(&cmd)))
+        })
+    }
+}
+
+impl<D: OpcodeDecoder> FramedParser<D> {
+    /// Create a new `FramedParser` which decodes frames with `decoder`.
+    pub fn new(decoder: D) -> FramedParser<D> {
+        FramedParser {
+            unframer: Unframer::new(),
+            buffer: [0u8; CMD_BUFFER_LEN],
+            count: 0,
+            overflowed: false,
+            decoder,
+        }
+    }
+
+    /// Process an incoming byte. Returns whatever our `OpcodeDecoder` makes
+    /// of a complete frame, or `Error::Overflow` if the frame didn't fit in
+    /// our buffer.
+    pub fn receive(&mut self, ch: u8) -> Result<Option<D::Command<'_>>, Error> {
+        match self.unframer.push(ch) {
+            None => Ok(None),
+            Some(FramedEvent::Literal(ch)) => {
+                if self.count < self.buffer.len() {
+                    self.buffer[self.count] = ch;
+                    self.count += 1;
+                } else {
+                    self.overflowed = true;
+                }
+                Ok(None)
+            }
+            Some(FramedEvent::Terminator(opcode)) => {
+                let count = self.count;
+                self.count = 0;
+                if self.overflowed {
+                    self.overflowed = false;
+                    return Err(Error::Overflow);
+                }
+                self.decoder.decode(opcode, &self.buffer[0..count])
+            }
+        }
+    }
+
+    /// The `OpcodeDecoder` we're using.
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    /// The `OpcodeDecoder` we're using, so you can change it.
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+}
+
+impl Default for FramedParser<TockloaderOpcodes> {
+    fn default() -> FramedParser<TockloaderOpcodes> {
+        FramedParser::new(TockloaderOpcodes)
+    }
+}
+
+impl<'a> AttrChunks<'a> {
+    /// Split `value` into chunks of at most 55 bytes, stored under `key` in
+    /// the slots starting at `index`. Fails if the key is the wrong length
+    /// or there aren't enough slots left for the whole value. An empty value
+    /// produces no commands.
+    pub fn new(index: u8, key: &'a [u8], value: &'a [u8]) -> Result<AttrChunks<'a>, Error> {
+        let slots = value.len().div_ceil(MAX_ATTR_LEN);
+        if key.len() != KEY_LEN {
+            return Err(Error::BadArguments);
+        }
+        if slots > 0 && index as usize + slots > ATTR_SLOTS {
+            return Err(Error::BadArguments);
+        }
+        Ok(AttrChunks { index, key, value })
+    }
+}
+
+impl<'a> Iterator for AttrChunks<'a> {
+    type Item = Command<'a>;
+
+    /// Supply the `Command::SetAttr` for the next slot.
+    fn next(&mut self) -> Option<Command<'a>> {
+        if self.value.is_empty() {
+            return None;
+        }
+        let len = self.value.len().min(MAX_ATTR_LEN);
+        let (value, rest) = self.value.split_at(len);
+        let cmd = Command::SetAttr {
+            index: self.index,
+            key: self.key,
+            value,
+        };
+        self.index += 1;
+        self.value = rest;
+        Some(cmd)
+    }
+}
+
+impl<'a> AttrAssembler<'a> {
+    /// Create a new `AttrAssembler` which stores the value in `buffer`.
+    pub fn new(buffer: &'a mut [u8]) -> AttrAssembler<'a> {
+        AttrAssembler { buffer, count: 0 }
+    }
+
+    /// Append the value from the next slot.
+    pub fn push(&mut self, value: &[u8]) -> Result<(), Error> {
+        let end = self.count + value.len();
+        if end > self.buffer.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buffer[self.count..end].copy_from_slice(value);
+        self.count = end;
+        Ok(())
+    }
+
+    /// The value assembled so far.
+    pub fn value(&self) -> &[u8] {
+        &self.buffer[0..self.count]
+    }
+}
+
+impl PingSession {
+    /// Start a new `PingSession`.
+    pub fn new() -> PingSession {
+        PingSession {
+            decoder: ResponseDecoder::new(),
+            ponged: false,
+        }
+    }
+
+    /// The encoded `Ping` to send.
+    pub fn request(&self) -> [u8; 2] {
+        let mut out = [0u8; 2];
+        // A Ping has no arguments, so the encoder always takes it
+        if let Ok(mut encoder) = CommandEncoder::new(&Command::Ping) {
+            encoder.write(&mut out);
+        }
+        out
+    }
+
+    /// Process a byte from the bootloader. Returns `Error::UnexpectedResponse`
+    /// if anything but a single `Pong` arrives, so a second `Pong` is an
+    /// error too.
+    pub fn feed_response(&mut self, ch: u8) -> Result<PingStatus, Error> {
+        match self.decoder.receive(ch)? {
+            Some(Response::Pong) if !self.ponged => self.ponged = true,
+            Some(_) => return Err(Error::UnexpectedResponse),
+            None => {}
+        }
+        Ok(if self.ponged {
+            PingStatus::Ready
+        } else {
+            PingStatus::Waiting
+        })
+    }
+}
+
+impl Default for PingSession {
+    fn default() -> PingSession {
+        PingSession::new()
+    }
+}
+
+impl Profile {
+    /// The commands in this profile, as for
+    /// `CommandDecoder::supported_commands`.
+    pub fn commands(&self) -> &'static [CommandKind] {
+        match *self {
+            Profile::Minimal => MINIMAL_COMMANDS,
+            Profile::Standard => STANDARD_COMMANDS,
+            Profile::Full => SUPPORTED_COMMANDS,
+        }
+    }
+
+    /// Is this sort of command in the profile?
+    pub fn allows(&self, kind: CommandKind) -> bool {
+        self.commands().contains(&kind)
+    }
+}
+
+impl InfoSession {
+    /// Start a new `InfoSession`.
+    pub fn new() -> InfoSession {
+        InfoSession {
+            decoder: ResponseDecoder::new(),
+            done: false,
+        }
+    }
+
+    /// The encoded `Info` command to send.
+    pub fn request(&self) -> [u8; 2] {
+        let mut out = [0u8; 2];
+        // Info has no arguments, so the encoder always takes it
+        if let Ok(mut encoder) = CommandEncoder::new(&Command::Info) {
+            encoder.write(&mut out);
+        }
+        out
+    }
+
+    /// Process a byte from the bootloader. Returns the information once the
+    /// `Response::Info` is complete, or `Error::UnexpectedResponse` if
+    /// anything else arrives (including a second `Info`).
+    pub fn feed_response(&mut self, ch: u8) -> Result<Option<BootloaderInfo>, Error> {
+        match self.decoder.receive(ch)? {
+            Some(Response::Info { info }) if !self.done => {
+                self.done = true;
+                let mut copy = [0u8; MAX_INFO_LEN];
+                copy[0..info.len()].copy_from_slice(info);
+                Ok(Some(BootloaderInfo {
+                    info: copy,
+                    len: info.len(),
+                }))
+            }
+            Some(_) => Err(Error::UnexpectedResponse),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Default for InfoSession {
+    fn default() -> InfoSession {
+        InfoSession::new()
+    }
+}
+
+impl BootloaderInfo {
+    /// The information string, without the zero padding.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.info[0..self.len]
+    }
+
+    /// The information string as text, if it's valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(self.as_bytes()).ok()
+    }
+}
+
+impl AttrTable {
+    /// Create an empty `AttrTable`.
+    pub fn new() -> AttrTable {
+        AttrTable {
+            slots: [None; ATTR_SLOTS],
+        }
+    }
+
+    /// Store the attribute from the response to a `GetAttr` for slot
+    /// `index`. Fails if the index is out of range, the response isn't a
+    /// `Response::GetAttr`, or its key or value are too long.
+    pub fn insert(&mut self, index: u8, response: &Response) -> Result<(), Error> {
+        let slot = self
+            .slots
+            .get_mut(index as usize)
+            .ok_or(Error::BadArguments)?;
+        let (key, value) = match *response {
+            Response::GetAttr { key, value } => (key, value),
+            _ => return Err(Error::BadArguments),
+        };
+        if key.len() > KEY_LEN || value.len() > MAX_ATTR_LEN {
+            return Err(Error::BadArguments);
+        }
+        let mut attr = Attr {
+            key: [0u8; KEY_LEN],
+            value: [0u8; MAX_ATTR_LEN],
+            len: value.len(),
+        };
+        attr.key[0..key.len()].copy_from_slice(key);
+        attr.value[0..value.len()].copy_from_slice(value);
+        *slot = Some(attr);
+        Ok(())
+    }
+
+    /// The attribute read from slot `index`, if we have it.
+    pub fn get(&self, index: u8) -> Option<&Attr> {
+        self.slots.get(index as usize)?.as_ref()
+    }
+
+    /// The attributes we have which are in use, with their indices. A
+    /// slot whose key is all zeroes is taken to be unused.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &Attr)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.as_ref().map(|attr| (i as u8, attr)))
+            .filter(|&(_, attr)| attr.key.iter().any(|&b| b != 0))
+    }
+}
+
+impl Default for AttrTable {
+    fn default() -> AttrTable {
+        AttrTable::new()
+    }
+}
+
+impl Attr {
+    /// The key, including any null padding.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The value.
+    pub fn value(&self) -> &[u8] {
+        &self.value[0..self.len]
+    }
+}
+
+impl<'a> CommandEncoder<'a> {
+    /// Create a new `CommandEncoder`.
+    ///
+    /// The encoder takes a reference to a `Command` to encode. The `next` method
+    /// will then supply the encoded bytes one at a time.
+    pub fn new(command: &'a Command) -> Result<CommandEncoder<'a>, Error> {
+        // We have to accept slices rather than arrays, so bounds check them
+        // all now to save surprises later.
+        match *command {
+            Command::WritePage { data, .. } if data.len() != INT_PAGE_SIZE => {
+                return Err(Error::BadArguments);
+            }
+            Command::WriteExPage { data, .. } if data.len() != EXT_PAGE_SIZE => {
+                return Err(Error::BadArguments);
+            }
+            #[cfg(feature = "partial-page")]
+            Command::WritePagePartial { len, data, .. }
+                if data.len() != len as usize || data.len() > INT_PAGE_SIZE => {
+                return Err(Error::BadArguments);
+            }
+            Command::ReadRange { length: 0, .. } | Command::ExReadRange { length: 0, .. } => {
+                return Err(Error::BadArguments);
+            }
+            // That would look like an escaped payload byte
+            Command::Unknown { opcode: ESCAPE_CHAR } => {
+                return Err(Error::BadArguments);
+            }
+            Command::SetAttr { index, key, value } => {
+                if index > MAX_INDEX {
+                    return Err(Error::BadArguments);
+                }
+                if key.len() != KEY_LEN {
+                    return Err(Error::BadArguments);
+                }
+                if value.len() > MAX_ATTR_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
+            _ => {}
+        };
+        Ok(CommandEncoder {
+            command,
+            count: 0,
+            sent_escape: false,
+        })
+    }
+
+    /// Reset the `CommandEncoder`, so that next time you call `self.next()`
+    /// you get the first byte again.
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.sent_escape = false;
+    }
+
+    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
+        if byte == ESCAPE_CHAR {
+            if self.sent_escape {
+                self.sent_escape = false;
+                (1, Some(ESCAPE_CHAR))
+            } else {
+                self.sent_escape = true;
+                (0, Some(ESCAPE_CHAR))
+            }
+        } else {
+            self.sent_escape = false;
+            (1, Some(byte))
+        }
+    }
+
+    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
+        match idx {
+            0 => self.render_byte(value as u8),
+            1 => self.render_byte((value >> 8) as u8),
+            _ => (0, None),
+        }
+    }
+
+    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
+        match idx {
+            0 => self.render_byte(value as u8),
+            1 => self.render_byte((value >> 8) as u8),
+            2 => self.render_byte((value >> 16) as u8),
+            3 => self.render_byte((value >> 24) as u8),
+            _ => (0, None),
+        }
+    }
+
+    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
+        if (idx < data.len()) && (idx < page_size) {
+            self.render_byte(data[idx])
+        } else if idx < page_size {
+            self.render_byte(0x00) // pad short data with nulls
+        } else {
+            (0, None)
+        }
+    }
+
+    fn render_basic_cmd(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
+        match count {
+            0 => (1, Some(ESCAPE_CHAR)), // Escape
+            1 => (1, Some(cmd)), // Command
+            _ => (0, None),
+        }
+    }
+
+    fn render_erasepage_cmd(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            _ => self.render_basic_cmd(count - 4, CMD_EPAGE),
+        }
+    }
+
+    fn render_writepage_cmd(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=515 => self.render_buffer(count - 4, INT_PAGE_SIZE, data),
+            _ => self.render_basic_cmd(count - 516, CMD_WPAGE),
+        }
+    }
+
+    fn render_eraseexblock(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            _ => self.render_basic_cmd(count - 4, CMD_XEBLOCK),
+        }
+    }
+
+    fn render_writeexpage(&mut self, address: u32, data: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=259 => self.render_buffer(count - 4, EXT_PAGE_SIZE, data),
+            _ => self.render_basic_cmd(count - (EXT_PAGE_SIZE + 4), CMD_XWPAGE),
+        }
+    }
+
+    #[cfg(feature = "partial-page")]
+    fn render_writepagepartial(
+        &mut self,
+        address: u32,
+        len: u16,
+        data: &[u8],
+    ) -> (usize, Option<u8>) {
+        let count = self.count;
+        let len = len as usize;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=5 => self.render_u16(count - 4, len as u16),
+            _ if count < 6 + len => self.render_buffer(count - 6, len, data),
+            _ => self.render_basic_cmd(count - (6 + len), CMD_WPAGE_PARTIAL),
+        }
+    }
+
+    fn render_readrange(&mut self, address: u32, length: u16) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=5 => self.render_u16(count - 4, length),
+            _ => self.render_basic_cmd(count - 6, CMD_RRANGE),
+        }
+    }
+
+    fn render_exreadrange(&mut self, address: u32, length: u16) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=5 => self.render_u16(count - 4, length),
+            _ => self.render_basic_cmd(count - 6, CMD_XRRANGE),
+        }
+    }
+
+    fn render_setattr(&mut self, index: u8, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        let max_len = if value.len() > MAX_ATTR_LEN {
+            MAX_ATTR_LEN
+        } else {
+            value.len()
+        };
+        match count {
+            0 => self.render_byte(index),
+            1..=8 => self.render_buffer(count - 1, KEY_LEN, key),
+            9 => self.render_byte(max_len as u8),
+            x if (max_len > 0) && (x < (max_len + 10)) => {
+                self.render_buffer(x - 10, max_len, value)
+            }
+            _ => self.render_basic_cmd(count - (10 + max_len), CMD_SATTR),
+        }
+    }
+
+    fn render_getattr(&mut self, index: u8) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0 => self.render_byte(index),
+            _ => self.render_basic_cmd(count - 1, CMD_GATTR),
+        }
+    }
+
+    fn render_crcintflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=7 => self.render_u32(count - 4, length),
+            _ => self.render_basic_cmd(count - 8, CMD_CRCIF),
+        }
+    }
+
+    fn render_crcextflash(&mut self, address: u32, length: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            4..=7 => self.render_u32(count - 4, length),
+            _ => self.render_basic_cmd(count - 8, CMD_CRCEF),
+        }
+    }
+
+    fn render_eraseexpage(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            _ => self.render_basic_cmd(count - 4, CMD_XEPAGE),
+        }
+    }
+
+    fn render_writeflashuserpages(&mut self, page1: u32, page2: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, page1),
+            4..=7 => self.render_u32(count - 4, page2),
+            _ => self.render_basic_cmd(count - 8, CMD_WUSER),
+        }
+    }
+
+    fn render_changebaud(&mut self, mode: BaudMode, baud: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0 => {
+                self.render_byte(match mode {
+                    BaudMode::Set => 0x01,
+                    BaudMode::Verify => 0x02,
+                })
+            }
+            1..=4 => self.render_u32(count - 1, baud),
+            _ => self.render_basic_cmd(count - 5, CMD_CHANGE_BAUD),
+        }
+    }
+
+    fn render_setstartaddress(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=3 => self.render_u32(count, address),
+            _ => self.render_basic_cmd(count - 4, CMD_SET_START_ADDRESS),
+        }
+    }
+
+    fn render_getversion(&mut self, version: u8) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0 => self.render_byte(version),
+            _ => self.render_basic_cmd(count - 1, CMD_GET_VERSION),
+        }
+    }
+}
+
+impl<'a> Iterator for CommandEncoder<'a> {
+    type Item = u8;
+
+    /// Supply the next encoded byte. Once all the bytes have been emitted, it
+    /// returns `None` forevermore.
+    fn next(&mut self) -> Option<u8> {
+        let count = self.count;
+        let (inc, result) = match *self.command {
+            Command::Ping => self.render_basic_cmd(count, CMD_PING),
+            Command::Info => self.render_basic_cmd(count, CMD_INFO),
+            Command::Id => self.render_basic_cmd(count, CMD_ID),
+            Command::Reset => self.render_basic_cmd(count, CMD_RESET),
+            Command::ErasePage { address } => self.render_erasepage_cmd(address),
+            Command::WritePage { address, data } => self.render_writepage_cmd(address, data),
+            Command::EraseExBlock { address } => self.render_eraseexblock(address),
+            Command::WriteExPage { address, data } => self.render_writeexpage(address, data),
+            #[cfg(feature = "partial-page")]
+            Command::WritePagePartial { address, len, data } => {
+                self.render_writepagepartial(address, len, data)
+            }
+            Command::CrcRxBuffer => self.render_basic_cmd(count, CMD_CRCRX),
+            Command::ReadRange { address, length } => self.render_readrange(address, length),
+            Command::ExReadRange { address, length } => self.render_exreadrange(address, length),
+            Command::SetAttr { index, key, value } => self.render_setattr(index, key, value),
+            Command::GetAttr { index } => self.render_getattr(index),
+            Command::CrcIntFlash { address, length } => self.render_crcintflash(address, length),
+            Command::CrcExtFlash { address, length } => self.render_crcextflash(address, length),
+            Command::EraseExPage { address } => self.render_eraseexpage(address),
+            Command::ExtFlashInit => self.render_basic_cmd(count, CMD_XFINIT),
+            Command::ClockOut => self.render_basic_cmd(count, CMD_CLKOUT),
+            Command::WriteFlashUserPages { page1, page2 } => {
+                self.render_writeflashuserpages(page1, page2)
+            }
+            Command::ChangeBaud { mode, baud } => self.render_changebaud(mode, baud),
+            Command::SetStartAddress { address } => self.render_setstartaddress(address),
+            Command::GetVersion { version } => self.render_getversion(version),
+            Command::Unknown { opcode } => self.render_basic_cmd(count, opcode),
+        };
+        self.count += inc;
+        result
+    }
+}
+
+#[cfg(feature = "seqnum")]
+impl<'a> TaggedEncoder<'a> {
+    /// Create a new `TaggedEncoder`, which sends `seq` before the command.
+    pub fn new(seq: u8, command: &'a Command) -> Result<TaggedEncoder<'a>, Error> {
+        Ok(TaggedEncoder {
+            seq,
+            pos: 0,
+            inner: CommandEncoder::new(command)?,
+        })
+    }
+}
+
+#[cfg(feature = "seqnum")]
+impl<'a> Iterator for TaggedEncoder<'a> {
+    type Item = u8;
+
+    /// Supply the next encoded byte.
+    fn next(&mut self) -> Option<u8> {
+        // The tag is escaped like any other payload byte
+        let tag_len = if self.seq == ESCAPE_CHAR { 2 } else { 1 };
+        if self.pos < tag_len {
+            self.pos += 1;
+            Some(self.seq)
+        } else {
+            self.inner.next()
+        }
+    }
+}
+
+#[cfg(feature = "seqnum")]
+impl<'a> Encoder for TaggedEncoder<'a> {
+    fn reset(&mut self) {
+        self.pos = 0;
+        self.inner.reset();
+    }
+}
+
+impl<'a> Encoder for CommandEncoder<'a> {
+    /// Reset the `Encoder`, so that next time you call `self.next()`
+    /// you get the first byte again.
+    fn reset(&mut self) {
+        self.count = 0;
+        self.sent_escape = false;
+    }
+}
+
+impl<'a> ResponseEncoder<'a> {
+    /// Create a new `ResponseEncoder`.
+    ///
+    /// The encoder takes a reference to a `Command` to encode. The `next` method
+    /// will then supply the encoded bytes one at a time.
+    pub fn new(response: &'a Response) -> Result<ResponseEncoder<'a>, Error> {
+        match *response {
+            Response::GetAttr { key, value } => {
+                if key.len() != KEY_LEN {
+                    return Err(Error::BadArguments);
+                }
+                if value.len() > MAX_ATTR_LEN {
+                    return Err(Error::BadArguments);
+                }
+            }
+            Response::Info { info } if info.len() > MAX_INFO_LEN => {
+                return Err(Error::BadArguments);
+            }
+            _ => {}
+        }
+        Ok(ResponseEncoder {
+            response,
+            count: 0,
+            sent_escape: false,
+        })
+    }
+
+    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
+        if byte == ESCAPE_CHAR {
+            if self.sent_escape {
+                self.sent_escape = false;
+                (1, Some(ESCAPE_CHAR))
+            } else {
+                self.sent_escape = true;
+                (0, Some(ESCAPE_CHAR))
+            }
+        } else {
+            (1, Some(byte))
+        }
+    }
+
+    fn render_crc_rx_buffer(&mut self, length: u16, crc: u32) -> (usize, Option<u8>) {
         let count = self.count;
         match count {
-            0...3 => self.render_u32(count, page1),
-            4...7 => self.render_u32(count - 4, page2),
-            _ => self.render_basic_cmd(count - 8, CMD_WUSER),
+            0..=1 => self.render_header(count, RES_CRCRX),
+            2..=3 => self.render_u16(count - 2, length),
+            4..=7 => self.render_u32(count - 4, crc),
+            _ => (0, None),
+        }
+    }
+
+    fn render_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_RRANGE),
+            x if x < data.len() + 2 => self.render_byte(data[x - 2]),
+            _ => (0, None),
+        }
+    }
+
+    fn render_ex_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_XRRANGE),
+            x if x - 2 < data.len() => self.render_byte(data[x - 2]),
+            _ => (0, None),
+        }
+    }
+
+    fn render_get_attr(&mut self, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_GATTR),
+            2..=9 => self.render_buffer(count - 2, 8, key),
+            10 => self.render_byte(value.len() as u8),
+            _ => self.render_buffer(count - 11, MAX_ATTR_LEN, value),
+        }
+    }
+
+    fn render_crc_int_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_CRCIF),
+            _ => self.render_u32(count - 2, crc),
+        }
+    }
+
+    fn render_version(&mut self, version: u8) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_VERSION),
+            2 => self.render_byte(version),
+            _ => (0, None),
+        }
+    }
+
+    fn render_crc_ex_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_CRCXF),
+            _ => self.render_u32(count - 2, crc),
+        }
+    }
+
+    fn render_info(&mut self, info: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0..=1 => self.render_header(count, RES_INFO),
+            2 => self.render_byte(info.len() as u8),
+            _ => self.render_buffer(count - 3, MAX_INFO_LEN, info),
+        }
+    }
+
+    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
+        match idx {
+            0 => self.render_byte(value as u8),
+            1 => self.render_byte((value >> 8) as u8),
+            _ => (0, None),
+        }
+    }
+
+    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
+        match idx {
+            0 => self.render_byte(value as u8),
+            1 => self.render_byte((value >> 8) as u8),
+            2 => self.render_byte((value >> 16) as u8),
+            3 => self.render_byte((value >> 24) as u8),
+            _ => (0, None),
+        }
+    }
+
+    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
+        if (idx < data.len()) && (idx < page_size) {
+            self.render_byte(data[idx])
+        } else if idx < page_size {
+            self.render_byte(0x00) // pad short data with nulls
+        } else {
+            (0, None)
+        }
+    }
+
+    fn render_header(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
+        match count {
+            0 => (1, Some(ESCAPE_CHAR)), // Escape
+            1 => (1, Some(cmd)), // Command
+            _ => (0, None),
+        }
+    }
+}
+
+impl<'a> Encoder for ResponseEncoder<'a> {
+    fn reset(&mut self) {
+        self.count = 0;
+        self.sent_escape = false;
+    }
+}
+
+impl<'a> Iterator for ResponseEncoder<'a> {
+    type Item = u8;
+
+    /// Supply the next encoded byte. Once all the bytes have been emitted, it
+    /// returns `None` forevermore.
+    fn next(&mut self) -> Option<u8> {
+        let count = self.count;
+        let (inc, result) = match *self.response {
+            Response::Overflow => self.render_header(count, RES_OVERFLOW),
+            Response::Pong => self.render_header(count, RES_PONG),
+            Response::BadAddress => self.render_header(count, RES_BADADDR),
+            Response::InternalError => self.render_header(count, RES_INTERROR),
+            Response::BadArguments => self.render_header(count, RES_BADARGS),
+            Response::Ok => self.render_header(count, RES_OK),
+            Response::Unknown => self.render_header(count, RES_UNKNOWN),
+            Response::ExtFlashTimeout => self.render_header(count, RES_XFTIMEOUT),
+            Response::ExtFlashPageError => self.render_header(count, RES_XFEPE),
+            Response::CrcRxBuffer { length, crc } => self.render_crc_rx_buffer(length, crc),
+            Response::ReadRange { data } => self.render_read_range(data),
+            Response::ExReadRange { data } => self.render_ex_read_range(data),
+            Response::GetAttr { key, value } => self.render_get_attr(key, value),
+            Response::CrcIntFlash { crc } => self.render_crc_int_flash(crc),
+            Response::CrcExtFlash { crc } => self.render_crc_ex_flash(crc),
+            Response::Info { info } => self.render_info(info),
+            Response::ChangeBaudFail => self.render_header(count, RES_CHANGE_BAUD_FAIL),
+            Response::Version { version } => self.render_version(version),
+        };
+        self.count += inc;
+        result
+    }
+}
+
+/// Helpers for handling protocol fields in your own code.
+pub mod util {
+    use byteorder::{BigEndian, LittleEndian, ByteOrder};
+    use Error;
+
+    /// Which way round the bytes in a word go.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Endian {
+        Little,
+        Big,
+    }
+
+    /// Read a little-endian `u32` from the start of `bytes`, as the protocol
+    /// sends addresses and lengths. Any bytes after the first four are
+    /// ignored. Returns `None` if there are fewer than four.
+    pub fn read_u32_le(bytes: &[u8]) -> Option<u32> {
+        bytes.get(0..4).map(LittleEndian::read_u32)
+    }
+
+    /// Read a little-endian `u16` from the start of `bytes`. Any bytes after
+    /// the first two are ignored. Returns `None` if there are fewer than two.
+    pub fn read_u16_le(bytes: &[u8]) -> Option<u16> {
+        bytes.get(0..2).map(LittleEndian::read_u16)
+    }
+
+    /// Go through `data` (e.g. the data in a `WritePage`) a 32-bit word at
+    /// a time, for flash controllers which write whole words. Returns
+    /// `Error::BadArguments` if the length isn't a multiple of four.
+    pub fn words(data: &[u8], endian: Endian) -> Result<impl Iterator<Item = u32> + '_, Error> {
+        // is_multiple_of needs a much newer compiler than the rest of the crate
+        #[allow(clippy::manual_is_multiple_of)]
+        if data.len() % 4 != 0 {
+            return Err(Error::BadArguments);
+        }
+        Ok(data.chunks_exact(4).map(move |word| match endian {
+            Endian::Little => LittleEndian::read_u32(word),
+            Endian::Big => BigEndian::read_u32(word),
+        }))
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(any(feature = "trace", feature = "error-history"))]
+impl<T: Copy, const N: usize> Ring<T, N> {
+    /// An empty ring. `fill` just occupies the unused slots, and is never
+    /// given back.
+    fn new(fill: T) -> Ring<T, N> {
+        Ring {
+            items: [fill; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Add an item, dropping the oldest if we're full.
+    fn push(&mut self, item: T) {
+        self.items[self.next] = item;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// The items, oldest first.
+    fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        let start = (self.next + N - self.len) % N;
+        (0..self.len).map(move |i| self.items[(start + i) % N])
+    }
+}
+
+#[cfg(feature = "trace")]
+impl TraceBuffer {
+    fn empty() -> TraceBuffer {
+        Ring::new(TraceEntry {
+            escaped: false,
+            byte: 0,
+            action: TraceAction::Ignored,
+        })
+    }
+
+    /// Record a byte, dropping the oldest entry if we're full.
+    fn record(&mut self, escaped: bool, byte: u8, action: TraceAction) {
+        self.push(TraceEntry {
+            escaped,
+            byte,
+            action,
+        });
+    }
+}
+
+#[cfg(feature = "error-history")]
+impl ErrorHistory {
+    fn empty() -> ErrorHistory {
+        Ring::new(ErrorRecord {
+            error: Error::Incomplete,
+            opcode: None,
+            offset: 0,
+        })
+    }
+
+    /// Record the error in `result`, if it is one.
+    fn record<T>(&mut self, result: &Result<T, Error>, opcode: Option<u8>, offset: usize) {
+        if let Err(error) = *result {
+            self.push(ErrorRecord {
+                error,
+                opcode,
+                offset,
+            });
+        }
+    }
+}
+
+/// Which sort of `Command` an opcode decodes to, if it's one this build
+/// understands.
+fn command_kind(opcode: u8) -> Option<CommandKind> {
+    match opcode {
+        CMD_PING => Some(CommandKind::Ping),
+        CMD_INFO => Some(CommandKind::Info),
+        CMD_ID => Some(CommandKind::Id),
+        CMD_RESET => Some(CommandKind::Reset),
+        #[cfg(feature = "write")]
+        CMD_EPAGE => Some(CommandKind::ErasePage),
+        #[cfg(feature = "write")]
+        CMD_WPAGE => Some(CommandKind::WritePage),
+        #[cfg(feature = "write")]
+        CMD_XEBLOCK => Some(CommandKind::EraseExBlock),
+        #[cfg(feature = "write")]
+        CMD_XWPAGE => Some(CommandKind::WriteExPage),
+        #[cfg(feature = "partial-page")]
+        CMD_WPAGE_PARTIAL => Some(CommandKind::WritePagePartial),
+        CMD_CRCRX => Some(CommandKind::CrcRxBuffer),
+        CMD_RRANGE => Some(CommandKind::ReadRange),
+        CMD_XRRANGE => Some(CommandKind::ExReadRange),
+        CMD_SATTR => Some(CommandKind::SetAttr),
+        CMD_GATTR => Some(CommandKind::GetAttr),
+        CMD_CRCIF => Some(CommandKind::CrcIntFlash),
+        CMD_CRCEF => Some(CommandKind::CrcExtFlash),
+        #[cfg(feature = "write")]
+        CMD_XEPAGE => Some(CommandKind::EraseExPage),
+        CMD_XFINIT => Some(CommandKind::ExtFlashInit),
+        CMD_CLKOUT => Some(CommandKind::ClockOut),
+        #[cfg(feature = "write")]
+        CMD_WUSER => Some(CommandKind::WriteFlashUserPages),
+        CMD_CHANGE_BAUD => Some(CommandKind::ChangeBaud),
+        CMD_SET_START_ADDRESS => Some(CommandKind::SetStartAddress),
+        CMD_GET_VERSION => Some(CommandKind::GetVersion),
+        _ => None,
+    }
+}
+
+/// Turn an opcode and its de-escaped payload into a `Command`. Returns
+/// `Ok(None)` for opcodes we don't recognise.
+fn decode_command(opcode: u8, buffer: &[u8]) -> Result<Option<Command<'_>>, Error> {
+    match opcode {
+        CMD_PING => Ok(Some(Command::Ping)),
+        CMD_INFO => Ok(Some(Command::Info)),
+        CMD_ID => Ok(Some(Command::Id)),
+        CMD_RESET => Ok(Some(Command::Reset)),
+        #[cfg(feature = "write")]
+        CMD_EPAGE => {
+            let num_expected_bytes: usize = 4;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                Ok(Some(Command::ErasePage { address }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        #[cfg(feature = "write")]
+        CMD_WPAGE => {
+            if buffer.len() == 4 {
+                return Err(Error::EmptyPage);
+            }
+            // let num_expected_bytes: usize = INT_PAGE_SIZE + 4;
+            // if buffer.len() == num_expected_bytes {
+                let address = read_u32_le(buffer, 0)?;
+                Ok(Some(Command::WritePage {
+                    address,
+                    data: &buffer[4..],
+                }))
+            // } else {
+            //     Err(Error::BadArguments)
+            // }
+        }
+        #[cfg(feature = "write")]
+        CMD_XEBLOCK => {
+            let num_expected_bytes: usize = 4;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                Ok(Some(Command::EraseExBlock { address }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        #[cfg(feature = "write")]
+        CMD_XWPAGE => {
+            let num_expected_bytes: usize = EXT_PAGE_SIZE + 4;
+            if buffer.len() == num_expected_bytes {
+                let payload = &buffer[0..num_expected_bytes];
+                let address = LittleEndian::read_u32(&payload[0..4]);
+                Ok(Some(Command::WriteExPage {
+                    address,
+                    data: &payload[4..num_expected_bytes],
+                }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_CRCRX => Ok(Some(Command::CrcRxBuffer)),
+        CMD_RRANGE => {
+            let num_expected_bytes: usize = 6;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                let length = LittleEndian::read_u16(&buffer[4..6]);
+                if length == 0 {
+                    // Nothing to read - almost certainly a mistake
+                    return Err(Error::BadArguments);
+                }
+                Ok(Some(Command::ReadRange { address, length }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_XRRANGE => {
+            let num_expected_bytes: usize = 6;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                let length = LittleEndian::read_u16(&buffer[4..6]);
+                if length == 0 {
+                    // Nothing to read - almost certainly a mistake
+                    return Err(Error::BadArguments);
+                }
+                Ok(Some(Command::ExReadRange { address, length }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_SATTR => {
+            let num_expected_bytes: usize = 10;
+            if buffer.len() >= num_expected_bytes {
+                let index = buffer[0];
+                let key = &buffer[1..9];
+                let length = buffer[9] as usize;
+                if buffer.len() == (num_expected_bytes + length) {
+                    let value = &buffer[10..10 + length];
+                    Ok(Some(Command::SetAttr { index, key, value }))
+                } else {
+                    Err(Error::BadArguments)
+                }
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_GATTR => {
+            let num_expected_bytes: usize = 1;
+            if buffer.len() == num_expected_bytes {
+                let index = buffer[0];
+                Ok(Some(Command::GetAttr { index }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_CRCIF => {
+            let num_expected_bytes: usize = 8;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                let length = LittleEndian::read_u32(&buffer[4..8]);
+                Ok(Some(Command::CrcIntFlash { address, length }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_CRCEF => {
+            let num_expected_bytes: usize = 8;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                let length = LittleEndian::read_u32(&buffer[4..8]);
+                Ok(Some(Command::CrcExtFlash { address, length }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        #[cfg(feature = "write")]
+        CMD_XEPAGE => {
+            let num_expected_bytes: usize = 4;
+            if buffer.len() == num_expected_bytes {
+                let address = LittleEndian::read_u32(&buffer[0..4]);
+                Ok(Some(Command::EraseExPage { address }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_XFINIT => Ok(Some(Command::ExtFlashInit)),
+        CMD_CLKOUT => Ok(Some(Command::ClockOut)),
+        #[cfg(feature = "write")]
+        CMD_WUSER => {
+            let num_expected_bytes: usize = 8;
+            if buffer.len() == num_expected_bytes {
+                let page1 = LittleEndian::read_u32(&buffer[0..4]);
+                let page2 = LittleEndian::read_u32(&buffer[4..8]);
+                Ok(Some(Command::WriteFlashUserPages { page1, page2 }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_CHANGE_BAUD => {
+            let num_expected_bytes: usize = 5;
+            if buffer.len() == num_expected_bytes {
+                let mode = buffer[0];
+                let baud = LittleEndian::read_u32(&buffer[1..5]);
+                match mode {
+                    0x01 => Ok(Some(Command::ChangeBaud {
+                        mode: BaudMode::Set,
+                        baud,
+                    })),
+                    0x02 => Ok(Some(Command::ChangeBaud {
+                        mode: BaudMode::Verify,
+                        baud,
+                    })),
+                    _ => Err(Error::BadArguments),
+
+                }
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        #[cfg(feature = "partial-page")]
+        CMD_WPAGE_PARTIAL => {
+            if buffer.len() < 6 {
+                return Err(Error::BadArguments);
+            }
+            let address = LittleEndian::read_u32(&buffer[0..4]);
+            let len = LittleEndian::read_u16(&buffer[4..6]);
+            let data = &buffer[6..];
+            if data.len() != len as usize || data.len() > INT_PAGE_SIZE {
+                return Err(Error::BadArguments);
+            }
+            Ok(Some(Command::WritePagePartial { address, len, data }))
+        }
+        #[cfg(not(feature = "write"))]
+        CMD_EPAGE | CMD_WPAGE | CMD_XEBLOCK | CMD_XWPAGE | CMD_XEPAGE | CMD_WUSER => {
+            Err(Error::Unsupported)
+        }
+        CMD_SET_START_ADDRESS => {
+            let num_expected_bytes: usize = 4;
+            if buffer.len() == num_expected_bytes {
+                let address = read_u32_le(buffer, 0)?;
+                Ok(Some(Command::SetStartAddress { address }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        CMD_GET_VERSION => {
+            if buffer.len() == 1 {
+                Ok(Some(Command::GetVersion { version: buffer[0] }))
+            } else {
+                Err(Error::BadArguments)
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Read the little-endian `u32` at `buffer[start..start + 4]`, or give
+/// `Error::BadArguments` if the buffer is too short.
+fn read_u32_le(buffer: &[u8], start: usize) -> Result<u32, Error> {
+    buffer
+        .get(start..)
+        .and_then(util::read_u32_le)
+        .ok_or(Error::BadArguments)
+}
+
+/// Overwrite `data` with zeroes.
+#[cfg(not(feature = "zeroize"))]
+fn wipe(data: &mut [u8]) {
+    data.fill(0);
+}
+
+/// Overwrite `data` with zeroes, in a way the compiler won't remove.
+#[cfg(feature = "zeroize")]
+fn wipe(data: &mut [u8]) {
+    use zeroize::Zeroize;
+    data.zeroize();
+}
+
+/// Read bytes from `source` until there's a whole response, and give it to
+/// `f`.
+#[cfg(all(feature = "std", feature = "crc"))]
+fn read_response<R, F, T>(
+    decoder: &mut ResponseDecoder,
+    source: &mut R,
+    f: F,
+) -> Result<T, FlashError>
+where
+    R: std::io::Read,
+    F: FnOnce(&Response) -> T,
+{
+    let mut byte = [0u8; 1];
+    loop {
+        source.read_exact(&mut byte)?;
+        if let Some(ref response) = decoder.receive(byte[0])? {
+            return Ok(f(response));
+        }
+    }
+}
+
+/// Add one byte to a running CRC-16.
+#[cfg(feature = "crc")]
+fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ (u16::from(byte) << 8);
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 {
+            (crc << 1) ^ CRC16_POLY
+        } else {
+            crc << 1
+        };
+    }
+    crc
+}
+
+/// Add one byte to a running CRC-32. This is the bitwise version, which is
+/// slower than a table but saves 1 KiB of flash.
+#[cfg(feature = "crc")]
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    let mut crc = crc ^ u32::from(byte);
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 {
+            (crc >> 1) ^ CRC32_POLY
+        } else {
+            crc >> 1
+        };
+    }
+    crc
+}
+
+/// The value of one hex digit.
+#[cfg(feature = "hex")]
+fn hex_digit(digit: u8) -> Result<u8, Error> {
+    match (digit as char).to_digit(16) {
+        Some(value) => Ok(value as u8),
+        None => Err(Error::BadArguments),
+    }
+}
+
+/// Write `data` into `out` as upper-case hex digits, returning the text.
+fn write_hex<'b>(data: &[u8], out: &'b mut [u8]) -> &'b str {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    let mut used = 0;
+    for (byte, pair) in data.iter().zip(out.chunks_mut(2)) {
+        if pair.len() < 2 {
+            break;
+        }
+        pair[0] = DIGITS[(byte >> 4) as usize];
+        pair[1] = DIGITS[(byte & 0x0F) as usize];
+        used += 2;
+    }
+    // Only ASCII hex digits have been written, so this cannot fail
+    core::str::from_utf8(&out[0..used]).unwrap_or("")
+}
+
+#[cfg(test)]
+// The tests predate these lints and are kept as written
+#[allow(clippy::needless_borrow, clippy::unnecessary_cast)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_cmd_ping() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_PING) {
+            Ok(Some(Command::Ping)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_ping() {
+        let cmd = Command::Ping;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_PING));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_info() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_INFO) {
+            Ok(Some(Command::Info)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_info() {
+        let cmd = Command::Info;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_INFO));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_id() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_ID) {
+            Ok(Some(Command::Id)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_id() {
+        let cmd = Command::Id;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_ID));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_reset() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_RESET) {
+            Ok(Some(Command::Reset)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn decode_cmd_reset_ignores_payload() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_RESET), Ok(Some(Command::Reset)));
+        assert_eq!(p.count, 0);
+        // Several stray bytes, including an escaped one
+        for &ch in &[0xAA, 0xBB, ESCAPE_CHAR, ESCAPE_CHAR, 0xCC] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_RESET), Ok(Some(Command::Reset)));
+        assert!(!p.is_mid_frame());
+        // None of them leak into the next frame
+        for &ch in &[0x00, 0x00, 0x03, 0x00] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive(CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress {
+                address: 0x0003_0000,
+            }))
+        );
+    }
+
+    #[test]
+    fn encode_cmd_reset() {
+        let cmd = Command::Reset;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_RESET));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_erase_page() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_EPAGE) {
+            Ok(Some(Command::ErasePage { address })) => {
+                assert_eq!(address, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_erase_page_zero() {
+        let mut p = CommandDecoder::new();
+        for _ in 0..4 {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(
+            p.receive(CMD_EPAGE),
+            Ok(Some(Command::ErasePage { address: 0 }))
+        );
+    }
+
+    #[test]
+    fn encode_cmd_erase_page() {
+        let cmd = Command::ErasePage { address: 0xDEADBEEF };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_EPAGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_write_page() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        for i in 0..INT_PAGE_SIZE {
+            let datum = i as u8;
+            assert_eq!(p.receive(datum), Ok(None));
+            if datum == ESCAPE_CHAR {
+                assert_eq!(p.receive(datum), Ok(None));
+            }
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_WPAGE) {
+            Ok(Some(Command::WritePage {
+                        address,
+                        data: ref page,
+                    })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(page.len(), INT_PAGE_SIZE);
+                for i in 0..INT_PAGE_SIZE {
+                    let datum = i as u8;
+                    assert_eq!(datum, page[i as usize]);
+                }
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_write_page_all_escapes() {
+        let mut p = CommandDecoder::new();
+        for &b in &[0xEF, 0xBE, 0xAD, 0xDE] {
+            assert_eq!(p.receive(b), Ok(None));
+        }
+        for _ in 0..INT_PAGE_SIZE {
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_WPAGE) {
+            Ok(Some(Command::WritePage { address, data })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(data, &[ESCAPE_CHAR; INT_PAGE_SIZE][..]);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_write_page_sized() {
+        assert!(CommandDecoder::new_with_page_size(8192).is_err());
+        assert!(CommandDecoder::new_with_page_size(usize::MAX).is_err());
+        let largest = CMD_BUFFER_LEN - MAX_TAG_LEN - 4 - MAX_CHECKSUM_LEN;
+        assert!(CommandDecoder::new_with_page_size(largest).is_ok());
+        assert!(CommandDecoder::new_with_page_size(largest + 1).is_err());
+        let mut p = CommandDecoder::new_with_page_size(256).unwrap();
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        for i in 0..256 {
+            assert_eq!(p.receive(i as u8), Ok(None));
+            if i == ESCAPE_CHAR as usize {
+                assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            }
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_WPAGE) {
+            Ok(Some(Command::WritePage { address, data })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(data.len(), 256);
+                for (i, datum) in data.iter().enumerate() {
+                    assert_eq!(i as u8, *datum);
+                }
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+        // A 512 byte page is now the wrong size
+        for _ in 0..(4 + INT_PAGE_SIZE) {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::BadArguments));
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_write_page_empty() {
+        let mut p = CommandDecoder::new();
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::EmptyPage));
+        // Just as empty with a fixed page size
+        let mut p = CommandDecoder::new_with_page_size(256).unwrap();
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::EmptyPage));
+        // A shorter frame is still just bad
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn encode_cmd_write_page() {
+        let mut buffer = [0xBBu8; INT_PAGE_SIZE];
+        buffer[0] = 0xAA;
+        buffer[INT_PAGE_SIZE - 1] = 0xCC;
+        let cmd = Command::WritePage {
+            address: 0xDEADBEEF,
+            data: &buffer,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // first byte of data
+        assert_eq!(e.next(), Some(0xAA));
+        for _ in 1..(INT_PAGE_SIZE - 1) {
+            assert_eq!(e.next(), Some(0xBB));
+        }
+        // last byte of data
+        assert_eq!(e.next(), Some(0xCC));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_WPAGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_erase_block() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_XEBLOCK) {
+            Ok(Some(Command::EraseExBlock { address })) => {
+                assert_eq!(address, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_erase_block() {
+        let cmd = Command::EraseExBlock { address: 0xDEADBEEF };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XEBLOCK));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_write_ex_page() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        for i in 0..EXT_PAGE_SIZE {
+            let datum = i as u8;
+            assert_eq!(p.receive(datum), Ok(None));
+            if datum == ESCAPE_CHAR {
+                assert_eq!(p.receive(datum), Ok(None));
+            }
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_XWPAGE) {
+            Ok(Some(Command::WriteExPage {
+                        address,
+                        data: ref page,
+                    })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(page.len(), EXT_PAGE_SIZE);
+                for i in 0..EXT_PAGE_SIZE {
+                    let datum = i as u8;
+                    assert_eq!(datum, page[i as usize]);
+                }
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_write_ex_page() {
+        let mut buffer = [0xBBu8; EXT_PAGE_SIZE];
+        buffer[0] = 0xAA;
+        buffer[EXT_PAGE_SIZE - 1] = 0xCC;
+        let cmd = Command::WriteExPage {
+            address: 0xDEADBEEF,
+            data: &buffer,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // first byte of data
+        assert_eq!(e.next(), Some(0xAA));
+        for _ in 1..(EXT_PAGE_SIZE - 1) {
+            assert_eq!(e.next(), Some(0xBB));
+        }
+        // last byte of data
+        assert_eq!(e.next(), Some(0xCC));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XWPAGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn encode_cmd_crcrx() {
+        let cmd = Command::CrcRxBuffer;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_CRCRX));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_crcrx() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_CRCRX) {
+            Ok(Some(Command::CrcRxBuffer)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_rrange() {
+        let cmd = Command::ReadRange {
+            address: 0xDEADBEEF,
+            length: 0x1234,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // 2 byte length
+        assert_eq!(e.next(), Some(0x34));
+        assert_eq!(e.next(), Some(0x12));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_RRANGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_rrange() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_RRANGE) {
+            Ok(Some(Command::ReadRange { address, length })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(length, 0x1234);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_xrrange() {
+        let cmd = Command::ExReadRange {
+            address: 0xDEADBEEF,
+            length: 0x1234,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // 2 byte length
+        assert_eq!(e.next(), Some(0x34));
+        assert_eq!(e.next(), Some(0x12));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XRRANGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_xrrange() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_XRRANGE) {
+            Ok(Some(Command::ExReadRange { address, length })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(length, 0x1234);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn check_cmd_rrange_zero_length() {
+        for &opcode in &[CMD_RRANGE, CMD_XRRANGE] {
+            let mut p = CommandDecoder::new();
+            for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00, ESCAPE_CHAR] {
+                assert_eq!(p.receive(ch), Ok(None));
+            }
+            assert_eq!(p.receive(opcode), Err(Error::BadArguments));
+        }
+        let cmd = Command::ReadRange {
+            address: 0xDEADBEEF,
+            length: 0,
+        };
+        assert!(CommandEncoder::new(&cmd).is_err());
+        let cmd = Command::ExReadRange {
+            address: 0xDEADBEEF,
+            length: 0,
+        };
+        assert!(CommandEncoder::new(&cmd).is_err());
+    }
+
+    #[test]
+    fn encode_cmd_sattr() {
+        let r = Command::SetAttr {
+            index: MAX_INDEX,
+            key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+            value: &[0xAA, 0xBB, 0xCC, 0xDD],
+        };
+        let mut e = CommandEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(MAX_INDEX));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x11));
+        assert_eq!(e.next(), Some(0x22));
+        assert_eq!(e.next(), Some(0x33));
+        assert_eq!(e.next(), Some(0x44));
+        assert_eq!(e.next(), Some(0x55));
+        assert_eq!(e.next(), Some(0x66));
+        assert_eq!(e.next(), Some(0x77));
+        assert_eq!(e.next(), Some(0x04));
+        assert_eq!(e.next(), Some(0xAA));
+        assert_eq!(e.next(), Some(0xBB));
+        assert_eq!(e.next(), Some(0xCC));
+        assert_eq!(e.next(), Some(0xDD));
+        // Commands don't seem to need padding
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_SATTR));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_sattr() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(MAX_INDEX), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x11), Ok(None));
+        assert_eq!(p.receive(0x22), Ok(None));
+        assert_eq!(p.receive(0x33), Ok(None));
+        assert_eq!(p.receive(0x44), Ok(None));
+        assert_eq!(p.receive(0x55), Ok(None));
+        assert_eq!(p.receive(0x66), Ok(None));
+        assert_eq!(p.receive(0x77), Ok(None));
+        assert_eq!(p.receive(0x04), Ok(None));
+        assert_eq!(p.receive(0xAA), Ok(None));
+        assert_eq!(p.receive(0xBB), Ok(None));
+        assert_eq!(p.receive(0xCC), Ok(None));
+        assert_eq!(p.receive(0xDD), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_SATTR) {
+            Ok(Some(Command::SetAttr { index, key, value })) => {
+                assert_eq!(index, MAX_INDEX);
+                assert_eq!(key, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+                assert_eq!(value, [0xAA, 0xBB, 0xCC, 0xDD]);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_gattr() {
+        let r = Command::GetAttr { index: MAX_INDEX };
+        let mut e = CommandEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(MAX_INDEX));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_GATTR));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_gattr() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(MAX_INDEX), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_GATTR) {
+            Ok(Some(Command::GetAttr { index })) => {
+                assert_eq!(index, MAX_INDEX);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_crcif() {
+        let cmd = Command::CrcIntFlash {
+            address: 0xDEADBEEF,
+            length: 0x12345678,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // 4 byte length
+        assert_eq!(e.next(), Some(0x78));
+        assert_eq!(e.next(), Some(0x56));
+        assert_eq!(e.next(), Some(0x34));
+        assert_eq!(e.next(), Some(0x12));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_CRCIF));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_crcif() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(0x78), Ok(None));
+        assert_eq!(p.receive(0x56), Ok(None));
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_CRCIF) {
+            Ok(Some(Command::CrcIntFlash { address, length })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(length, 0x12345678);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_crcef() {
+        let cmd = Command::CrcExtFlash {
+            address: 0xDEADBEEF,
+            length: 0x12345678,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // 4 byte length
+        assert_eq!(e.next(), Some(0x78));
+        assert_eq!(e.next(), Some(0x56));
+        assert_eq!(e.next(), Some(0x34));
+        assert_eq!(e.next(), Some(0x12));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_CRCEF));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_crcef() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(0x78), Ok(None));
+        assert_eq!(p.receive(0x56), Ok(None));
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_CRCEF) {
+            Ok(Some(Command::CrcExtFlash { address, length })) => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(length, 0x12345678);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_xepage() {
+        let cmd = Command::EraseExPage { address: 0xDEADBEEF };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XEPAGE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_xepage() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_XEPAGE) {
+            Ok(Some(Command::EraseExPage { address })) => {
+                assert_eq!(address, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_xfinit() {
+        let cmd = Command::ExtFlashInit {};
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_XFINIT));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_xfinit() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_XFINIT) {
+            Ok(Some(Command::ExtFlashInit)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_clkout() {
+        let cmd = Command::ClockOut {};
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_CLKOUT));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_clkout() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_CLKOUT) {
+            Ok(Some(Command::ClockOut)) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_wuser() {
+        let cmd = Command::WriteFlashUserPages {
+            page1: 0xDEADBEEF,
+            page2: 0x12345678,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        // 4 byte length
+        assert_eq!(e.next(), Some(0x78));
+        assert_eq!(e.next(), Some(0x56));
+        assert_eq!(e.next(), Some(0x34));
+        assert_eq!(e.next(), Some(0x12));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_WUSER));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn decode_cmd_wuser() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(0x78), Ok(None));
+        assert_eq!(p.receive(0x56), Ok(None));
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_WUSER) {
+            Ok(Some(Command::WriteFlashUserPages { page1, page2 })) => {
+                assert_eq!(page1, 0xDEADBEEF);
+                assert_eq!(page2, 0x12345678);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_change_baud() {
+        let cmd = Command::ChangeBaud {
+            mode: BaudMode::Set,
+            baud: 0xDEADBEEF,
+        };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // Mode
+        assert_eq!(e.next(), Some(0x01));
+        // 4 byte baud (0x0001 C200)
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_CHANGE_BAUD));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_change_baud() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_CHANGE_BAUD) {
+            Ok(Some(Command::ChangeBaud { mode, baud })) => {
+                assert_eq!(mode, BaudMode::Set);
+                assert_eq!(baud, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+        assert_eq!(p.receive(0x02), Ok(None));
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_CHANGE_BAUD) {
+            Ok(Some(Command::ChangeBaud { mode, baud })) => {
+                assert_eq!(mode, BaudMode::Verify);
+                assert_eq!(baud, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+    #[test]
+    fn encode_cmd_set_start_address() {
+        let cmd = Command::SetStartAddress { address: 0xDEADBEEF };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_SET_START_ADDRESS));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_set_start_address() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(p.receive(0xDE), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_SET_START_ADDRESS) {
+            Ok(Some(Command::SetStartAddress { address })) => {
+                assert_eq!(address, 0xDEADBEEF);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn check_set_start_address_replies() {
+        // The bootloader answers with either OK or BADADDR
+        for response in &[Response::Ok, Response::BadAddress] {
+            let mut buffer = [0u8; 8];
+            let used = ResponseEncoder::new(response).unwrap().write(&mut buffer);
+            let mut p = ResponseDecoder::new();
+            assert_eq!(p.receive(buffer[0]), Ok(None));
+            match p.receive(buffer[1]) {
+                Ok(Some(ref x)) if x == response => {}
+                e => panic!("Did not expect: {:?}", e),
+            }
+            assert_eq!(used, 2);
+        }
+    }
+
+    // Responses
+
+    fn check_rsp_generic(response: Response, cmd: u8) {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(cmd) {
+            Ok(Some(ref x)) if x == &response => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+
+        let mut e = ResponseEncoder::new(&response).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(cmd));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_overflow() {
+        check_rsp_generic(Response::Overflow, RES_OVERFLOW);
+    }
+
+    #[test]
+    fn check_rsp_pong() {
+        check_rsp_generic(Response::Pong, RES_PONG);
+    }
+
+    #[test]
+    fn check_rsp_badaddress() {
+        check_rsp_generic(Response::BadAddress, RES_BADADDR);
+    }
+
+    #[test]
+    fn check_rsp_internalerror() {
+        check_rsp_generic(Response::InternalError, RES_INTERROR);
+    }
+
+    #[test]
+    fn check_rsp_badarguments() {
+        check_rsp_generic(Response::BadArguments, RES_BADARGS);
+    }
+
+    #[test]
+    fn check_rsp_ok() {
+        check_rsp_generic(Response::Ok, RES_OK);
+    }
+
+    #[test]
+    fn check_rsp_unknown() {
+        check_rsp_generic(Response::Unknown, RES_UNKNOWN);
+    }
+
+    #[test]
+    fn check_rsp_exflashtimeout() {
+        check_rsp_generic(Response::ExtFlashTimeout, RES_XFTIMEOUT);
+    }
+
+    #[test]
+    fn check_rsp_exflashpageerror() {
+        check_rsp_generic(Response::ExtFlashPageError, RES_XFEPE);
+    }
+
+    #[test]
+    fn check_rsp_changebaudfail() {
+        check_rsp_generic(Response::ChangeBaudFail, RES_CHANGE_BAUD_FAIL);
+    }
+
+    #[test]
+    fn check_rsp_changebaudfail_round_trip() {
+        // A host waiting on a baud change sees the failure, then the
+        // bootloader carries on at the old rate
+        let mut p = ResponseDecoder::new();
+        let mut decoded = 0;
+        for response in &[Response::ChangeBaudFail, Response::Pong] {
+            for ch in ResponseEncoder::new(response).unwrap() {
+                if let Some(r) = p.receive(ch).unwrap() {
+                    assert_eq!(&r, response);
+                    decoded += 1;
+                }
+            }
+        }
+        assert_eq!(decoded, 2);
+    }
+
+    #[test]
+    fn check_rsp_crc_rx() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_CRCRX), Ok(None));
+        // Length
+        assert_eq!(p.receive(0x34), Ok(None));
+        assert_eq!(p.receive(0x12), Ok(None));
+        // CRC
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(
+            p.receive(0xDE),
+            Ok(Some(Response::CrcRxBuffer {
+                length: 0x1234,
+                crc: 0xDEADBEEF,
+            }))
+        );
+
+        let r = Response::CrcRxBuffer {
+            length: 0x1234,
+            crc: 0xDEADBEEF,
+        };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_CRCRX));
+        assert_eq!(e.next(), Some(0x34));
+        assert_eq!(e.next(), Some(0x12));
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_crc_rx_round_trip() {
+        // Escape characters in both fields
+        let mut p = ResponseDecoder::new();
+        let mut decoded = 0;
+        for &(length, crc) in &[(0x01FC, 0xFC00_FCFC), (0xFCFC, 0x0000_00FC), (0, 0)] {
+            let r = Response::CrcRxBuffer { length, crc };
+            for ch in ResponseEncoder::new(&r).unwrap() {
+                if let Some(rsp) = p.receive(ch).unwrap() {
+                    assert_eq!(rsp, r);
+                    decoded += 1;
+                }
+            }
+        }
+        assert_eq!(decoded, 3);
+    }
+
+    #[test]
+    fn check_rsp_rrange() {
+        let mut p = ResponseDecoder::new();
+        p.set_payload_len(4).unwrap();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_RRANGE), Ok(None));
+        // four bytes of data
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x11), Ok(None));
+        assert_eq!(p.receive(0x22), Ok(None));
+        assert_eq!(
+            p.receive(0x33),
+            Ok(Some(
+                Response::ReadRange { data: &[0x00, 0x11, 0x22, 0x33] },
+            ))
+        );
+
+        let r = Response::ReadRange { data: &[0x00, 0x11, 0x22, 0x33] };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_RRANGE));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x11));
+        assert_eq!(e.next(), Some(0x22));
+        assert_eq!(e.next(), Some(0x33));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_xrrange() {
+        let mut p = ResponseDecoder::new();
+        p.set_payload_len(4).unwrap();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_XRRANGE), Ok(None));
+        // four bytes of data
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x11), Ok(None));
+        assert_eq!(p.receive(0x22), Ok(None));
+        assert_eq!(
+            p.receive(0x33),
+            Ok(Some(
+                Response::ExReadRange { data: &[0x00, 0x11, 0x22, 0x33] },
+            ))
+        );
+
+        let r = Response::ExReadRange { data: &[0x00, 0x11, 0x22, 0x33] };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_XRRANGE));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x11));
+        assert_eq!(e.next(), Some(0x22));
+        assert_eq!(e.next(), Some(0x33));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_get_attr() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_GATTR), Ok(None));
+        // eight bytes of key
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x11), Ok(None));
+        assert_eq!(p.receive(0x22), Ok(None));
+        assert_eq!(p.receive(0x33), Ok(None));
+        assert_eq!(p.receive(0x44), Ok(None));
+        assert_eq!(p.receive(0x55), Ok(None));
+        assert_eq!(p.receive(0x66), Ok(None));
+        assert_eq!(p.receive(0x77), Ok(None));
+        // Length byte
+        assert_eq!(p.receive(0x04), Ok(None));
+        // length bytes of data
+        assert_eq!(p.receive(0xAA), Ok(None));
+        assert_eq!(p.receive(0xBB), Ok(None));
+        assert_eq!(p.receive(0xCC), Ok(None));
+        assert_eq!(p.receive(0xDD), Ok(None));
+        // 55 - length bytes of padding
+        for _ in 4..MAX_ATTR_LEN - 1 {
+            assert_eq!(p.receive(0xFF), Ok(None));
+        }
+        assert_eq!(
+            p.receive(0xFF),
+            Ok(Some(Response::GetAttr {
+                key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+                value: &[0xAA, 0xBB, 0xCC, 0xDD],
+            }))
+        );
+
+        let r = Response::GetAttr {
+            key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+            value: &[0xAA, 0xBB, 0xCC, 0xDD],
+        };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_GATTR));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x11));
+        assert_eq!(e.next(), Some(0x22));
+        assert_eq!(e.next(), Some(0x33));
+        assert_eq!(e.next(), Some(0x44));
+        assert_eq!(e.next(), Some(0x55));
+        assert_eq!(e.next(), Some(0x66));
+        assert_eq!(e.next(), Some(0x77));
+        assert_eq!(e.next(), Some(0x04));
+        assert_eq!(e.next(), Some(0xAA));
+        assert_eq!(e.next(), Some(0xBB));
+        assert_eq!(e.next(), Some(0xCC));
+        assert_eq!(e.next(), Some(0xDD));
+        for _ in 4..MAX_ATTR_LEN {
+            assert!(e.next().is_some());
         }
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
     }
 
-    fn render_changebaud(&mut self, mode: BaudMode, baud: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0 => {
-                self.render_byte(match mode {
-                    BaudMode::Set => 0x01,
-                    BaudMode::Verify => 0x02,
-                })
+    #[test]
+    fn check_version_exchange() {
+        // Host to bootloader
+        let cmd = Command::GetVersion { version: 2 };
+        let mut buffer = [0u8; 8];
+        let used = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(&buffer[0..used], &[0x02, ESCAPE_CHAR, CMD_GET_VERSION]);
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0x02), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_GET_VERSION), Ok(Some(cmd)));
+
+        // And back again
+        let rsp = Response::Version { version: 1 };
+        let used = ResponseEncoder::new(&rsp).unwrap().write(&mut buffer);
+        assert_eq!(&buffer[0..used], &[ESCAPE_CHAR, RES_VERSION, 0x01]);
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_VERSION), Ok(None));
+        assert_eq!(p.receive(0x01), Ok(Some(rsp)));
+    }
+
+    #[test]
+    fn check_rsp_crc_int_flash() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_CRCIF), Ok(None));
+        // CRC
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(
+            p.receive(0xDE),
+            Ok(Some(Response::CrcIntFlash { crc: 0xDEADBEEF }))
+        );
+
+        let r = Response::CrcIntFlash { crc: 0xDEADBEEF };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_CRCIF));
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_crc_ext_flash() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_CRCXF), Ok(None));
+        // CRC
+        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0xAD), Ok(None));
+        assert_eq!(
+            p.receive(0xDE),
+            Ok(Some(Response::CrcExtFlash { crc: 0xDEADBEEF }))
+        );
+
+        let r = Response::CrcExtFlash { crc: 0xDEADBEEF };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_CRCXF));
+        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0xAD));
+        assert_eq!(e.next(), Some(0xDE));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_rsp_info() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_INFO), Ok(None));
+        // length
+        assert_eq!(p.receive(0x08), Ok(None));
+        // eight bytes of data
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x11), Ok(None));
+        assert_eq!(p.receive(0x22), Ok(None));
+        assert_eq!(p.receive(0x33), Ok(None));
+        assert_eq!(p.receive(0x44), Ok(None));
+        assert_eq!(p.receive(0x55), Ok(None));
+        assert_eq!(p.receive(0x66), Ok(None));
+        assert_eq!(p.receive(0x77), Ok(None));
+        // pad up to 191 bytes
+        for _ in 8..MAX_INFO_LEN - 1 {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        // final padding byte
+        assert_eq!(
+            p.receive(0x00),
+            Ok(Some(Response::Info {
+                info: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+            }))
+        );
+
+        // Check follow-on command
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_PONG), Ok(Some(Response::Pong)));
+
+        let r = Response::Info { info: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77] };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_INFO));
+        // length
+        assert_eq!(e.next(), Some(0x08));
+        // data
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x11));
+        assert_eq!(e.next(), Some(0x22));
+        assert_eq!(e.next(), Some(0x33));
+        assert_eq!(e.next(), Some(0x44));
+        assert_eq!(e.next(), Some(0x55));
+        assert_eq!(e.next(), Some(0x66));
+        assert_eq!(e.next(), Some(0x77));
+        // padding
+        for _ in 8..MAX_INFO_LEN {
+            assert_eq!(e.next(), Some(0x00));
+        }
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn check_response_write() {
+        let r = Response::Pong;
+        // More than large enough
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        let mut buffer = [0xFFu8; 4];
+        assert_eq!(e.write(&mut buffer), 2);
+        assert_eq!(&buffer[0..4], &[ESCAPE_CHAR, RES_PONG, 0xFF, 0xFF]);
+
+        // Too small - do it in pieces
+        e.reset();
+        buffer[0] = 0xFF;
+        assert_eq!(e.write(&mut buffer[0..1]), 1);
+        assert_eq!(buffer[0], ESCAPE_CHAR);
+        buffer[0] = 0xFF;
+        assert_eq!(e.write(&mut buffer[0..1]), 1);
+        assert_eq!(buffer[0], RES_PONG);
+        assert_eq!(e.write(&mut buffer[0..1]), 0);
+
+        // perfectly sized
+        e.reset();
+        for b in buffer.iter_mut() {
+            *b = 0xFF
+        }
+        assert_eq!(e.write(&mut buffer[0..2]), 2);
+        assert_eq!(&buffer[0..2], &[ESCAPE_CHAR, RES_PONG]);
+    }
+
+    #[test]
+    fn check_command_write() {
+        let r = Command::Ping;
+        let mut e = CommandEncoder::new(&r).unwrap();
+        let mut buffer = [0xFFu8; 4];
+        // More than large enough
+        assert_eq!(e.write(&mut buffer), 2);
+        assert_eq!(&buffer[0..4], &[ESCAPE_CHAR, CMD_PING, 0xFF, 0xFF]);
+
+        // Too small - do it in pieces
+        e.reset();
+        buffer[0] = 0xFF;
+        assert_eq!(e.write(&mut buffer[0..1]), 1);
+        assert_eq!(buffer[0], ESCAPE_CHAR);
+        buffer[0] = 0xFF;
+        assert_eq!(e.write(&mut buffer[0..1]), 1);
+        assert_eq!(buffer[0], CMD_PING);
+        assert_eq!(e.write(&mut buffer[0..1]), 0);
+
+        // perfectly sized
+        e.reset();
+        for b in buffer.iter_mut() {
+            *b = 0xFF
+        }
+        assert_eq!(e.write(&mut buffer[0..2]), 2);
+        assert_eq!(&buffer[0..2], &[ESCAPE_CHAR, CMD_PING]);
+    }
+
+    #[test]
+    fn check_command_decode_buffer() {
+        let mut p = CommandDecoder::new();
+        let buffer = [
+            0xEFu8,
+            0xBE,
+            0xAD,
+            0xDE,
+            0x78,
+            0x56,
+            0x34,
+            0x12,
+            ESCAPE_CHAR,
+            CMD_CRCIF,
+        ];
+        let callback = |x: &Command| match x {
+            &Command::CrcIntFlash { address, length } => {
+                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(length, 0x12345678);
             }
-            1...4 => self.render_u32(count - 1, baud),
-            _ => self.render_basic_cmd(count - 5, CMD_CHANGE_BAUD),
+            _ => panic!("Bad command {:?}", x),
+        };
+        match p.read(&buffer, callback) {
+            Ok(_) => {}
+            Err(e) => panic!("Did not expect: {:?}", e),
         }
     }
-}
-
-impl<'a> Iterator for CommandEncoder<'a> {
-    type Item = u8;
 
-    /// Supply the next encoded byte. Once all the bytes have been emitted, it
-    /// returns `None` forevermore.
-    fn next(&mut self) -> Option<u8> {
-        let count = self.count;
-        let (inc, result) = match self.command {
-            &Command::Ping => self.render_basic_cmd(count, CMD_PING),
-            &Command::Info => self.render_basic_cmd(count, CMD_INFO),
-            &Command::Id => self.render_basic_cmd(count, CMD_ID),
-            &Command::Reset => self.render_basic_cmd(count, CMD_RESET),
-            &Command::ErasePage { address } => self.render_erasepage_cmd(address),
-            &Command::WritePage { address, data } => self.render_writepage_cmd(address, data),
-            &Command::EraseExBlock { address } => self.render_eraseexblock(address),
-            &Command::WriteExPage { address, data } => self.render_writeexpage(address, data),
-            &Command::CrcRxBuffer => self.render_basic_cmd(count, CMD_CRCRX),
-            &Command::ReadRange { address, length } => self.render_readrange(address, length),
-            &Command::ExReadRange { address, length } => self.render_exreadrange(address, length),
-            &Command::SetAttr { index, key, value } => self.render_setattr(index, key, value),
-            &Command::GetAttr { index } => self.render_getattr(index),
-            &Command::CrcIntFlash { address, length } => self.render_crcintflash(address, length),
-            &Command::CrcExtFlash { address, length } => self.render_crcextflash(address, length),
-            &Command::EraseExPage { address } => self.render_eraseexpage(address),
-            &Command::ExtFlashInit => self.render_basic_cmd(count, CMD_XFINIT),
-            &Command::ClockOut => self.render_basic_cmd(count, CMD_CLKOUT),
-            &Command::WriteFlashUserPages { page1, page2 } => {
-                self.render_writeflashuserpages(page1, page2)
+    #[test]
+    fn check_response_decode_buffer() {
+        let mut p = ResponseDecoder::new();
+        let buffer = [ESCAPE_CHAR, RES_CRCXF, 0xEF, 0xBE, 0xAD, 0xDE];
+        let callback = |x: &Response| match x {
+            &Response::CrcExtFlash { crc } => {
+                assert_eq!(crc, 0xDEADBEEF);
             }
-            &Command::ChangeBaud { mode, baud } => self.render_changebaud(mode, baud),
+            _ => panic!("Bad command {:?}", x),
         };
-        self.count = self.count + inc;
-        result
+        match p.read(&buffer, callback) {
+            Ok(_) => {}
+            Err(e) => panic!("Did not expect: {:?}", e),
+        }
     }
-}
 
-impl<'a> Encoder for CommandEncoder<'a> {
-    /// Reset the `Encoder`, so that next time you call `self.next()`
-    /// you get the first byte again.
-    fn reset(&mut self) {
-        self.count = 0;
-        self.sent_escape = false;
+    #[test]
+    fn check_unframer() {
+        let mut u = Unframer::new();
+        assert_eq!(u.push(0x01), Some(FramedEvent::Literal(0x01)));
+        // Doubled escape
+        assert_eq!(u.push(ESCAPE_CHAR), None);
+        assert_eq!(u.push(ESCAPE_CHAR), Some(FramedEvent::Literal(ESCAPE_CHAR)));
+        // Doubled escape then a terminator
+        assert_eq!(u.push(ESCAPE_CHAR), None);
+        assert_eq!(u.push(ESCAPE_CHAR), Some(FramedEvent::Literal(ESCAPE_CHAR)));
+        assert_eq!(u.push(ESCAPE_CHAR), None);
+        assert_eq!(u.push(CMD_PING), Some(FramedEvent::Terminator(CMD_PING)));
+        // Reset drops a pending escape
+        assert_eq!(u.push(ESCAPE_CHAR), None);
+        u.reset();
+        assert_eq!(u.push(CMD_PING), Some(FramedEvent::Literal(CMD_PING)));
     }
-}
 
-impl<'a> ResponseEncoder<'a> {
-    /// Create a new `ResponseEncoder`.
-    ///
-    /// The encoder takes a reference to a `Command` to encode. The `next` method
-    /// will then supply the encoded bytes one at a time.
-    pub fn new(response: &'a Response) -> Result<ResponseEncoder<'a>, Error> {
-        match response {
-            &Response::GetAttr { key, value } => {
-                if key.len() != KEY_LEN {
-                    return Err(Error::BadArguments);
-                }
-                if value.len() > MAX_ATTR_LEN {
-                    return Err(Error::BadArguments);
-                }
-            }
-            &Response::Info { info } => {
-                if info.len() > MAX_INFO_LEN {
-                    return Err(Error::BadArguments);
-                }
-            }
-            _ => {}
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_log_record() {
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = i as u8;
         }
-        Ok(ResponseEncoder {
-            response: response,
-            count: 0,
-            sent_escape: false,
-        })
+        let cmd = Command::WritePage {
+            address: 0xDEADBEEF,
+            data: &page,
+        };
+        let mut record = [0u8; LOG_HEADER_LEN + 4 + INT_PAGE_SIZE];
+        assert_eq!(cmd.to_log_record(&mut record), Ok(record.len()));
+        assert_eq!(&record[0..7], &[CMD_WPAGE, 0x04, 0x02, 0xEF, 0xBE, 0xAD, 0xDE]);
+        // No escaping in a log record
+        assert_eq!(&record[7..], &page[..]);
+        assert_eq!(
+            Command::from_log_record(&record[0..100]),
+            Err(Error::BadArguments)
+        );
+        assert_eq!(Command::from_log_record(&record), Ok(cmd));
+        assert_eq!(
+            Command::Ping.to_log_record(&mut [0u8; 2]),
+            Err(Error::BufferTooSmall)
+        );
     }
 
-    fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
-        if byte == ESCAPE_CHAR {
-            if self.sent_escape {
-                self.sent_escape = false;
-                (1, Some(ESCAPE_CHAR))
-            } else {
-                self.sent_escape = true;
-                (0, Some(ESCAPE_CHAR))
-            }
-        } else {
-            (1, Some(byte))
+    #[test]
+    fn check_address() {
+        let address = 0x0003_0200;
+        let data = [0u8; 4];
+        let cmds = [
+            Command::ErasePage { address },
+            Command::WritePage { address, data: &data },
+            Command::EraseExBlock { address },
+            Command::WriteExPage { address, data: &data },
+            Command::ReadRange { address, length: 4 },
+            Command::ExReadRange { address, length: 4 },
+            Command::CrcIntFlash { address, length: 4 },
+            Command::CrcExtFlash { address, length: 4 },
+            Command::EraseExPage { address },
+            Command::SetStartAddress { address },
+            #[cfg(feature = "partial-page")]
+            Command::WritePagePartial { address, len: 4, data: &data },
+        ];
+        for cmd in &cmds {
+            assert_eq!(cmd.address(), Some(0x0003_0200), "{:?}", cmd);
+            assert_eq!(cmd.address_usize(), Ok(Some(0x0003_0200)), "{:?}", cmd);
         }
-    }
-
-    fn render_crc_rx_buffer(&mut self, length: u16, crc: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_CRCRX),
-            2...3 => self.render_u16(count - 2, length),
-            4...7 => self.render_u32(count - 4, crc),
-            _ => (0, None),
+        let max = Command::SetStartAddress {
+            address: 0xFFFF_FFFF,
+        };
+        assert_eq!(max.address(), Some(0xFFFF_FFFF));
+        #[cfg(target_pointer_width = "16")]
+        assert_eq!(max.address_usize(), Err(Error::BadAddress));
+        #[cfg(not(target_pointer_width = "16"))]
+        assert_eq!(max.address_usize(), Ok(Some(0xFFFF_FFFF)));
+        for cmd in &[
+            Command::Ping,
+            Command::CrcRxBuffer,
+            Command::GetAttr { index: 0 },
+            Command::WriteFlashUserPages { page1: 1, page2: 2 },
+            Command::Unknown { opcode: 0x99 },
+        ] {
+            assert_eq!(cmd.address(), None);
+            assert_eq!(cmd.address_usize(), Ok(None));
         }
     }
 
-    fn render_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_RRANGE),
-            x if x < data.len() + 2 => self.render_byte(data[x - 2]),
-            _ => (0, None),
-        }
+    #[test]
+    fn check_continue_read() {
+        let read = Command::ReadRange {
+            address: 0x0003_0000,
+            length: 1024,
+        };
+        assert_eq!(
+            read.continue_read(512),
+            Some(Command::ReadRange {
+                address: 0x0003_0200,
+                length: 512,
+            })
+        );
+        assert_eq!(read.continue_read(1024), None);
+        assert_eq!(read.continue_read(2000), None);
+        let read = Command::ExReadRange {
+            address: 0x100,
+            length: 10,
+        };
+        assert_eq!(
+            read.continue_read(1),
+            Some(Command::ExReadRange {
+                address: 0x101,
+                length: 9,
+            })
+        );
+        let read = Command::ReadRange {
+            address: 0xFFFF_FFFF,
+            length: 10,
+        };
+        assert_eq!(read.continue_read(1), None);
+        assert_eq!(Command::Ping.continue_read(0), None);
     }
 
-    fn render_ex_read_range(&mut self, data: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_XRRANGE),
-            x if x - 2 < data.len() => self.render_byte(data[x - 2]),
-            _ => (0, None),
-        }
+    #[test]
+    fn check_log_record_short() {
+        let mut record = [0u8; 8];
+        assert_eq!(Command::Ping.to_log_record(&mut record), Ok(3));
+        assert_eq!(&record[0..3], &[CMD_PING, 0x00, 0x00]);
+        assert_eq!(Command::from_log_record(&record[0..3]), Ok(Command::Ping));
+        assert_eq!(Command::from_log_record(&record[0..2]), Err(Error::BadArguments));
+        let cmd = Command::Unknown { opcode: 0x99 };
+        assert_eq!(cmd.to_log_record(&mut record), Ok(3));
+        assert_eq!(Command::from_log_record(&record[0..3]), Ok(cmd));
     }
 
-    fn render_get_attr(&mut self, key: &[u8], value: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_GATTR),
-            2...9 => self.render_buffer(count - 2, 8, key),
-            10 => self.render_byte(value.len() as u8),
-            _ => self.render_buffer(count - 11, MAX_ATTR_LEN, value),
-        }
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_from_parts() {
+        let mut payload = [0xFCu8; 4 + INT_PAGE_SIZE];
+        payload[0..4].copy_from_slice(&[0x00, 0x02, 0x03, 0x00]);
+        assert_eq!(
+            Command::from_parts(CMD_WPAGE, &payload),
+            Ok(Command::WritePage {
+                address: 0x0003_0200,
+                data: &payload[4..],
+            })
+        );
+        assert_eq!(Command::from_parts(CMD_WPAGE, &payload[0..4]), Err(Error::EmptyPage));
+        assert_eq!(Command::from_parts(CMD_WPAGE, &payload[0..3]), Err(Error::BadArguments));
+        assert_eq!(
+            Command::from_parts(CMD_EPAGE, &payload[0..4]),
+            Ok(Command::ErasePage {
+                address: 0x0003_0200,
+            })
+        );
+        assert_eq!(Command::from_parts(CMD_EPAGE, &payload[0..3]), Err(Error::BadArguments));
+        assert_eq!(Command::from_parts(CMD_EPAGE, &payload[0..5]), Err(Error::BadArguments));
+        assert_eq!(Command::from_parts(CMD_PING, &[]), Ok(Command::Ping));
+        assert_eq!(
+            Command::from_parts(0x99, &payload),
+            Ok(Command::Unknown { opcode: 0x99 })
+        );
     }
 
-    fn render_crc_int_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_CRCIF),
-            _ => self.render_u32(count - 2, crc),
-        }
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_is_mid_frame() {
+        let mut p = CommandDecoder::new();
+        assert!(!p.is_mid_frame());
+        let mut bytes = [0u8; 100];
+        bytes[0] = ESCAPE_CHAR;
+        bytes[1] = CMD_PING;
+        p.read(&bytes[0..2], |_| {}).unwrap();
+        assert!(!p.is_mid_frame());
+        // The first 100 bytes of a WritePage
+        p.read(&bytes[2..], |_| {}).unwrap();
+        assert!(p.is_mid_frame());
+        // Ending on an escape is mid-frame too
+        p.reset();
+        assert!(!p.is_mid_frame());
+        p.read(&[ESCAPE_CHAR], |_| {}).unwrap();
+        assert!(p.is_mid_frame());
+        assert_eq!(p.flush(), Err(Error::DanglingEscape));
+        // More bytes can still finish a truncated slice
+        p.read(&bytes[2..], |_| {}).unwrap();
+        let rest = [0u8; INT_PAGE_SIZE + 4 - 98];
+        p.read(&rest, |_| {}).unwrap();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert!(matches!(
+            p.receive(CMD_WPAGE),
+            Ok(Some(Command::WritePage { .. }))
+        ));
+        assert!(!p.is_mid_frame());
     }
 
-    fn render_crc_ex_flash(&mut self, crc: u32) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_CRCXF),
-            _ => self.render_u32(count - 2, crc),
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_last_opcode() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.last_opcode(), None);
+        for _ in 0..(4 + INT_PAGE_SIZE) {
+            assert_eq!(p.receive(0x00), Ok(None));
         }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert!(p.receive(CMD_WPAGE).unwrap().is_some());
+        assert_eq!(p.last_opcode(), Some(0x07));
+        // Errors and ignored frames count too
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
+        assert_eq!(p.last_opcode(), Some(CMD_SET_START_ADDRESS));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x99), Ok(None));
+        assert_eq!(p.last_opcode(), Some(0x99));
     }
 
-    fn render_info(&mut self, info: &[u8]) -> (usize, Option<u8>) {
-        let count = self.count;
-        match count {
-            0...1 => self.render_header(count, RES_INFO),
-            2 => self.render_byte(info.len() as u8),
-            _ => self.render_buffer(count - 3, MAX_INFO_LEN, info),
+    #[cfg(feature = "trace")]
+    #[test]
+    fn check_trace() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.trace().count(), 0);
+        for &ch in &[0x01, ESCAPE_CHAR, ESCAPE_CHAR, ESCAPE_CHAR, 0x42] {
+            let _ = p.receive(ch);
+        }
+        let entry = |escaped, byte, action| TraceEntry {
+            escaped,
+            byte,
+            action,
+        };
+        let expected = [
+            entry(false, 0x01, TraceAction::Literal),
+            entry(false, ESCAPE_CHAR, TraceAction::Escape),
+            entry(true, ESCAPE_CHAR, TraceAction::Literal),
+            entry(false, ESCAPE_CHAR, TraceAction::Escape),
+            entry(true, 0x42, TraceAction::Ignored),
+        ];
+        assert!(p.trace().eq(expected.iter().cloned()));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
+        let expected = [
+            entry(false, ESCAPE_CHAR, TraceAction::Escape),
+            entry(true, CMD_PING, TraceAction::Command),
+            entry(false, 0x01, TraceAction::Literal),
+            entry(false, ESCAPE_CHAR, TraceAction::Escape),
+            entry(true, CMD_SET_START_ADDRESS, TraceAction::Error),
+        ];
+        assert!(p.trace().skip(5).eq(expected.iter().cloned()));
+        // Only the most recent entries are kept
+        for _ in 0..100 {
+            assert_eq!(p.receive(0x55), Ok(None));
         }
+        assert_eq!(p.trace().count(), TRACE_LEN);
+        assert!(p.trace().all(|e| e == entry(false, 0x55, TraceAction::Literal)));
     }
 
-    fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            _ => (0, None),
+    #[cfg(all(feature = "dma", feature = "write"))]
+    #[test]
+    fn check_dma() {
+        // A page of escapes needs twice as much room on the wire as in the
+        // buffer, so it can't arrive in one fill
+        let page = [ESCAPE_CHAR; INT_PAGE_SIZE];
+        let cmds = [
+            Command::Ping,
+            Command::WritePage {
+                address: 0x0003_0000,
+                data: &page,
+            },
+            Command::Info,
+        ];
+        let mut bytes = [0u8; 2 + 4 + 2 * INT_PAGE_SIZE + 2 + 2];
+        let mut len = 0;
+        for cmd in &cmds {
+            len += CommandEncoder::new(cmd).unwrap().write(&mut bytes[len..]);
+        }
+        assert_eq!(len, bytes.len());
+        let mut p = CommandDecoder::new();
+        let mut kinds = [CommandKind::Unknown; 3];
+        let mut decoded = 0;
+        let mut sent = 0;
+        let mut fills = 0;
+        while sent < len {
+            let free = p.dma_buffer();
+            let n = free.len().min(len - sent).min(100);
+            assert!(n > 0);
+            free[0..n].copy_from_slice(&bytes[sent..sent + n]);
+            sent += n;
+            fills += 1;
+            p.dma_advance(n, |cmd| {
+                if let Command::WritePage { address, data } = *cmd {
+                    assert_eq!(address, 0x0003_0000);
+                    assert_eq!(data, &page[..]);
+                }
+                kinds[decoded] = cmd.kind();
+                decoded += 1;
+            })
+            .unwrap();
         }
+        assert!(fills > 1);
+        assert_eq!(
+            kinds,
+            [CommandKind::Ping, CommandKind::WritePage, CommandKind::Info]
+        );
+        assert!(!p.is_mid_frame());
+        // Can't claim more than we were given room for
+        let room = p.dma_buffer().len();
+        assert_eq!(p.dma_advance(room + 1, |_| {}), Err(Error::BufferTooSmall));
+        // Errors stop processing
+        p.dma_buffer()[0..4].copy_from_slice(&[0x01, ESCAPE_CHAR, CMD_SET_START_ADDRESS, 0x00]);
+        assert_eq!(p.dma_advance(4, |_| panic!()), Err(Error::BadArguments));
+        assert!(!p.is_mid_frame());
     }
 
-    fn render_u32(&mut self, idx: usize, value: u32) -> (usize, Option<u8>) {
-        match idx {
-            0 => self.render_byte(value as u8),
-            1 => self.render_byte((value >> 8) as u8),
-            2 => self.render_byte((value >> 16) as u8),
-            3 => self.render_byte((value >> 24) as u8),
-            _ => (0, None),
+    #[cfg(feature = "dma")]
+    #[test]
+    fn check_dma_overflow() {
+        let mut p = CommandDecoder::new();
+        // More payload than the buffer holds, in several fills
+        let mut sent = 0;
+        while sent < COMMAND_BUFFER_LEN + 10 {
+            let free = p.dma_buffer();
+            let n = free.len().min(100);
+            assert!(n > 0);
+            free[0..n].iter_mut().for_each(|b| *b = 0x55);
+            sent += n;
+            p.dma_advance(n, |_| panic!()).unwrap();
         }
+        assert!(p.is_mid_frame());
+        // There's still room for the end of the frame, and the next one
+        let frame = [ESCAPE_CHAR, CMD_PING, ESCAPE_CHAR, CMD_PING];
+        p.dma_buffer()[0..4].copy_from_slice(&frame);
+        assert_eq!(p.dma_advance(2, |_| panic!()), Err(Error::Overflow));
+        p.dma_buffer()[0..2].copy_from_slice(&frame[2..]);
+        let mut pings = 0;
+        p.dma_advance(2, |cmd| {
+            assert_eq!(cmd, &Command::Ping);
+            pings += 1;
+        })
+        .unwrap();
+        assert_eq!(pings, 1);
     }
 
-    fn render_buffer(&mut self, idx: usize, page_size: usize, data: &[u8]) -> (usize, Option<u8>) {
-        if (idx < data.len()) && (idx < page_size) {
-            self.render_byte(data[idx])
-        } else if idx < page_size {
-            self.render_byte(0x00) // pad short data with nulls
-        } else {
-            (0, None)
+    #[cfg(feature = "error-history")]
+    #[test]
+    fn check_recent_errors() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.recent_errors().count(), 0);
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        p.set_max_frame_len(Some(4));
+        for _ in 0..4 {
+            assert_eq!(p.receive(0x55), Ok(None));
+        }
+        assert_eq!(p.receive(0x55), Err(Error::FrameTooLong));
+        let expected = [
+            ErrorRecord {
+                error: Error::BadArguments,
+                opcode: Some(CMD_SET_START_ADDRESS),
+                offset: 3,
+            },
+            ErrorRecord {
+                error: Error::FrameTooLong,
+                opcode: None,
+                offset: 5,
+            },
+        ];
+        assert!(p.recent_errors().eq(expected.iter().cloned()));
+        // The rest of the long frame is skipped
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(None));
+        // Only the most recent errors are kept
+        p.set_max_frame_len(None);
+        for _ in 0..20 {
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
         }
+        assert_eq!(p.recent_errors().count(), ERROR_HISTORY_LEN);
+        assert!(p.recent_errors().all(|r| r.offset == 2));
     }
 
-    fn render_header(&mut self, count: usize, cmd: u8) -> (usize, Option<u8>) {
-        match count {
-            0 => (1, Some(ESCAPE_CHAR)), // Escape
-            1 => (1, Some(cmd)), // Command
-            _ => (0, None),
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_flash_bounds() {
+        fn erase(p: &mut CommandDecoder, address: u32) -> Result<Option<Command<'_>>, Error> {
+            let mut bytes = [0u8; 4];
+            LittleEndian::write_u32(&mut bytes, address);
+            for &ch in &bytes {
+                assert_eq!(p.receive(ch), Ok(None));
+                if ch == ESCAPE_CHAR {
+                    assert_eq!(p.receive(ch), Ok(None));
+                }
+            }
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            p.receive(CMD_EPAGE)
+        }
+        let mut p = CommandDecoder::new();
+        // Anything goes by default
+        assert!(erase(&mut p, 0xFFFF_FF00).unwrap().is_some());
+        // 256 KiB at 0x40000
+        p.set_flash_bounds(Some((0x40000, 0x40000)));
+        assert!(erase(&mut p, 0x40000).unwrap().is_some());
+        assert!(erase(&mut p, 0x7FE00).unwrap().is_some());
+        assert_eq!(erase(&mut p, 0x3FE00), Err(Error::BadAddress));
+        // The page would run off the end
+        assert_eq!(erase(&mut p, 0x7FF00), Err(Error::BadAddress));
+        assert_eq!(erase(&mut p, 0x80000), Err(Error::BadAddress));
+        assert_eq!(erase(&mut p, 0xFFFF_FFFF), Err(Error::BadAddress));
+        // Writes are checked too
+        for &(address, ok) in &[(0x7FE00u32, true), (0x80000, false)] {
+            let mut bytes = [0u8; 4];
+            LittleEndian::write_u32(&mut bytes, address);
+            for &ch in bytes.iter().chain([0u8; INT_PAGE_SIZE].iter()) {
+                assert_eq!(p.receive(ch), Ok(None));
+            }
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            match p.receive(CMD_WPAGE) {
+                Ok(Some(Command::WritePage { .. })) if ok => {}
+                Err(Error::BadAddress) if !ok => {}
+                e => panic!("Did not expect: {:?}", e),
+            }
         }
+        p.set_flash_bounds(None);
+        assert!(erase(&mut p, 0x80000).unwrap().is_some());
     }
-}
 
-impl<'a> Encoder for ResponseEncoder<'a> {
-    fn reset(&mut self) {
-        self.count = 0;
-        self.sent_escape = false;
+    #[test]
+    fn check_supported_commands() {
+        let p = CommandDecoder::new();
+        let supported = p.supported_commands();
+        assert!(supported.contains(&CommandKind::Ping));
+        assert!(supported.contains(&CommandKind::ReadRange));
+        assert!(!supported.contains(&CommandKind::Unknown));
+        assert_eq!(
+            supported.contains(&CommandKind::WritePage),
+            cfg!(feature = "write")
+        );
+        assert_eq!(
+            supported.contains(&CommandKind::WritePagePartial),
+            cfg!(feature = "partial-page")
+        );
+        assert_eq!(Command::Ping.kind(), CommandKind::Ping);
+        assert_eq!(
+            Command::Unknown { opcode: 0x99 }.kind(),
+            CommandKind::Unknown
+        );
     }
-}
-
-impl<'a> Iterator for ResponseEncoder<'a> {
-    type Item = u8;
 
-    /// Supply the next encoded byte. Once all the bytes have been emitted, it
-    /// returns `None` forevermore.
-    fn next(&mut self) -> Option<u8> {
-        let count = self.count;
-        let (inc, result) = match self.response {
-            &Response::Overflow => self.render_header(count, RES_OVERFLOW),
-            &Response::Pong => self.render_header(count, RES_PONG),
-            &Response::BadAddress => self.render_header(count, RES_BADADDR),
-            &Response::InternalError => self.render_header(count, RES_INTERROR),
-            &Response::BadArguments => self.render_header(count, RES_BADARGS),
-            &Response::Ok => self.render_header(count, RES_OK),
-            &Response::Unknown => self.render_header(count, RES_UNKNOWN),
-            &Response::ExtFlashTimeout => self.render_header(count, RES_XFTIMEOUT),
-            &Response::ExtFlashPageError => self.render_header(count, RES_XFEPE),
-            &Response::CrcRxBuffer { length, crc } => self.render_crc_rx_buffer(length, crc),
-            &Response::ReadRange { data } => self.render_read_range(data),
-            &Response::ExReadRange { data } => self.render_ex_read_range(data),
-            &Response::GetAttr { key, value } => self.render_get_attr(key, value),
-            &Response::CrcIntFlash { crc } => self.render_crc_int_flash(crc),
-            &Response::CrcExtFlash { crc } => self.render_crc_ex_flash(crc),
-            &Response::Info { info } => self.render_info(info),
-            &Response::ChangeBaudFail => self.render_header(count, RES_CHANGE_BAUD_FAIL),
-        };
-        self.count = self.count + inc;
-        result
+    /// Without `write`, a bootloader is read-only
+    #[cfg(not(feature = "write"))]
+    #[test]
+    fn check_supported_commands_read_only() {
+        let p = CommandDecoder::new();
+        for kind in &[
+            CommandKind::ErasePage,
+            CommandKind::WritePage,
+            CommandKind::EraseExBlock,
+            CommandKind::WriteExPage,
+            CommandKind::EraseExPage,
+            CommandKind::WriteFlashUserPages,
+        ] {
+            assert!(!p.supported_commands().contains(kind));
+        }
     }
-}
 
-// ****************************************************************************
-//
-// Private Impl/Functions/Modules
-//
-// ****************************************************************************
+    #[test]
+    fn check_profile() {
+        let mut p = CommandDecoder::new_with_profile(Profile::Minimal);
+        assert_eq!(p.supported_commands(), MINIMAL_COMMANDS);
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        #[cfg(feature = "write")]
+        {
+            for _ in 0..(4 + INT_PAGE_SIZE) {
+                assert_eq!(p.receive(0x00), Ok(None));
+            }
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(p.receive(CMD_WPAGE), Err(Error::UnsupportedInProfile));
+            // Even a page which would be bad anyway
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(p.receive(CMD_WPAGE), Err(Error::UnsupportedInProfile));
+        }
+        for &ch in &[0x00, 0x00, 0x03, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::UnsupportedInProfile));
+        // The profile is checked before the (too short) payload
+        for &ch in &[0x00, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::UnsupportedInProfile));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_CHANGE_BAUD), Err(Error::UnsupportedInProfile));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_INFO), Ok(Some(Command::Info)));
+        // Standard takes the page, but not external flash commands
+        p.set_profile(Profile::Standard);
+        for &ch in &[0x00, 0x00, 0x03, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress { address: 0x0003_0000 }))
+        );
+        for &ch in &[0x00, 0x00, 0x00, 0x00, 0x10, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_XRRANGE), Err(Error::UnsupportedInProfile));
+        assert_eq!(Profile::Standard.allows(CommandKind::WritePage), cfg!(feature = "write"));
+        assert!(!Profile::Standard.allows(CommandKind::ClockOut));
+        assert!(Profile::Full.allows(CommandKind::ClockOut));
+        assert_eq!(CommandDecoder::new().supported_commands(), SUPPORTED_COMMANDS);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn check_scoped() {
+        let mut p = CommandDecoder::new();
+        {
+            let mut scope = p.scoped();
+            assert_eq!(scope.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(scope.receive(CMD_PING), Ok(Some(Command::Ping)));
+            for &ch in &[0x01, 0x02, ESCAPE_CHAR] {
+                assert_eq!(scope.receive(ch), Ok(None));
+            }
+            assert!(scope.is_mid_frame());
+        }
+        assert!(!p.is_mid_frame());
+        // The old partial frame is gone, escape and all
+        assert_eq!(p.receive(CMD_PING), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_RESET), Ok(Some(Command::Reset)));
+    }
 
+    #[cfg(feature = "test-internals")]
     #[test]
-    fn decode_cmd_ping() {
+    fn check_test_internals() {
         let mut p = CommandDecoder::new();
+        assert_eq!(p.state(), DecoderState::Loading);
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        match p.receive(CMD_PING) {
-            Ok(Some(Command::Ping)) => {}
-            e => panic!("Did not expect: {:?}", e),
+        assert_eq!(p.state(), DecoderState::Escape);
+        assert_eq!(p.count(), 0);
+        // Doubled, so a payload byte
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.state(), DecoderState::Loading);
+        assert_eq!(p.count(), 1);
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert_eq!(p.state(), DecoderState::Loading);
+        assert_eq!(p.count(), 0);
+    }
+
+    #[test]
+    fn check_clone_decoder() {
+        let mut p = CommandDecoder::new();
+        for &ch in &[0x01, 0x00, 0x03] {
+            assert_eq!(p.receive(ch), Ok(None));
         }
+        let mut q = p.clone();
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive(CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress {
+                address: 0x0003_0001,
+            }))
+        );
+        // The clone still has its own copy of the partial frame
+        assert_eq!(q.receive(0x00), Ok(None));
+        assert_eq!(q.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            q.receive(CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress {
+                address: 0x0003_0001,
+            }))
+        );
+        // And a clone of an idle decoder is idle
+        assert!(!p.clone().is_mid_frame());
     }
 
+    #[cfg(feature = "crc")]
     #[test]
-    fn encode_cmd_ping() {
-        let cmd = Command::Ping;
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_PING));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+
+    /// Feed `payload`, then its CRC-16, then the command byte.
+    #[cfg(feature = "crc")]
+    fn feed_checksummed<'p>(
+        p: &'p mut CommandDecoder,
+        payload: &[u8],
+        checksum: u16,
+        opcode: u8,
+    ) -> Result<Option<Command<'p>>, Error> {
+        let mut crc = [0u8; 2];
+        LittleEndian::write_u16(&mut crc, checksum);
+        for &ch in payload.iter().chain(crc.iter()) {
+            assert_eq!(p.receive(ch), Ok(None));
+            if ch == ESCAPE_CHAR {
+                assert_eq!(p.receive(ch), Ok(None));
+            }
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        p.receive(opcode)
     }
 
+    #[cfg(feature = "crc")]
     #[test]
-    fn decode_cmd_info() {
+    fn check_checksum() {
         let mut p = CommandDecoder::new();
+        p.set_checksum(true);
+        let payload = [0x00, 0x00, 0x03, 0x00];
+        assert_eq!(
+            feed_checksummed(&mut p, &payload, crc16(&payload), CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress {
+                address: 0x0003_0000,
+            }))
+        );
+        assert_eq!(
+            feed_checksummed(&mut p, &payload, crc16(&payload) ^ 1, CMD_SET_START_ADDRESS),
+            Err(Error::ChecksumMismatch)
+        );
+        // Frames with no payload still carry a checksum
+        assert_eq!(
+            feed_checksummed(&mut p, &[], crc16(&[]), CMD_PING),
+            Ok(Some(Command::Ping))
+        );
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        match p.receive(CMD_INFO) {
-            Ok(Some(Command::Info)) => {}
+        assert_eq!(p.receive(CMD_PING), Err(Error::BadArguments));
+        // A page, with its CRC-32 still worked out
+        let mut page = [0u8; 4 + INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        match feed_checksummed(&mut p, &page, crc16(&page), CMD_WPAGE) {
+            Ok(Some(Command::WritePage { data, .. })) => assert_eq!(data, &page[4..]),
             e => panic!("Did not expect: {:?}", e),
         }
+        assert_eq!(p.current_page_crc(), Some(crc32(&page[4..])));
+        // Without checksum mode, the checksum is just more payload
+        p.set_checksum(false);
+        assert_eq!(
+            feed_checksummed(&mut p, &payload, crc16(&payload), CMD_SET_START_ADDRESS),
+            Err(Error::BadArguments)
+        );
     }
 
+    #[cfg(feature = "crc")]
     #[test]
-    fn encode_cmd_info() {
-        let cmd = Command::Info;
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_INFO));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_receive_in_place_checksum() {
+        let mut p = CommandDecoder::new();
+        p.set_checksum(true);
+        let mut input = [0u8; 4 + CHECKSUM_LEN + 2];
+        input[0..4].copy_from_slice(&[0x00, 0x00, 0x03, 0x00]);
+        let crc = crc16(&input[0..4]);
+        LittleEndian::write_u16(&mut input[4..6], crc);
+        assert!(!input[4..6].contains(&ESCAPE_CHAR));
+        input[6] = ESCAPE_CHAR;
+        input[7] = CMD_SET_START_ADDRESS;
+        assert_eq!(
+            p.receive_in_place(&input),
+            (8, Ok(Some(Command::SetStartAddress { address: 0x0003_0000 })))
+        );
+        LittleEndian::write_u16(&mut input[4..6], crc ^ 1);
+        assert_eq!(p.receive_in_place(&input), (8, Err(Error::ChecksumMismatch)));
+        // Too short to have a checksum at all
+        assert_eq!(
+            p.receive_in_place(&[0x99, ESCAPE_CHAR, CMD_PING]),
+            (3, Err(Error::BadArguments))
+        );
+        // Checksum bytes aren't payload
+        let mut ping = [0u8; CHECKSUM_LEN + 2];
+        LittleEndian::write_u16(&mut ping[0..2], crc16(&[]));
+        ping[2] = ESCAPE_CHAR;
+        ping[3] = CMD_PING;
+        assert_eq!(p.receive_in_place(&ping), (4, Ok(Some(Command::Ping))));
+        assert_eq!(
+            p.receive_in_place(&[0x99, 0x98, ESCAPE_CHAR, CMD_PING]),
+            (4, Err(Error::ChecksumMismatch))
+        );
     }
 
     #[test]
-    fn decode_cmd_id() {
+    fn check_max_frame_len() {
         let mut p = CommandDecoder::new();
+        // A SetAttr with a full value is 67 bytes on the wire
+        p.set_max_frame_len(Some(32));
+        assert_eq!(p.receive(0x00), Ok(None));
+        for _ in 0..KEY_LEN {
+            assert_eq!(p.receive(b'k'), Ok(None));
+        }
+        assert_eq!(p.receive(MAX_ATTR_LEN as u8), Ok(None));
+        for i in 0..MAX_ATTR_LEN {
+            let expected = if i == 32 - 10 {
+                Err(Error::FrameTooLong)
+            } else {
+                Ok(None)
+            };
+            assert_eq!(p.receive(0x55), expected);
+        }
+        // The end of the long frame is ignored
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        match p.receive(CMD_ID) {
-            Ok(Some(Command::Id)) => {}
-            e => panic!("Did not expect: {:?}", e),
+        assert_eq!(p.receive(CMD_SATTR), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        // Escapes count, including the one before the command byte
+        p.set_max_frame_len(Some(4));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Err(Error::FrameTooLong));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        // No limit by default
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0x00), Ok(None));
+        for _ in 0..KEY_LEN {
+            assert_eq!(p.receive(b'k'), Ok(None));
         }
+        assert_eq!(p.receive(MAX_ATTR_LEN as u8), Ok(None));
+        for _ in 0..MAX_ATTR_LEN {
+            assert_eq!(p.receive(0x55), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert!(matches!(
+            p.receive(CMD_SATTR),
+            Ok(Some(Command::SetAttr { .. }))
+        ));
     }
 
     #[test]
-    fn encode_cmd_id() {
-        let cmd = Command::Id;
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_ID));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_fault_threshold() {
+        let bad = [0x01, ESCAPE_CHAR, CMD_SET_START_ADDRESS];
+        let mut p = CommandDecoder::new();
+        p.set_fault_threshold(Some(3));
+        for _ in 0..2 {
+            for &ch in &bad {
+                let _ = p.receive(ch);
+            }
+        }
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::LinkFault));
+        // Stays faulty until a good command
+        for &ch in &bad[0..2] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::LinkFault));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        for &ch in &bad[0..2] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
+    }
+
+    /// A small xorshift generator, so the fuzz tests are repeatable.
+    fn next_random(state: &mut u32) -> u32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state
+    }
+
+    /// A random byte, biased towards escapes and real command bytes so we
+    /// actually finish some frames.
+    fn random_byte(state: &mut u32) -> u8 {
+        let r = next_random(state);
+        match r % 8 {
+            0 | 1 => ESCAPE_CHAR,
+            2 => (r >> 8) as u8 % 0x32,
+            _ => (r >> 8) as u8,
+        }
     }
 
     #[test]
-    fn decode_cmd_reset() {
-        let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        match p.receive(CMD_RESET) {
-            Ok(Some(Command::Reset)) => {}
-            e => panic!("Did not expect: {:?}", e),
+    fn check_no_panic_random_commands() {
+        let mut state = 0x1234_5678;
+        for run in 0..256 {
+            let mut p = CommandDecoder::new();
+            p.set_strict(run % 2 == 1);
+            p.set_unknown_policy(match run % 3 {
+                0 => UnknownPolicy::Ignore,
+                1 => UnknownPolicy::Error,
+                _ => UnknownPolicy::Report,
+            });
+            #[cfg(feature = "seqnum")]
+            p.set_seqnum(run % 4 >= 2);
+            #[cfg(feature = "crc")]
+            p.set_checksum(run % 8 >= 4);
+            // Long enough to overflow the buffer now and then
+            let len = 64 + (next_random(&mut state) as usize % (2 * CMD_BUFFER_LEN));
+            for _ in 0..len {
+                let ch = random_byte(&mut state);
+                if let Ok(Some(ref cmd)) = p.receive(ch) {
+                    // Whatever we decoded must be safe to encode again
+                    if let Ok(e) = CommandEncoder::new(cmd) {
+                        assert!(e.count() >= 2);
+                    }
+                }
+                let _ = p.bytes_remaining();
+            }
+            let _ = p.flush();
+        }
+    }
+
+    /// Sum up the commands seen, so two runs can be compared without an
+    /// allocator.
+    fn fingerprint(fp: &core::cell::Cell<u32>, cmd: &Command) {
+        let mut value = fp.get().wrapping_mul(31).wrapping_add(cmd.kind() as u32);
+        if let Command::WritePage { address, data } = *cmd {
+            value = value.wrapping_add(address);
+            for &b in data {
+                value = value.wrapping_mul(31).wrapping_add(u32::from(b));
+            }
         }
+        fp.set(value);
     }
 
     #[test]
-    fn encode_cmd_reset() {
-        let cmd = Command::Reset;
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_RESET));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_read_matches_receive() {
+        let mut state = 0x0BAD_F00D;
+        for run in 0..64 {
+            let mut stream = [0u8; 2048];
+            for b in stream.iter_mut() {
+                // Mostly long runs without escapes, for the fast path
+                let r = next_random(&mut state);
+                *b = match r % 64 {
+                    0 => ESCAPE_CHAR,
+                    1 => (r >> 8) as u8 % 0x32,
+                    _ => (r >> 8) as u8,
+                };
+            }
+            let mut slow = CommandDecoder::new();
+            let mut fast = CommandDecoder::new();
+            let slow_fp = core::cell::Cell::new(0);
+            let fast_fp = core::cell::Cell::new(0);
+            for chunk in stream.chunks(1 + run * 37) {
+                let mut slow_result = Ok(());
+                for &ch in chunk {
+                    match slow.receive(ch) {
+                        Ok(Some(ref cmd)) => fingerprint(&slow_fp, cmd),
+                        Ok(None) => {}
+                        Err(e) => {
+                            slow_result = Err(e);
+                            break;
+                        }
+                    }
+                }
+                let fast_result = fast.read(chunk, |cmd| fingerprint(&fast_fp, cmd));
+                assert_eq!(slow_result, fast_result);
+                if slow_result.is_err() {
+                    break;
+                }
+            }
+            assert_eq!(slow_fp.get(), fast_fp.get());
+            assert_eq!(slow.into_parts(), fast.into_parts());
+        }
     }
 
     #[test]
-    fn decode_cmd_erase_page() {
-        let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_EPAGE) {
-            Ok(Some(Command::ErasePage { address })) => {
-                assert_eq!(address, 0xDEADBEEF);
+    fn check_no_panic_random_responses() {
+        let mut state = 0x8765_4321;
+        for run in 0..256 {
+            let mut p = ResponseDecoder::new();
+            let len = 64 + (next_random(&mut state) as usize % 8192);
+            for i in 0..len {
+                let ch = random_byte(&mut state);
+                if (run + i) % 97 == 0 {
+                    let _ = p.set_payload_len(next_random(&mut state) as usize % 8192);
+                }
+                let _ = p.receive(ch);
             }
-            e => panic!("Did not expect: {:?}", e),
         }
     }
 
     #[test]
-    fn encode_cmd_erase_page() {
-        let cmd = Command::ErasePage { address: 0xDEADBEEF };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_EPAGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_escape_transitions() {
+        // Every run of up to six escapes and literals, so the debug
+        // assertions in the state machine see every transition
+        for len in 1..7 {
+            for pattern in 0..(1u32 << len) {
+                let mut u = Unframer::new();
+                let mut p = CommandDecoder::new();
+                for bit in 0..len {
+                    let ch = if pattern & (1 << bit) != 0 {
+                        ESCAPE_CHAR
+                    } else {
+                        CMD_PING
+                    };
+                    let was_escaped = u.escaped;
+                    let event = u.push(ch);
+                    assert_eq!(event.is_none(), u.escaped);
+                    assert!(!(was_escaped && u.escaped));
+                    let _ = p.receive(ch);
+                    assert_eq!(p.unframer.escaped, u.escaped);
+                }
+            }
+        }
     }
 
     #[test]
-    fn decode_cmd_write_page() {
+    fn check_framer() {
+        let mut f = Framer::new();
+        assert_eq!(f.push(FramedEvent::Literal(0x01)).as_slice(), &[0x01]);
+        assert_eq!(
+            f.push(FramedEvent::Literal(ESCAPE_CHAR)).as_slice(),
+            &[ESCAPE_CHAR, ESCAPE_CHAR]
+        );
+        let mut bytes = f.push(FramedEvent::Terminator(CMD_PING));
+        assert_eq!(bytes.next(), Some(ESCAPE_CHAR));
+        assert_eq!(bytes.next(), Some(CMD_PING));
+        assert_eq!(bytes.next(), None);
+        // Round trip through the unframer
+        let mut u = Unframer::new();
+        let events = [
+            FramedEvent::Literal(ESCAPE_CHAR),
+            FramedEvent::Literal(0x02),
+            FramedEvent::Terminator(CMD_INFO),
+        ];
+        let mut seen = 0;
+        for event in &events {
+            for ch in f.push(*event) {
+                if let Some(e) = u.push(ch) {
+                    assert_eq!(e, events[seen]);
+                    seen += 1;
+                }
+            }
+        }
+        assert_eq!(seen, events.len());
+    }
+
+    #[test]
+    fn check_frame_hex() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        for i in 0..INT_PAGE_SIZE {
-            let datum = i as u8;
-            assert_eq!(p.receive(datum), Ok(None));
-            if datum == ESCAPE_CHAR {
-                assert_eq!(p.receive(datum), Ok(None));
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(0xAB), Ok(None));
+        assert_eq!(p.receive(0x5F), Ok(None));
+        let mut out = [0u8; 16];
+        assert_eq!(p.frame_hex(&mut out), "01AB5F");
+        // Truncated to whole bytes if the output is too small
+        let mut short = [0u8; 5];
+        assert_eq!(p.frame_hex(&mut short), "01AB");
+    }
+
+    /// Byte vectors as built by the Python `tockloader` tool. Its
+    /// `_issue_command(command, message, ...)` doubles any 0xFC in
+    /// `message` and then appends `[0xFC, command]`; each vector notes the
+    /// call that produces it.
+    mod golden_vectors {
+        use super::super::*;
+
+        /// `_issue_command(COMMAND_PING, bytes(), ...)`
+        const PING: [u8; 2] = [0xFC, 0x01];
+
+        /// `_issue_command(COMMAND_INFO, bytes(), ...)`
+        const INFO: [u8; 2] = [0xFC, 0x03];
+
+        /// `_issue_command(COMMAND_ERASE_PAGE, struct.pack('<I', 0x00030000), ...)`
+        #[cfg(feature = "write")]
+        const ERASE_PAGE: [u8; 6] = [0x00, 0x00, 0x03, 0x00, 0xFC, 0x06];
+
+        /// `_issue_command(COMMAND_CHANGE_BAUD_RATE, struct.pack('<BI', 0x01, 921600), ...)`
+        const CHANGE_BAUD_RATE: [u8; 7] = [0x01, 0x00, 0x10, 0x0E, 0x00, 0xFC, 0x21];
+
+        /// `_issue_command(COMMAND_WRITE_PAGE, struct.pack('<I', 0x00030000) + page, ...)`
+        /// where `page` is 512 bytes whose first byte is 0xFC and the rest
+        /// 0xFF, as found in unprogrammed flash.
+        #[cfg(feature = "write")]
+        fn write_page() -> [u8; 4 + 1 + INT_PAGE_SIZE + 2] {
+            let mut v = [0xFFu8; 4 + 1 + INT_PAGE_SIZE + 2];
+            v[0..4].copy_from_slice(&[0x00, 0x00, 0x03, 0x00]);
+            v[4] = 0xFC;
+            v[5] = 0xFC;
+            v[4 + 1 + INT_PAGE_SIZE] = 0xFC;
+            v[4 + 1 + INT_PAGE_SIZE + 1] = 0x07;
+            v
+        }
+
+        fn decode(bytes: &[u8], check: &dyn Fn(&Command)) {
+            let mut p = CommandDecoder::new();
+            let (last, rest) = bytes.split_last().unwrap();
+            for ch in rest {
+                assert_eq!(p.receive(*ch), Ok(None));
+            }
+            match p.receive(*last) {
+                Ok(Some(ref cmd)) => check(cmd),
+                e => panic!("Did not expect: {:?}", e),
             }
         }
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_WPAGE) {
-            Ok(Some(Command::WritePage {
-                        address,
-                        data: ref page,
-                    })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(page.len(), INT_PAGE_SIZE);
-                for i in 0..INT_PAGE_SIZE {
-                    let datum = i as u8;
-                    assert_eq!(datum, page[i as usize]);
+
+        #[test]
+        fn golden_ping() {
+            decode(&PING, &|cmd| assert_eq!(cmd, &Command::Ping));
+        }
+
+        #[test]
+        fn golden_info() {
+            decode(&INFO, &|cmd| assert_eq!(cmd, &Command::Info));
+        }
+
+        #[cfg(feature = "write")]
+        #[test]
+        fn golden_erase_page() {
+            decode(&ERASE_PAGE, &|cmd| {
+                assert_eq!(cmd, &Command::ErasePage { address: 0x00030000 })
+            });
+        }
+
+        #[cfg(feature = "write")]
+        #[test]
+        fn golden_write_page() {
+            decode(&write_page(), &|cmd| match *cmd {
+                Command::WritePage { address, data } => {
+                    assert_eq!(address, 0x00030000);
+                    assert_eq!(data.len(), INT_PAGE_SIZE);
+                    assert_eq!(data[0], 0xFC);
+                    assert!(data[1..].iter().all(|b| *b == 0xFF));
                 }
-            }
-            e => panic!("Did not expect: {:?}", e),
+                ref e => panic!("Did not expect: {:?}", e),
+            });
+        }
+
+        #[test]
+        fn golden_change_baud_rate() {
+            decode(&CHANGE_BAUD_RATE, &|cmd| {
+                assert_eq!(
+                    cmd,
+                    &Command::ChangeBaud {
+                        mode: BaudMode::Set,
+                        baud: 921600,
+                    }
+                )
+            });
         }
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn encode_cmd_write_page() {
-        let mut buffer = [0xBBu8; INT_PAGE_SIZE];
-        buffer[0] = 0xAA;
-        buffer[INT_PAGE_SIZE - 1] = 0xCC;
-        let cmd = Command::WritePage {
-            address: 0xDEADBEEF,
-            data: &buffer,
-        };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // first byte of data
-        assert_eq!(e.next(), Some(0xAA));
-        for _ in 1..(INT_PAGE_SIZE - 1) {
-            assert_eq!(e.next(), Some(0xBB));
+    fn check_abort_payload() {
+        let mut p = CommandDecoder::new();
+        for i in 0..200 {
+            assert_eq!(p.receive(i as u8), Ok(None));
         }
-        // last byte of data
-        assert_eq!(e.next(), Some(0xCC));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_WPAGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        p.abort_payload();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        // The aborted bytes don't end up in the next payload either
+        for i in 0..200 {
+            assert_eq!(p.receive(i as u8), Ok(None));
+        }
+        p.abort_payload();
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_EPAGE),
+            Ok(Some(Command::ErasePage { address: 0xDEADBEEF }))
+        );
     }
 
     #[test]
-    fn decode_cmd_erase_block() {
+    fn check_allowed_bauds() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_XEBLOCK) {
-            Ok(Some(Command::EraseExBlock { address })) => {
-                assert_eq!(address, 0xDEADBEEF);
+        p.set_allowed_bauds(Some(STANDARD_BAUDS));
+        // 115200
+        for ch in &[0x01, 0x00, 0xC2, 0x01, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_CHANGE_BAUD),
+            Ok(Some(Command::ChangeBaud {
+                mode: BaudMode::Set,
+                baud: 115200,
+            }))
+        );
+        // 1234
+        for ch in &[0x01, 0xD2, 0x04, 0x00, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_CHANGE_BAUD), Err(Error::UnsupportedBaud));
+        // Anything goes without a list
+        p.set_allowed_bauds(None);
+        for ch in &[0x01, 0xD2, 0x04, 0x00, 0x00, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_CHANGE_BAUD),
+            Ok(Some(Command::ChangeBaud {
+                mode: BaudMode::Set,
+                baud: 1234,
+            }))
+        );
+    }
+
+    #[cfg(not(feature = "write"))]
+    #[test]
+    fn check_write_unsupported() {
+        let mut p = CommandDecoder::new();
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::Unsupported));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_EPAGE), Err(Error::Unsupported));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_RRANGE), Err(Error::BadArguments));
+    }
+
+    #[test]
+    fn check_receive_in_place() {
+        let mut input = [0u8; 1 + KEY_LEN + 1 + 16 + 2 + 2];
+        input[0] = 2;
+        input[1..9].copy_from_slice(b"key\0\0\0\0\0");
+        input[9] = 16;
+        for (i, b) in input[10..26].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        input[26] = ESCAPE_CHAR;
+        input[27] = CMD_SATTR;
+        input[28] = ESCAPE_CHAR;
+        input[29] = CMD_PING;
+        let mut p = CommandDecoder::new();
+        let (used, result) = p.receive_in_place(&input);
+        assert_eq!(used, 28);
+        match result {
+            Ok(Some(Command::SetAttr { index, key, value })) => {
+                assert_eq!(index, 2);
+                // The key and value are the caller's memory, not a copy
+                assert_eq!(key.as_ptr(), input[1..].as_ptr());
+                assert_eq!(value.as_ptr(), input[10..].as_ptr());
+                assert_eq!(value.len(), 16);
             }
             e => panic!("Did not expect: {:?}", e),
         }
+        assert_eq!(
+            p.receive_in_place(&input[used..]),
+            (2, Ok(Some(Command::Ping)))
+        );
+        // Incomplete
+        assert_eq!(p.receive_in_place(&input[0..27]), (0, Ok(None)));
+        // Escaped data
+        let input = [ESCAPE_CHAR, ESCAPE_CHAR, ESCAPE_CHAR, CMD_GATTR];
+        assert_eq!(p.receive_in_place(&input), (0, Err(Error::Unsupported)));
     }
 
     #[test]
-    fn encode_cmd_erase_block() {
-        let cmd = Command::EraseExBlock { address: 0xDEADBEEF };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XEBLOCK));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_back_to_back_commands() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_INFO), Ok(Some(Command::Info)));
+        // Nothing is left over from either frame
+        assert_eq!(p.count, 0);
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress { address: 0xDEADBEEF }))
+        );
     }
 
     #[test]
-    fn decode_cmd_write_ex_page() {
+    fn check_address_bytes() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        for i in 0..EXT_PAGE_SIZE {
-            let datum = i as u8;
-            assert_eq!(p.receive(datum), Ok(None));
-            if datum == ESCAPE_CHAR {
-                assert_eq!(p.receive(datum), Ok(None));
-            }
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
         }
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_XWPAGE) {
-            Ok(Some(Command::WriteExPage {
-                        address,
-                        data: ref page,
-                    })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(page.len(), EXT_PAGE_SIZE);
-                for i in 0..EXT_PAGE_SIZE {
-                    let datum = i as u8;
-                    assert_eq!(datum, page[i as usize]);
-                }
+        match p.receive(CMD_SET_START_ADDRESS) {
+            Ok(Some(cmd)) => {
+                assert_eq!(cmd.address_bytes(), Some([0xEF, 0xBE, 0xAD, 0xDE]));
+                assert_eq!(cmd.address(), Some(0xDEADBEEF));
             }
             e => panic!("Did not expect: {:?}", e),
         }
+        assert_eq!(Command::Ping.address_bytes(), None);
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn encode_cmd_write_ex_page() {
-        let mut buffer = [0xBBu8; EXT_PAGE_SIZE];
-        buffer[0] = 0xAA;
-        buffer[EXT_PAGE_SIZE - 1] = 0xCC;
-        let cmd = Command::WriteExPage {
-            address: 0xDEADBEEF,
-            data: &buffer,
-        };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // first byte of data
-        assert_eq!(e.next(), Some(0xAA));
-        for _ in 1..(EXT_PAGE_SIZE - 1) {
-            assert_eq!(e.next(), Some(0xBB));
+    fn check_bytes_remaining() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.bytes_remaining(), None);
+        for _ in 0..MAX_SHORT_PAYLOAD {
+            assert_eq!(p.receive(0xAA), Ok(None));
         }
-        // last byte of data
-        assert_eq!(e.next(), Some(0xCC));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XWPAGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        // Could still be a SetAttr
+        assert_eq!(p.bytes_remaining(), None);
+        for _ in MAX_SHORT_PAYLOAD..200 {
+            assert_eq!(p.receive(0xAA), Ok(None));
+        }
+        assert_eq!(p.bytes_remaining(), Some(316));
+        for _ in 200..516 {
+            assert_eq!(p.receive(0xAA), Ok(None));
+        }
+        assert_eq!(p.bytes_remaining(), Some(0));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert!(p.receive(CMD_WPAGE).is_ok());
+        assert_eq!(p.bytes_remaining(), None);
     }
 
+    #[cfg(feature = "heapless")]
     #[test]
-    fn encode_cmd_crcrx() {
-        let cmd = Command::CrcRxBuffer;
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_CRCRX));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_receive_to_queue() {
+        let mut queue: heapless::spsc::Queue<CommandBuf, 4> = heapless::spsc::Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        let mut p = CommandDecoder::new();
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive_to_queue(ch, &mut producer), Ok(false));
+        }
+        assert_eq!(p.receive_to_queue(CMD_SET_START_ADDRESS, &mut producer), Ok(true));
+        // The decoder can carry on without affecting the queued command
+        assert_eq!(p.receive_to_queue(ESCAPE_CHAR, &mut producer), Ok(false));
+        assert_eq!(p.receive_to_queue(CMD_PING, &mut producer), Ok(true));
+        let buf = consumer.dequeue().unwrap();
+        assert_eq!(
+            buf.as_command(),
+            Command::SetStartAddress { address: 0xDEADBEEF }
+        );
+        assert_eq!(consumer.dequeue().unwrap().as_command(), Command::Ping);
+        assert!(consumer.dequeue().is_none());
+        // Fill the queue (which holds N - 1 items)
+        for _ in 0..3 {
+            assert_eq!(p.receive_to_queue(ESCAPE_CHAR, &mut producer), Ok(false));
+            assert_eq!(p.receive_to_queue(CMD_PING, &mut producer), Ok(true));
+        }
+        assert_eq!(p.receive_to_queue(ESCAPE_CHAR, &mut producer), Ok(false));
+        assert_eq!(
+            p.receive_to_queue(CMD_PING, &mut producer),
+            Err(Error::BufferTooSmall)
+        );
     }
 
+    #[cfg(all(feature = "heapless", feature = "seqnum", feature = "crc"))]
     #[test]
-    fn decode_cmd_crcrx() {
+    fn check_receive_to_queue_tagged_checksummed() {
+        let mut queue: heapless::spsc::Queue<CommandBuf, 4> = heapless::spsc::Queue::new();
+        let (mut producer, mut consumer) = queue.split();
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_CRCRX) {
-            Ok(Some(Command::CrcRxBuffer)) => {}
-            e => panic!("Did not expect: {:?}", e),
+        p.set_seqnum(true);
+        p.set_checksum(true);
+        // A sequence number, an address, then the CRC-16 of both
+        let frame = [0x07, 0x00, 0x00, 0x03, 0x00];
+        let mut crc = [0u8; 2];
+        LittleEndian::write_u16(&mut crc, crc16(&frame));
+        for &ch in frame.iter().chain(crc.iter()) {
+            assert_eq!(p.receive_to_queue(ch, &mut producer), Ok(false));
+            if ch == ESCAPE_CHAR {
+                assert_eq!(p.receive_to_queue(ch, &mut producer), Ok(false));
+            }
         }
+        assert_eq!(p.receive_to_queue(ESCAPE_CHAR, &mut producer), Ok(false));
+        assert_eq!(p.receive_to_queue(CMD_SET_START_ADDRESS, &mut producer), Ok(true));
+        assert_eq!(
+            consumer.dequeue().unwrap().as_command(),
+            Command::SetStartAddress { address: 0x0003_0000 }
+        );
+        // A bad checksum queues nothing
+        for &ch in &frame {
+            assert_eq!(p.receive_to_queue(ch, &mut producer), Ok(false));
+        }
+        assert_eq!(p.receive_to_queue(0x00, &mut producer), Ok(false));
+        assert_eq!(p.receive_to_queue(0x00, &mut producer), Ok(false));
+        assert_eq!(p.receive_to_queue(ESCAPE_CHAR, &mut producer), Ok(false));
+        assert_eq!(
+            p.receive_to_queue(CMD_SET_START_ADDRESS, &mut producer),
+            Err(Error::ChecksumMismatch)
+        );
+        assert!(consumer.dequeue().is_none());
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn encode_cmd_rrange() {
-        let cmd = Command::ReadRange {
-            address: 0xDEADBEEF,
-            length: 0x1234,
+    fn check_write_counted() {
+        let page = [ESCAPE_CHAR; INT_PAGE_SIZE];
+        let cmd = Command::WritePage {
+            address: 0x00030000,
+            data: &page,
         };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // 2 byte length
-        assert_eq!(e.next(), Some(0x34));
-        assert_eq!(e.next(), Some(0x12));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_RRANGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        let mut buffer = [0u8; 2 * INT_PAGE_SIZE + 16];
+        let stats = CommandEncoder::new(&cmd).unwrap().write_counted(&mut buffer);
+        assert_eq!(stats.escape_count, 512);
+        assert_eq!(stats.payload_len, 516);
+        assert_eq!(stats.framed_len, 516 + 512 + 2);
+
+        let rsp = Response::CrcIntFlash { crc: 0x00FC_FC01 };
+        let stats = ResponseEncoder::new(&rsp).unwrap().write_counted(&mut buffer);
+        assert_eq!(
+            stats,
+            EncodeStats {
+                framed_len: 8,
+                payload_len: 4,
+                escape_count: 2,
+            }
+        );
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn decode_cmd_rrange() {
+    fn check_into_parts() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_RRANGE) {
-            Ok(Some(Command::ReadRange { address, length })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(length, 0x1234);
+        for &ch in &[0x00, 0x00, 0x03, 0x00] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        for i in 0..256 {
+            assert_eq!(p.receive((i % 0xF0) as u8), Ok(None));
+        }
+        // Stop on an escape, to check it survives too
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        let parts = p.into_parts();
+        assert!(parts.escaped);
+        assert_eq!(parts.len, 4 + 256);
+
+        let mut p = CommandDecoder::from_parts(parts.clone());
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        for i in 257..INT_PAGE_SIZE {
+            assert_eq!(p.receive((i % 0xF0) as u8), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        match p.receive(CMD_WPAGE) {
+            Ok(Some(Command::WritePage { address, data })) => {
+                assert_eq!(address, 0x00030000);
+                assert_eq!(data.len(), INT_PAGE_SIZE);
+                assert_eq!(&data[0..256], &parts.payload[4..260]);
+                assert_eq!(data[256], ESCAPE_CHAR);
+                for (i, &b) in data.iter().enumerate().skip(257) {
+                    assert_eq!(b, (i % 0xF0) as u8);
+                }
             }
             e => panic!("Did not expect: {:?}", e),
         }
     }
 
     #[test]
-    fn encode_cmd_xrrange() {
-        let cmd = Command::ExReadRange {
-            address: 0xDEADBEEF,
-            length: 0x1234,
-        };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // 2 byte length
-        assert_eq!(e.next(), Some(0x34));
-        assert_eq!(e.next(), Some(0x12));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XRRANGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_into_parts_frame_len() {
+        let mut p = CommandDecoder::new();
+        p.set_max_frame_len(Some(8));
+        for &ch in &[0x01, ESCAPE_CHAR, ESCAPE_CHAR, 0x02] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        let mut p = CommandDecoder::from_parts(p.into_parts());
+        p.set_max_frame_len(Some(8));
+        // Four bytes in already, so four more fit
+        for _ in 0..4 {
+            assert_eq!(p.receive(0x55), Ok(None));
+        }
+        assert_eq!(p.receive(0x55), Err(Error::FrameTooLong));
+        // Skipping the rest of the frame carries on after a restore too
+        let mut p = CommandDecoder::from_parts(p.into_parts());
+        for &ch in &[0x55, ESCAPE_CHAR, CMD_PING] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        // And end_of_stream still knows how much of a frame it had
+        for &ch in &[0x01, 0x02, ESCAPE_CHAR, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        let mut p = CommandDecoder::from_parts(p.into_parts());
+        assert_eq!(p.end_of_stream(), Err(Error::Truncated { have: 4 }));
     }
 
+    #[cfg(all(feature = "crc", feature = "seqnum"))]
     #[test]
-    fn decode_cmd_xrrange() {
-        let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_XRRANGE) {
-            Ok(Some(Command::ExReadRange { address, length })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(length, 0x1234);
+    fn check_into_parts_crc() {
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = (i % 0xF0) as u8;
+        }
+        // Restore after each of the first few bytes, as well as part way
+        // through the page
+        for &split in &[1, 2, 3, 4, 5, 6, 100] {
+            let mut frame = std::vec::Vec::new();
+            frame.push(0x42);
+            frame.extend_from_slice(&[0x00, 0x00, 0x03, 0x00]);
+            frame.extend_from_slice(&page);
+            let mut p = CommandDecoder::new();
+            p.set_seqnum(true);
+            for &ch in &frame[0..split] {
+                assert_eq!(p.receive(ch), Ok(None));
             }
-            e => panic!("Did not expect: {:?}", e),
+            let parts = p.into_parts();
+            assert_eq!(parts.tag_len, 1);
+            let mut p = CommandDecoder::from_parts(parts);
+            for &ch in &frame[split..] {
+                assert_eq!(p.receive(ch), Ok(None));
+            }
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert!(p.receive(CMD_WPAGE).unwrap().is_some());
+            assert_eq!(p.current_page_crc(), Some(crc32(&page)));
         }
     }
 
     #[test]
-    fn encode_cmd_sattr() {
-        let r = Command::SetAttr {
-            index: MAX_INDEX,
-            key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
-            value: &[0xAA, 0xBB, 0xCC, 0xDD],
-        };
-        let mut e = CommandEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(MAX_INDEX));
-        assert_eq!(e.next(), Some(0x00));
-        assert_eq!(e.next(), Some(0x11));
-        assert_eq!(e.next(), Some(0x22));
-        assert_eq!(e.next(), Some(0x33));
-        assert_eq!(e.next(), Some(0x44));
-        assert_eq!(e.next(), Some(0x55));
-        assert_eq!(e.next(), Some(0x66));
-        assert_eq!(e.next(), Some(0x77));
-        assert_eq!(e.next(), Some(0x04));
-        assert_eq!(e.next(), Some(0xAA));
-        assert_eq!(e.next(), Some(0xBB));
-        assert_eq!(e.next(), Some(0xCC));
-        assert_eq!(e.next(), Some(0xDD));
-        // Commands don't seem to need padding
+    fn check_unknown_policy() {
+        let mut p = CommandDecoder::new();
+        p.set_unknown_policy(UnknownPolicy::Ignore);
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x99), Ok(None));
+        // The payload was kept
+        assert_eq!(p.count, 1);
+
+        p.set_unknown_policy(UnknownPolicy::Error);
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x99), Err(Error::UnknownCommand));
+        assert_eq!(p.count, 0);
+
+        p.set_unknown_policy(UnknownPolicy::Report);
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x99), Ok(Some(Command::Unknown { opcode: 0x99 })));
+        assert_eq!(p.count, 0);
+        // Known commands are unaffected
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+
+        let cmd = Command::Unknown { opcode: 0x99 };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
         assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_SATTR));
-        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), Some(0x99));
         assert_eq!(e.next(), None);
+        let cmd = Command::Unknown { opcode: ESCAPE_CHAR };
+        assert!(CommandEncoder::new(&cmd).is_err());
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn decode_cmd_sattr() {
-        let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(MAX_INDEX), Ok(None));
-        assert_eq!(p.receive(0x00), Ok(None));
-        assert_eq!(p.receive(0x11), Ok(None));
-        assert_eq!(p.receive(0x22), Ok(None));
-        assert_eq!(p.receive(0x33), Ok(None));
-        assert_eq!(p.receive(0x44), Ok(None));
-        assert_eq!(p.receive(0x55), Ok(None));
-        assert_eq!(p.receive(0x66), Ok(None));
-        assert_eq!(p.receive(0x77), Ok(None));
-        assert_eq!(p.receive(0x04), Ok(None));
-        assert_eq!(p.receive(0xAA), Ok(None));
-        assert_eq!(p.receive(0xBB), Ok(None));
-        assert_eq!(p.receive(0xCC), Ok(None));
-        assert_eq!(p.receive(0xDD), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_SATTR) {
-            Ok(Some(Command::SetAttr { index, key, value })) => {
-                assert_eq!(index, MAX_INDEX);
-                assert_eq!(key, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
-                assert_eq!(value, [0xAA, 0xBB, 0xCC, 0xDD]);
-            }
-            e => panic!("Did not expect: {:?}", e),
+    fn check_tee() {
+        use std::sync::Mutex;
+        use std::vec::Vec;
+        static TEED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+        fn tee(bytes: &[u8]) {
+            TEED.lock().unwrap().extend_from_slice(bytes);
         }
-    }
+        let mut p = CommandDecoder::new();
+        p.set_tee(Some(tee));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert_eq!(&TEED.lock().unwrap()[..], &[ESCAPE_CHAR, CMD_PING]);
 
-    #[test]
-    fn encode_cmd_gattr() {
-        let r = Command::GetAttr { index: MAX_INDEX };
-        let mut e = CommandEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(MAX_INDEX));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_GATTR));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        TEED.lock().unwrap().clear();
+        let frame = [
+            ESCAPE_CHAR, ESCAPE_CHAR, 0xBE, ESCAPE_CHAR, ESCAPE_CHAR, ESCAPE_CHAR, ESCAPE_CHAR,
+            ESCAPE_CHAR, CMD_SET_START_ADDRESS,
+        ];
+        for &ch in &frame[0..frame.len() - 1] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert!(p.receive(CMD_SET_START_ADDRESS).unwrap().is_some());
+        assert_eq!(&TEED.lock().unwrap()[..], &frame[..]);
+
+        // Bad frames are passed on as they were
+        TEED.lock().unwrap().clear();
+        let bad = [0x01, ESCAPE_CHAR, 0x99, ESCAPE_CHAR, CMD_SET_START_ADDRESS];
+        for &ch in &bad[0..bad.len() - 1] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert!(p.receive(CMD_SET_START_ADDRESS).is_err());
+        assert_eq!(&TEED.lock().unwrap()[..], &bad[..]);
+
+        // So are runs copied in one go by read
+        TEED.lock().unwrap().clear();
+        let bytes = [0x10, 0x11, 0x12, ESCAPE_CHAR, 0x99, 0x13, ESCAPE_CHAR];
+        p.read(&bytes, |_| {}).unwrap();
+        assert_eq!(&TEED.lock().unwrap()[..], &bytes[..]);
+
+        // And frames decoded in place, up to the command byte
+        p.reset();
+        TEED.lock().unwrap().clear();
+        let input = [ESCAPE_CHAR, CMD_PING, 0x55];
+        assert_eq!(p.receive_in_place(&input), (2, Ok(Some(Command::Ping))));
+        assert_eq!(&TEED.lock().unwrap()[..], &input[0..2]);
     }
 
+    #[cfg(feature = "hex")]
     #[test]
-    fn decode_cmd_gattr() {
+    fn check_feed_hex() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(MAX_INDEX), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_GATTR) {
-            Ok(Some(Command::GetAttr { index })) => {
-                assert_eq!(index, MAX_INDEX);
-            }
-            e => panic!("Did not expect: {:?}", e),
-        }
+        assert_eq!(p.feed_hex("FC 01"), Ok(Some(Command::Ping)));
+        assert_eq!(p.feed_hex(""), Ok(None));
+        assert_eq!(
+            p.feed_hex("ef be\tad de\nfc23 fc 01"),
+            Ok(Some(Command::SetStartAddress { address: 0xDEADBEEF }))
+        );
+        // Split across calls
+        assert_eq!(p.feed_hex("FC"), Ok(None));
+        assert_eq!(p.feed_hex("03"), Ok(Some(Command::Info)));
+        assert_eq!(p.feed_hex("FG"), Err(Error::BadArguments));
+        assert_eq!(p.feed_hex("F"), Err(Error::BadArguments));
     }
 
+    #[cfg(feature = "latin1")]
     #[test]
-    fn encode_cmd_crcif() {
-        let cmd = Command::CrcIntFlash {
-            address: 0xDEADBEEF,
-            length: 0x12345678,
-        };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // 4 byte length
-        assert_eq!(e.next(), Some(0x78));
-        assert_eq!(e.next(), Some(0x56));
-        assert_eq!(e.next(), Some(0x34));
-        assert_eq!(e.next(), Some(0x12));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_CRCIF));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_feed_bytes_lossy() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.feed_bytes_lossy("\u{FC}\u{01}"), Ok(Some(Command::Ping)));
+        assert_eq!(p.feed_bytes_lossy(""), Ok(None));
+        // Only the low byte counts
+        assert_eq!(p.feed_bytes_lossy("\u{1FC}"), Ok(None));
+        assert_eq!(p.feed_bytes_lossy("\u{301}"), Ok(Some(Command::Ping)));
     }
 
     #[test]
-    fn decode_cmd_crcif() {
+    fn check_just_completed() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(0x78), Ok(None));
-        assert_eq!(p.receive(0x56), Ok(None));
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_CRCIF) {
-            Ok(Some(Command::CrcIntFlash { address, length })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(length, 0x12345678);
-            }
-            e => panic!("Did not expect: {:?}", e),
+        assert!(!p.just_completed());
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+            assert!(!p.just_completed());
         }
+        assert!(p.receive(CMD_SET_START_ADDRESS).unwrap().is_some());
+        assert!(p.just_completed());
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert!(!p.just_completed());
+        // Errors don't count
+        assert!(p.receive(CMD_SET_START_ADDRESS).is_err());
+        assert!(!p.just_completed());
+        p.read(&[ESCAPE_CHAR, CMD_PING], |_| {}).unwrap();
+        assert!(p.just_completed());
     }
 
+    #[cfg(feature = "seqnum")]
     #[test]
-    fn encode_cmd_crcef() {
-        let cmd = Command::CrcExtFlash {
+    fn check_seqnum() {
+        let cmd = Command::SetStartAddress {
             address: 0xDEADBEEF,
-            length: 0x12345678,
         };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // 4 byte length
-        assert_eq!(e.next(), Some(0x78));
-        assert_eq!(e.next(), Some(0x56));
-        assert_eq!(e.next(), Some(0x34));
-        assert_eq!(e.next(), Some(0x12));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_CRCEF));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
-    }
-
-    #[test]
-    fn decode_cmd_crcef() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(0x78), Ok(None));
-        assert_eq!(p.receive(0x56), Ok(None));
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_CRCEF) {
-            Ok(Some(Command::CrcExtFlash { address, length })) => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(length, 0x12345678);
+        assert_eq!(p.receive_tagged(ESCAPE_CHAR), Err(Error::Unsupported));
+        p.set_seqnum(true);
+        for &seq in &[0x42, ESCAPE_CHAR] {
+            let mut result = None;
+            for ch in TaggedEncoder::new(seq, &cmd).unwrap() {
+                if let Some(t) = p.receive_tagged(ch).unwrap() {
+                    result = Some((t.seq, t.command == cmd));
+                }
             }
-            e => panic!("Did not expect: {:?}", e),
+            assert_eq!(result, Some((seq, true)));
         }
+        // A frame with no room for a tag
+        assert_eq!(p.receive_tagged(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive_tagged(CMD_PING), Err(Error::BadArguments));
+        assert_eq!(p.receive_tagged(0x07), Ok(None));
+        assert_eq!(p.receive_tagged(ESCAPE_CHAR), Ok(None));
+        assert_eq!(
+            p.receive_tagged(CMD_PING),
+            Ok(Some(Tagged {
+                seq: 0x07,
+                command: Command::Ping,
+            }))
+        );
     }
 
+    #[cfg(feature = "seqnum")]
     #[test]
-    fn encode_cmd_xepage() {
-        let cmd = Command::EraseExPage { address: 0xDEADBEEF };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XEPAGE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_receive_in_place_seqnum() {
+        let mut p = CommandDecoder::new();
+        p.set_seqnum(true);
+        let input = [0x42, 0x00, 0x00, 0x03, 0x00, ESCAPE_CHAR, CMD_SET_START_ADDRESS];
+        assert_eq!(
+            p.receive_in_place(&input),
+            (7, Ok(Some(Command::SetStartAddress { address: 0x0003_0000 })))
+        );
+        assert_eq!(
+            p.receive_in_place(&[0x42, ESCAPE_CHAR, CMD_PING]),
+            (3, Ok(Some(Command::Ping)))
+        );
+        // No room for a tag
+        assert_eq!(
+            p.receive_in_place(&[ESCAPE_CHAR, CMD_PING]),
+            (2, Err(Error::BadArguments))
+        );
     }
 
     #[test]
-    fn decode_cmd_xepage() {
+    fn check_receive_ref() {
+        let bytes = [ESCAPE_CHAR, CMD_PING, ESCAPE_CHAR, CMD_INFO, ESCAPE_CHAR, CMD_PING];
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_XEPAGE) {
-            Ok(Some(Command::EraseExPage { address })) => {
-                assert_eq!(address, 0xDEADBEEF);
-            }
-            e => panic!("Did not expect: {:?}", e),
-        }
+        let pings = bytes
+            .iter()
+            .filter(|b| p.receive_ref(*b) == Ok(Some(Command::Ping)))
+            .count();
+        assert_eq!(pings, 2);
+        assert_eq!(p.receive_ref(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive_ref(CMD_ID), Ok(Some(Command::Id)));
     }
 
     #[test]
-    fn encode_cmd_xfinit() {
-        let cmd = Command::ExtFlashInit {};
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_XFINIT));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_frame_boundaries() {
+        let bytes = [
+            0x01, ESCAPE_CHAR, ESCAPE_CHAR, 0x02, ESCAPE_CHAR, CMD_PING,
+            ESCAPE_CHAR, ESCAPE_CHAR, ESCAPE_CHAR, CMD_INFO, 0x03,
+        ];
+        let mut b = frame_boundaries(&bytes);
+        assert_eq!(b.next(), Some((4, CMD_PING)));
+        assert_eq!(b.next(), Some((8, CMD_INFO)));
+        assert_eq!(b.next(), None);
+        assert_eq!(frame_boundaries(&[ESCAPE_CHAR]).next(), None);
     }
 
     #[test]
-    fn decode_cmd_xfinit() {
-        let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_XFINIT) {
-            Ok(Some(Command::ExtFlashInit)) => {}
-            e => panic!("Did not expect: {:?}", e),
-        }
+    fn check_verify_crc() {
+        let rsp = Response::CrcIntFlash { crc: 0xDEADBEEF };
+        assert_eq!(verify_crc(0xDEADBEEF, &rsp), Ok(()));
+        assert_eq!(
+            verify_crc(0x12345678, &rsp),
+            Err(CrcMismatch {
+                local: 0x12345678,
+                remote: Some(0xDEADBEEF),
+            })
+        );
+        let rsp = Response::CrcExtFlash { crc: 0xDEADBEEF };
+        assert_eq!(verify_crc(0xDEADBEEF, &rsp), Ok(()));
+        assert_eq!(
+            verify_crc(0x12345678, &Response::BadAddress),
+            Err(CrcMismatch {
+                local: 0x12345678,
+                remote: None,
+            })
+        );
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn encode_cmd_clkout() {
-        let cmd = Command::ClockOut {};
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_CLKOUT));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    fn check_crc_mismatch_display() {
+        use std::string::ToString;
+        let e = CrcMismatch {
+            local: 0x12345678,
+            remote: Some(0xDEADBEEF),
+        };
+        assert_eq!(
+            e.to_string(),
+            "CRC mismatch: expected 0x12345678, device sent 0xdeadbeef"
+        );
     }
 
+    #[cfg(feature = "partial-page")]
     #[test]
-    fn decode_cmd_clkout() {
+    fn check_write_page_partial() {
+        let data = [0xAAu8; 100];
+        let cmd = Command::WritePagePartial {
+            address: 0x00030000,
+            len: 100,
+            data: &data,
+        };
+        let mut buffer = [0u8; 200];
+        let used = CommandEncoder::new(&cmd).unwrap().write(&mut buffer);
+        assert_eq!(used, 4 + 2 + 100 + 2);
+        assert_eq!(&buffer[0..6], &[0x00, 0x00, 0x03, 0x00, 100, 0x00]);
+        assert_eq!(&buffer[106..108], &[ESCAPE_CHAR, CMD_WPAGE_PARTIAL]);
+
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_CLKOUT) {
-            Ok(Some(Command::ClockOut)) => {}
-            e => panic!("Did not expect: {:?}", e),
+        for &ch in &buffer[0..used - 1] {
+            assert_eq!(p.receive(ch), Ok(None));
         }
-    }
+        assert_eq!(p.receive(CMD_WPAGE_PARTIAL), Ok(Some(cmd)));
 
-    #[test]
-    fn encode_cmd_wuser() {
-        let cmd = Command::WriteFlashUserPages {
-            page1: 0xDEADBEEF,
-            page2: 0x12345678,
+        // The length must match the data
+        for &ch in &[0x00, 0x00, 0x03, 0x00, 100, 0x00, 0xAA, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_WPAGE_PARTIAL), Err(Error::BadArguments));
+        let cmd = Command::WritePagePartial {
+            address: 0x00030000,
+            len: 99,
+            data: &data,
         };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        // 4 byte length
-        assert_eq!(e.next(), Some(0x78));
-        assert_eq!(e.next(), Some(0x56));
-        assert_eq!(e.next(), Some(0x34));
-        assert_eq!(e.next(), Some(0x12));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_WUSER));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        assert!(CommandEncoder::new(&cmd).is_err());
     }
 
     #[test]
-    fn decode_cmd_wuser() {
+    fn check_reset_wipes_buffer() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(0x78), Ok(None));
-        assert_eq!(p.receive(0x56), Ok(None));
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_WUSER) {
-            Ok(Some(Command::WriteFlashUserPages { page1, page2 })) => {
-                assert_eq!(page1, 0xDEADBEEF);
-                assert_eq!(page2, 0x12345678);
-            }
-            e => panic!("Did not expect: {:?}", e),
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
         }
+        assert!(p.receive(CMD_SET_START_ADDRESS).unwrap().is_some());
+        assert_eq!(&p.buffer[0..4], &[0xEF, 0xBE, 0xAD, 0xDE]);
+        p.reset();
+        assert!(p.buffer.iter().all(|&b| b == 0));
+
+        // Part of a frame
+        assert_eq!(p.receive(0xAA), Ok(None));
+        p.reset();
+        assert_eq!(p.buffer[0], 0);
     }
 
+    #[cfg(feature = "crc")]
     #[test]
-    fn encode_cmd_change_baud() {
-        let cmd = Command::ChangeBaud {
-            mode: BaudMode::Set,
-            baud: 0xDEADBEEF,
+    fn check_current_page_crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = (i * 7) as u8;
+        }
+        let cmd = Command::WritePage {
+            address: 0x00030000,
+            data: &page,
         };
-        let mut e = CommandEncoder::new(&cmd).unwrap();
-        // Mode
-        assert_eq!(e.next(), Some(0x01));
-        // 4 byte baud (0x0001 C200)
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(CMD_CHANGE_BAUD));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.current_page_crc(), None);
+        // Something else first, to check the CRC starts again
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, 0xAA, 0xBB, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert!(p.receive(CMD_RRANGE).is_ok());
+        let mut result = None;
+        for ch in CommandEncoder::new(&cmd).unwrap() {
+            if let Some(cmd) = p.receive(ch).unwrap() {
+                result = Some(matches!(cmd, Command::WritePage { .. }));
+            }
+        }
+        assert_eq!(result, Some(true));
+        assert_eq!(p.current_page_crc(), Some(crc32(&page)));
     }
 
+    #[cfg(feature = "crc")]
     #[test]
-    fn decode_cmd_change_baud() {
+    fn check_expect_next_page_crc() {
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = (i * 3) as u8;
+        }
+        let cmd = Command::WritePage {
+            address: 0x00030000,
+            data: &page,
+        };
+        let mut bytes = [0u8; 2 * (4 + INT_PAGE_SIZE) + 2];
+        let len = CommandEncoder::new(&cmd).unwrap().write(&mut bytes);
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0x01), Ok(None));
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_CHANGE_BAUD) {
-            Ok(Some(Command::ChangeBaud { mode, baud })) => {
-                assert_eq!(mode, BaudMode::Set);
-                assert_eq!(baud, 0xDEADBEEF);
+        let send = |p: &mut CommandDecoder| {
+            for &ch in &bytes[0..len - 1] {
+                assert_eq!(p.receive(ch), Ok(None));
             }
-            e => panic!("Did not expect: {:?}", e),
+            p.receive(bytes[len - 1]).map(|c| c.map(|c| c.kind()))
+        };
+        p.expect_next_page_crc(crc32(&page));
+        assert_eq!(send(&mut p), Ok(Some(CommandKind::WritePage)));
+        p.expect_next_page_crc(crc32(&page) ^ 1);
+        // Other commands and bad frames leave the expectation alone
+        for &ch in &[0x01, ESCAPE_CHAR, CMD_SET_START_ADDRESS, ESCAPE_CHAR] {
+            let _ = p.receive(ch);
         }
-        assert_eq!(p.receive(0x02), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert_eq!(send(&mut p), Err(Error::PageCrcMismatch));
+        // We still know what did arrive
+        assert_eq!(p.current_page_crc(), Some(crc32(&page)));
+        // And the expectation has gone
+        assert_eq!(send(&mut p), Ok(Some(CommandKind::WritePage)));
+    }
+
+    #[test]
+    fn check_with_buffer() {
+        let mut p = CommandDecoder::with_buffer([0xAA; COMMAND_BUFFER_LEN]);
+        assert_eq!(p.frame_hex(&mut [0u8; 8]), "");
+        // Too short, and the garbage mustn't make up the difference
         assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(p.receive(0xDE), Ok(None));
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
-        match p.receive(CMD_CHANGE_BAUD) {
-            Ok(Some(Command::ChangeBaud { mode, baud })) => {
-                assert_eq!(mode, BaudMode::Verify);
-                assert_eq!(baud, 0xDEADBEEF);
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(
+            p.receive(CMD_SET_START_ADDRESS),
+            Ok(Some(Command::SetStartAddress { address: 0xDEADBEEF }))
+        );
+    }
+
+    #[cfg(feature = "serialport")]
+    #[test]
+    fn check_serial_link() {
+        use std::io::{Cursor, Read, Write};
+        use std::vec::Vec;
+        struct MockPort {
+            input: Cursor<Vec<u8>>,
+            output: Vec<u8>,
+        }
+        impl Read for MockPort {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.input.read(buf)
+            }
+        }
+        impl Write for MockPort {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.output.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
             }
-            e => panic!("Did not expect: {:?}", e),
         }
+        let port = MockPort {
+            input: Cursor::new(std::vec![ESCAPE_CHAR, CMD_PING]),
+            output: Vec::new(),
+        };
+        let mut link = SerialLink::new(port);
+        assert_eq!(link.receive().unwrap(), Command::Ping);
+        link.send(&Response::Pong).unwrap();
+        // Nothing more to read
+        assert!(link.receive().is_err());
+        assert_eq!(link.into_inner().output, &[ESCAPE_CHAR, RES_PONG]);
     }
 
-    // Responses
+    #[test]
+    fn check_read_u32_le() {
+        let bytes = [0x00, 0xEF, 0xBE, 0xAD, 0xDE];
+        assert_eq!(read_u32_le(&bytes, 1), Ok(0xDEADBEEF));
+        assert_eq!(read_u32_le(&bytes, 2), Err(Error::BadArguments));
+        assert_eq!(read_u32_le(&bytes, usize::MAX), Err(Error::BadArguments));
+    }
 
-    fn check_rsp_generic(response: Response, cmd: u8) {
-        let mut p = ResponseDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        match p.receive(cmd) {
-            Ok(Some(ref x)) if x == &response => {}
-            e => panic!("Did not expect: {:?}", e),
-        }
+    /// Turns every frame into its opcode and payload length, and counts them.
+    struct MarkerOpcodes {
+        frames: usize,
+    }
 
-        let mut e = ResponseEncoder::new(&response).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(cmd));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    impl OpcodeDecoder for MarkerOpcodes {
+        type Command<'b> = (u8, usize);
+
+        fn decode(&mut self, opcode: u8, payload: &[u8]) -> Result<Option<(u8, usize)>, Error> {
+            self.frames += 1;
+            Ok(Some((opcode, payload.len())))
+        }
     }
 
     #[test]
-    fn check_rsp_overflow() {
-        check_rsp_generic(Response::Overflow, RES_OVERFLOW);
+    fn check_framed_parser() {
+        let bytes = [
+            ESCAPE_CHAR,
+            CMD_PING,
+            0x01,
+            ESCAPE_CHAR,
+            ESCAPE_CHAR,
+            0x02,
+            ESCAPE_CHAR,
+            0x99,
+        ];
+        let mut p = FramedParser::new(MarkerOpcodes { frames: 0 });
+        let mut markers = [(0u8, 0usize); 2];
+        let mut n = 0;
+        for &ch in &bytes {
+            if let Some(marker) = p.receive(ch).unwrap() {
+                markers[n] = marker;
+                n += 1;
+            }
+        }
+        // Even opcodes the real decoder doesn't know
+        assert_eq!(&markers[..n], &[(CMD_PING, 0), (0x99, 3)]);
+        assert_eq!(p.decoder().frames, 2);
+        p.decoder_mut().frames = 0;
+        // The real decoder is the default
+        let mut p = FramedParser::default();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_INFO), Ok(Some(Command::Info)));
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_SET_START_ADDRESS), Err(Error::BadArguments));
+        for _ in 0..CMD_BUFFER_LEN + 1 {
+            assert_eq!(p.receive(0x00), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Err(Error::Overflow));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
     }
 
     #[test]
-    fn check_rsp_pong() {
-        check_rsp_generic(Response::Pong, RES_PONG);
+    fn check_info_session() {
+        const INFO: &[u8] = br#"{"version":"1.1.0","name":"Tock Bootloader"}"#;
+        let mut session = InfoSession::new();
+        // The device end
+        let mut device = CommandDecoder::new();
+        let mut reply = [0u8; 2 + 1 + MAX_INFO_LEN];
+        let mut len = 0;
+        for &ch in &session.request() {
+            if let Some(cmd) = device.receive(ch).unwrap() {
+                assert_eq!(cmd, Command::Info);
+                let r = Response::Info { info: INFO };
+                len = ResponseEncoder::new(&r).unwrap().write(&mut reply);
+            }
+        }
+        assert_eq!(len, reply.len());
+        for &ch in &reply[0..len - 1] {
+            assert_eq!(session.feed_response(ch), Ok(None));
+        }
+        let info = session.feed_response(reply[len - 1]).unwrap().expect("no info");
+        assert_eq!(info.as_bytes(), INFO);
+        assert_eq!(info.as_str(), Some(r#"{"version":"1.1.0","name":"Tock Bootloader"}"#));
+        // Only one answer is expected
+        assert_eq!(session.feed_response(ESCAPE_CHAR), Ok(None));
+        assert_eq!(session.feed_response(RES_PONG), Err(Error::UnexpectedResponse));
+        // Something other than info
+        let mut session = InfoSession::new();
+        assert_eq!(session.feed_response(ESCAPE_CHAR), Ok(None));
+        assert_eq!(session.feed_response(RES_BADADDR), Err(Error::UnexpectedResponse));
+        // Not text
+        let mut session = InfoSession::new();
+        let r = Response::Info { info: &[0xFF, 0xFE] };
+        let mut results = ResponseEncoder::new(&r).unwrap().map(|ch| session.feed_response(ch));
+        let info = results.find_map(|r| r.unwrap()).unwrap();
+        assert_eq!(info.as_bytes(), &[0xFF, 0xFE]);
+        assert_eq!(info.as_str(), None);
     }
 
     #[test]
-    fn check_rsp_badaddress() {
-        check_rsp_generic(Response::BadAddress, RES_BADADDR);
+    fn check_ping_session() {
+        let mut session = PingSession::new();
+        let mut p = CommandDecoder::new();
+        let request = session.request();
+        assert_eq!(p.receive(request[0]), Ok(None));
+        assert_eq!(p.receive(request[1]), Ok(Some(Command::Ping)));
+        let pong = [ESCAPE_CHAR, RES_PONG];
+        assert_eq!(session.feed_response(pong[0]), Ok(PingStatus::Waiting));
+        assert_eq!(session.feed_response(pong[1]), Ok(PingStatus::Ready));
+        // One Pong too many
+        assert_eq!(session.feed_response(pong[0]), Ok(PingStatus::Ready));
+        assert_eq!(session.feed_response(pong[1]), Err(Error::UnexpectedResponse));
+        // Something other than a Pong
+        let mut session = PingSession::new();
+        assert_eq!(session.feed_response(ESCAPE_CHAR), Ok(PingStatus::Waiting));
+        assert_eq!(session.feed_response(RES_UNKNOWN), Err(Error::UnexpectedResponse));
     }
 
     #[test]
-    fn check_rsp_internalerror() {
-        check_rsp_generic(Response::InternalError, RES_INTERROR);
+    fn check_get_attrs() {
+        let mut commands = get_attrs(0..16).unwrap();
+        assert_eq!(commands.next(), Some(Command::GetAttr { index: 0 }));
+        assert_eq!(commands.last(), Some(Command::GetAttr { index: 15 }));
+        assert_eq!(get_attrs(0..16).unwrap().count(), 16);
+        assert_eq!(get_attrs(15..16).unwrap().count(), 1);
+        // There is no slot 16
+        assert!(get_attrs(16..17).is_err());
+        assert!(get_attrs(0..17).is_err());
+        // The bootloader's replies: slots 0 and 3 are in use
+        let mut table = AttrTable::new();
+        for cmd in get_attrs(0..16).unwrap() {
+            let index = match cmd {
+                Command::GetAttr { index } => index,
+                _ => unreachable!(),
+            };
+            let response = match index {
+                0 => Response::GetAttr {
+                    key: b"board\0\0\0",
+                    value: b"hail",
+                },
+                3 => Response::GetAttr {
+                    key: b"appaddr\0",
+                    value: &[0x00, 0x00, 0x03, 0x00],
+                },
+                _ => Response::GetAttr {
+                    key: &[0u8; KEY_LEN],
+                    value: &[],
+                },
+            };
+            table.insert(index, &response).unwrap();
+        }
+        assert_eq!(table.get(0).unwrap().key(), b"board\0\0\0");
+        assert_eq!(table.get(0).unwrap().value(), b"hail");
+        assert_eq!(table.get(1).unwrap().value(), b"");
+        assert_eq!(table.get(16), None);
+        let in_use: [u8; 2] = [0, 3];
+        assert!(table.iter().map(|(i, _)| i).eq(in_use.iter().cloned()));
+        assert_eq!(
+            table.insert(16, &Response::GetAttr { key: b"", value: b"" }),
+            Err(Error::BadArguments)
+        );
+        assert_eq!(table.insert(0, &Response::Pong), Err(Error::BadArguments));
     }
 
     #[test]
-    fn check_rsp_badarguments() {
-        check_rsp_generic(Response::BadArguments, RES_BADARGS);
+    fn check_util_read() {
+        let bytes = [0xEF, 0xBE, 0xAD, 0xDE, 0x01];
+        assert_eq!(util::read_u32_le(&bytes[0..4]), Some(0xDEADBEEF));
+        assert_eq!(util::read_u32_le(&bytes), Some(0xDEADBEEF));
+        assert_eq!(util::read_u32_le(&bytes[0..3]), None);
+        assert_eq!(util::read_u32_le(&[]), None);
+        assert_eq!(util::read_u16_le(&bytes[0..2]), Some(0xBEEF));
+        assert_eq!(util::read_u16_le(&bytes[3..]), Some(0x01DE));
+        assert_eq!(util::read_u16_le(&bytes[4..]), None);
     }
 
     #[test]
-    fn check_rsp_ok() {
-        check_rsp_generic(Response::Ok, RES_OK);
+    fn check_util_words() {
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut words = util::words(&page, util::Endian::Little).unwrap();
+        assert_eq!(words.next(), Some(0x0302_0100));
+        assert_eq!(words.next(), Some(0x0706_0504));
+        assert_eq!(words.count(), 126);
+        let last = util::words(&page, util::Endian::Little).unwrap().last();
+        assert_eq!(last, Some(0xFFFE_FDFC));
+        let mut words = util::words(&page, util::Endian::Big).unwrap();
+        assert_eq!(words.next(), Some(0x0001_0203));
+        assert!(util::words(&page[0..3], util::Endian::Little).is_err());
+        assert_eq!(util::words(&[], util::Endian::Big).unwrap().count(), 0);
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn check_rsp_unknown() {
-        check_rsp_generic(Response::Unknown, RES_UNKNOWN);
+    fn decode_cmd_write_page_short() {
+        let mut p = CommandDecoder::new();
+        for &ch in &[0x00, 0x03, ESCAPE_CHAR] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::BadArguments));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::BadArguments));
     }
 
     #[test]
-    fn check_rsp_exflashtimeout() {
-        check_rsp_generic(Response::ExtFlashTimeout, RES_XFTIMEOUT);
+    fn check_overflow() {
+        let mut p = CommandDecoder::new();
+        for _ in 0..(CMD_BUFFER_LEN + 1) {
+            assert_eq!(p.receive(0xAA), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Err(Error::Overflow));
+        // Which we report like this
+        let mut e = ResponseEncoder::new(&Response::Overflow).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_OVERFLOW));
+        assert_eq!(e.next(), None);
+        // And the next command is fine
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
     }
 
     #[test]
-    fn check_rsp_exflashpageerror() {
-        check_rsp_generic(Response::ExtFlashPageError, RES_XFEPE);
+    fn check_flush() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.flush(), Ok(()));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.flush(), Err(Error::DanglingEscape));
+        // The escape has gone, so this is just data
+        assert_eq!(p.receive(CMD_PING), Ok(None));
+        assert_eq!(p.flush(), Err(Error::Incomplete));
+        assert_eq!(p.flush(), Ok(()));
     }
 
     #[test]
-    fn check_rsp_changebaudfail() {
-        check_rsp_generic(Response::ChangeBaudFail, RES_CHANGE_BAUD_FAIL);
+    fn check_end_of_stream() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.end_of_stream(), Ok(()));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        assert_eq!(p.end_of_stream(), Ok(()));
+        // Part of a SetStartAddress, with a doubled escape in the address
+        for &ch in &[0x00, ESCAPE_CHAR, ESCAPE_CHAR, 0x03] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.end_of_stream(), Err(Error::Truncated { have: 4 }));
+        assert_eq!(p.end_of_stream(), Ok(()));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.end_of_stream(), Err(Error::Truncated { have: 1 }));
+        // An ignored frame leaves its payload, and its bytes, behind
+        for &ch in &[0x01, ESCAPE_CHAR, 0x99] {
+            assert_eq!(p.receive(ch), Ok(None));
+        }
+        assert_eq!(p.end_of_stream(), Err(Error::Truncated { have: 3 }));
+        // And we start afresh
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn check_rsp_crc_rx() {
-        let mut p = ResponseDecoder::new();
+    fn check_receive_outcomes() {
+        let mut p = CommandDecoder::new();
+        // Nothing yet
+        assert_eq!(p.receive(0x01), Ok(None));
+        // An error, which drops the frame
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_CRCRX), Ok(None));
-        // Length
-        assert_eq!(p.receive(0x34), Ok(None));
-        assert_eq!(p.receive(0x12), Ok(None));
-        // CRC
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(
-            p.receive(0xDE),
-            Ok(Some(Response::CrcRxBuffer {
-                length: 0x1234,
-                crc: 0xDEADBEEF,
-            }))
-        );
-
-        let r = Response::CrcRxBuffer {
-            length: 0x1234,
-            crc: 0xDEADBEEF,
-        };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_CRCRX));
-        assert_eq!(e.next(), Some(0x34));
-        assert_eq!(e.next(), Some(0x12));
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        assert_eq!(p.receive(CMD_EPAGE), Err(Error::BadArguments));
+        // A command, straight after the error
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
     }
 
     #[test]
-    fn check_rsp_rrange() {
-        let mut p = ResponseDecoder::new();
-        p.set_payload_len(4).unwrap();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_RRANGE), Ok(None));
-        // four bytes of data
-        assert_eq!(p.receive(0x00), Ok(None));
-        assert_eq!(p.receive(0x11), Ok(None));
-        assert_eq!(p.receive(0x22), Ok(None));
+    fn check_receive_command() {
+        let mut p = CommandDecoder::new();
+        // Nothing yet
+        assert_eq!(p.receive_command(0x01), None);
+        assert_eq!(p.receive_command(ESCAPE_CHAR), None);
+        // An error looks just the same, and drops the frame
+        assert_eq!(p.receive_command(CMD_SET_START_ADDRESS), None);
+        assert!(!p.is_mid_frame());
+        // A command
+        assert_eq!(p.receive_command(ESCAPE_CHAR), None);
+        assert_eq!(p.receive_command(CMD_PING), Some(Command::Ping));
+        for &ch in &[0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR] {
+            assert_eq!(p.receive_command(ch), None);
+        }
         assert_eq!(
-            p.receive(0x33),
-            Ok(Some(
-                Response::ReadRange { data: &[0x00, 0x11, 0x22, 0x33] },
-            ))
+            p.receive_command(CMD_SET_START_ADDRESS),
+            Some(Command::SetStartAddress { address: 0xDEADBEEF })
         );
-
-        let r = Response::ReadRange { data: &[0x00, 0x11, 0x22, 0x33] };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_RRANGE));
-        assert_eq!(e.next(), Some(0x00));
-        assert_eq!(e.next(), Some(0x11));
-        assert_eq!(e.next(), Some(0x22));
-        assert_eq!(e.next(), Some(0x33));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
     }
 
     #[test]
-    fn check_rsp_xrrange() {
-        let mut p = ResponseDecoder::new();
-        p.set_payload_len(4).unwrap();
+    fn check_strict_escape_run() {
+        let mut p = CommandDecoder::new();
+        p.set_strict(true);
+        // Doubled escapes are just 0xFC bytes in the payload
+        for _ in 0..8 {
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        }
+        p.reset();
+        // Three escapes in a row which don't give a command, then a fourth
+        for _ in 0..3 {
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(p.receive(0x99), Ok(None));
+        }
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_XRRANGE), Ok(None));
-        // four bytes of data
-        assert_eq!(p.receive(0x00), Ok(None));
-        assert_eq!(p.receive(0x11), Ok(None));
-        assert_eq!(p.receive(0x22), Ok(None));
-        assert_eq!(
-            p.receive(0x33),
-            Ok(Some(
-                Response::ExReadRange { data: &[0x00, 0x11, 0x22, 0x33] },
-            ))
-        );
+        assert_eq!(p.receive(0x99), Err(Error::TooManyEscapes));
+        // The decoder recovers afterwards
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_PING), Ok(Some(Command::Ping)));
+        // A higher limit lets the run through
+        p.set_max_escape_run(4);
+        for _ in 0..4 {
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+            assert_eq!(p.receive(0x99), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(0x99), Err(Error::TooManyEscapes));
+    }
 
-        let r = Response::ExReadRange { data: &[0x00, 0x11, 0x22, 0x33] };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_XRRANGE));
-        assert_eq!(e.next(), Some(0x00));
-        assert_eq!(e.next(), Some(0x11));
-        assert_eq!(e.next(), Some(0x22));
-        assert_eq!(e.next(), Some(0x33));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_strict_escaped_page() {
+        let mut page = [0x11u8; INT_PAGE_SIZE];
+        page[10..14].copy_from_slice(&[ESCAPE_CHAR; 4]);
+        let cmd = Command::WritePage {
+            address: 0x0003_0000,
+            data: &page,
+        };
+        let mut p = CommandDecoder::new();
+        p.set_strict(true);
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        let mut ch = e.next().unwrap();
+        for next in e {
+            assert_eq!(p.receive(ch), Ok(None));
+            ch = next;
+        }
+        assert_eq!(p.receive(ch), Ok(Some(cmd)));
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn check_rsp_get_attr() {
-        let mut p = ResponseDecoder::new();
+    fn check_strict_missing_escape() {
+        // Page data where 0xFC at offset 10 wasn't doubled
+        let mut p = CommandDecoder::new();
+        p.set_strict(true);
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        for i in 0..10 {
+            assert_eq!(p.receive(i as u8), Ok(None));
+        }
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_GATTR), Ok(None));
-        // eight bytes of key
-        assert_eq!(p.receive(0x00), Ok(None));
-        assert_eq!(p.receive(0x11), Ok(None));
-        assert_eq!(p.receive(0x22), Ok(None));
-        assert_eq!(p.receive(0x33), Ok(None));
-        assert_eq!(p.receive(0x44), Ok(None));
-        assert_eq!(p.receive(0x55), Ok(None));
-        assert_eq!(p.receive(0x66), Ok(None));
-        assert_eq!(p.receive(0x77), Ok(None));
-        // Length byte
-        assert_eq!(p.receive(0x04), Ok(None));
-        // length bytes of data
-        assert_eq!(p.receive(0xAA), Ok(None));
-        assert_eq!(p.receive(0xBB), Ok(None));
-        assert_eq!(p.receive(0xCC), Ok(None));
-        assert_eq!(p.receive(0xDD), Ok(None));
-        // 55 - length bytes of padding
-        for _ in 4..MAX_ATTR_LEN - 1 {
-            assert_eq!(p.receive(0xFF), Ok(None));
+        assert_eq!(p.receive(0x0A), Err(Error::MissingEscape));
+
+        // This time the following data byte looks like a WritePage
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE] {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        for i in 0..10 {
+            assert_eq!(p.receive(i as u8), Ok(None));
+        }
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(CMD_WPAGE), Err(Error::MissingEscape));
+
+        // Without strict mode the short page goes through
+        p.set_strict(false);
+        for ch in &[0xEF, 0xBE, 0xAD, 0xDE] {
+            assert_eq!(p.receive(*ch), Ok(None));
         }
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
         assert_eq!(
-            p.receive(0xFF),
-            Ok(Some(Response::GetAttr {
-                key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
-                value: &[0xAA, 0xBB, 0xCC, 0xDD],
+            p.receive(CMD_WPAGE),
+            Ok(Some(Command::WritePage {
+                address: 0xDEADBEEF,
+                data: &[0x00],
             }))
         );
+    }
 
-        let r = Response::GetAttr {
-            key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
-            value: &[0xAA, 0xBB, 0xCC, 0xDD],
+    #[test]
+    fn check_lax_escape_run() {
+        let mut p = CommandDecoder::new();
+        for _ in 0..8 {
+            assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        }
+    }
+
+    #[cfg(all(feature = "std", feature = "write"))]
+    #[test]
+    fn check_command_write_to() {
+        let mut page = [0xBBu8; INT_PAGE_SIZE];
+        page[10] = ESCAPE_CHAR;
+        let cmd = Command::WritePage {
+            address: 0xDEADBEEF,
+            data: &page,
         };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_GATTR));
-        assert_eq!(e.next(), Some(0x00));
-        assert_eq!(e.next(), Some(0x11));
-        assert_eq!(e.next(), Some(0x22));
-        assert_eq!(e.next(), Some(0x33));
-        assert_eq!(e.next(), Some(0x44));
-        assert_eq!(e.next(), Some(0x55));
-        assert_eq!(e.next(), Some(0x66));
-        assert_eq!(e.next(), Some(0x77));
-        assert_eq!(e.next(), Some(0x04));
-        assert_eq!(e.next(), Some(0xAA));
-        assert_eq!(e.next(), Some(0xBB));
-        assert_eq!(e.next(), Some(0xCC));
-        assert_eq!(e.next(), Some(0xDD));
-        for _ in 4..MAX_ATTR_LEN {
-            assert!(e.next().is_some());
+        let mut out = std::vec::Vec::new();
+        cmd.write_to(&mut out).unwrap();
+        // Address, page, one doubled escape, then the terminator
+        assert_eq!(out.len(), 4 + INT_PAGE_SIZE + 1 + 2);
+        let mut p = CommandDecoder::new();
+        let (last, rest) = out.split_last().unwrap();
+        for ch in rest {
+            assert_eq!(p.receive(*ch), Ok(None));
+        }
+        assert_eq!(p.receive(*last), Ok(Some(cmd)));
+    }
+
+    #[test]
+    fn check_attr_chunks() {
+        let key = [0x6B, 0x65, 0x79, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let mut value = [0u8; 120];
+        for (i, v) in value.iter_mut().enumerate() {
+            *v = i as u8;
         }
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        let mut out = [0u8; 128];
+        let mut assembler = AttrAssembler::new(&mut out);
+        let mut slots = 0;
+        for (i, cmd) in AttrChunks::new(3, &key, &value).unwrap().enumerate() {
+            match cmd {
+                Command::SetAttr {
+                    index,
+                    key: k,
+                    value: v,
+                } => {
+                    assert_eq!(index, 3 + i as u8);
+                    assert_eq!(k, &key);
+                    assert_eq!(v.len(), [55, 55, 10][i]);
+                    // Check the command is valid on the wire
+                    assert!(CommandEncoder::new(&cmd).is_ok());
+                    assembler.push(v).unwrap();
+                }
+                e => panic!("Did not expect: {:?}", e),
+            }
+            slots += 1;
+        }
+        assert_eq!(slots, 3);
+        assert_eq!(assembler.value(), &value[..]);
     }
 
     #[test]
-    fn check_rsp_crc_int_flash() {
-        let mut p = ResponseDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_CRCIF), Ok(None));
-        // CRC
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
-        assert_eq!(
-            p.receive(0xDE),
-            Ok(Some(Response::CrcIntFlash { crc: 0xDEADBEEF }))
-        );
+    fn check_attr_chunks_bad() {
+        let key = [0u8; KEY_LEN];
+        let value = [0u8; 120];
+        // Slots 14, 15 and 16 - 16 doesn't exist
+        assert!(AttrChunks::new(14, &key, &value).is_err());
+        assert!(AttrChunks::new(16, &key, &value[0..1]).is_err());
+        assert!(AttrChunks::new(13, &key, &value).is_ok());
+        assert!(AttrChunks::new(0, &key[0..4], &value).is_err());
+        let mut out = [0u8; 4];
+        let mut assembler = AttrAssembler::new(&mut out);
+        assert_eq!(assembler.push(&value[0..5]), Err(Error::BufferTooSmall));
+    }
 
-        let r = Response::CrcIntFlash { crc: 0xDEADBEEF };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_CRCIF));
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    #[cfg(all(feature = "stats", feature = "write"))]
+    #[test]
+    fn check_stats() {
+        let mut p = CommandDecoder::new();
+        let buffer = [
+            ESCAPE_CHAR,
+            CMD_PING,
+            ESCAPE_CHAR,
+            CMD_INFO,
+            ESCAPE_CHAR,
+            CMD_PING,
+            0xEF,
+            0xBE,
+            0xAD,
+            0xDE,
+            ESCAPE_CHAR,
+            CMD_EPAGE,
+            ESCAPE_CHAR,
+            CMD_EPAGE,
+        ];
+        // The last erase has no address
+        assert_eq!(p.read(&buffer, |_| {}), Err(Error::BadArguments));
+        let stats = p.stats();
+        assert_eq!(stats.ping, 2);
+        assert_eq!(stats.info, 1);
+        assert_eq!(stats.erase_page, 1);
+        assert_eq!(stats.write_page, 0);
+        assert_eq!(stats.errors, 1);
     }
 
+    #[cfg(all(feature = "async", feature = "write"))]
     #[test]
-    fn check_rsp_crc_ext_flash() {
-        let mut p = ResponseDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_CRCXF), Ok(None));
-        // CRC
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
-        assert_eq!(p.receive(0xAD), Ok(None));
+    fn check_command_stream() {
+        extern crate futures;
+        let bytes = [
+            0xEFu8,
+            0xBE,
+            0xAD,
+            0xDE,
+            ESCAPE_CHAR,
+            CMD_EPAGE,
+            ESCAPE_CHAR,
+            CMD_PING,
+        ];
+        let reader = futures::io::Cursor::new(&bytes[..]);
+        let mut stream = futures::executor::block_on_stream(CommandStream::new(reader));
         assert_eq!(
-            p.receive(0xDE),
-            Ok(Some(Response::CrcExtFlash { crc: 0xDEADBEEF }))
+            stream.next(),
+            Some(Ok(CommandOwned::ErasePage { address: 0xDEADBEEF }))
         );
+        assert_eq!(stream.next(), Some(Ok(CommandOwned::Ping)));
+        assert_eq!(stream.next(), None);
+    }
 
-        let r = Response::CrcExtFlash { crc: 0xDEADBEEF };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_CRCXF));
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
-        assert_eq!(e.next(), Some(0xAD));
-        assert_eq!(e.next(), Some(0xDE));
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+    #[cfg(feature = "async")]
+    #[test]
+    fn check_command_stream_truncated() {
+        extern crate futures;
+        let bytes = [0xEFu8, 0xBE];
+        let reader = futures::io::Cursor::new(&bytes[..]);
+        let mut stream = futures::executor::block_on_stream(CommandStream::new(reader));
+        assert_eq!(stream.next(), Some(Err(Error::Incomplete)));
+        assert_eq!(stream.next(), None);
     }
 
+    #[cfg(feature = "async")]
     #[test]
-    fn check_rsp_info() {
-        let mut p = ResponseDecoder::new();
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_INFO), Ok(None));
-        // length
-        assert_eq!(p.receive(0x08), Ok(None));
-        // eight bytes of data
-        assert_eq!(p.receive(0x00), Ok(None));
-        assert_eq!(p.receive(0x11), Ok(None));
-        assert_eq!(p.receive(0x22), Ok(None));
-        assert_eq!(p.receive(0x33), Ok(None));
-        assert_eq!(p.receive(0x44), Ok(None));
-        assert_eq!(p.receive(0x55), Ok(None));
-        assert_eq!(p.receive(0x66), Ok(None));
-        assert_eq!(p.receive(0x77), Ok(None));
-        // pad up to 191 bytes
-        for _ in 8..MAX_INFO_LEN - 1 {
-            assert_eq!(p.receive(0x00), Ok(None));
+    fn check_command_stream_io_error() {
+        extern crate futures;
+        use core::pin::Pin;
+        use core::task::{Context, Poll};
+        struct TimesOut;
+        impl futures::io::AsyncRead for TimesOut {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                _buf: &mut [u8],
+            ) -> Poll<std::io::Result<usize>> {
+                Poll::Ready(Err(std::io::ErrorKind::TimedOut.into()))
+            }
         }
-        // final padding byte
-        assert_eq!(
-            p.receive(0x00),
-            Ok(Some(Response::Info {
-                info: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
-            }))
-        );
+        let mut stream = futures::executor::block_on_stream(CommandStream::new(TimesOut));
+        let e = stream.next().unwrap().unwrap_err();
+        assert_eq!(e, Error::Io(std::io::ErrorKind::TimedOut));
+        // And it comes back out as the same kind of io::Error
+        assert_eq!(std::io::Error::from(e).kind(), std::io::ErrorKind::TimedOut);
+    }
 
-        // Check follow-on command
-        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
-        assert_eq!(p.receive(RES_PONG), Ok(Some(Response::Pong)));
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_read_from() {
+        let bytes = [0x42, ESCAPE_CHAR, 0x99, ESCAPE_CHAR, CMD_INFO, 0x01];
+        let mut cursor = std::io::Cursor::new(&bytes[..]);
+        let mut p = CommandDecoder::new();
+        // The unknown command byte is skipped over
+        assert_eq!(p.read_from(&mut cursor).unwrap(), Command::Info);
+        // Out of bytes part way through the next frame
+        let e = p.read_from(&mut cursor).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+        // A bad frame
+        let bytes = [0x01, ESCAPE_CHAR, CMD_EPAGE];
+        let mut cursor = std::io::Cursor::new(&bytes[..]);
+        let e = p.read_from(&mut cursor).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
 
-        let r = Response::Info { info: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77] };
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(ESCAPE_CHAR));
-        assert_eq!(e.next(), Some(RES_INFO));
-        // length
-        assert_eq!(e.next(), Some(0x08));
-        // data
-        assert_eq!(e.next(), Some(0x00));
-        assert_eq!(e.next(), Some(0x11));
-        assert_eq!(e.next(), Some(0x22));
-        assert_eq!(e.next(), Some(0x33));
-        assert_eq!(e.next(), Some(0x44));
-        assert_eq!(e.next(), Some(0x55));
-        assert_eq!(e.next(), Some(0x66));
-        assert_eq!(e.next(), Some(0x77));
-        // padding
-        for _ in 8..MAX_INFO_LEN {
-            assert_eq!(e.next(), Some(0x00));
+    #[test]
+    fn check_receive_iter() {
+        let bytes = [
+            ESCAPE_CHAR,
+            CMD_PING,
+            ESCAPE_CHAR,
+            CMD_INFO,
+            0x99,
+            ESCAPE_CHAR,
+            0x42,
+            0x01,
+            ESCAPE_CHAR,
+            CMD_SET_START_ADDRESS,
+            ESCAPE_CHAR,
+            CMD_PING,
+            ESCAPE_CHAR,
+        ];
+        let mut p = CommandDecoder::new();
+        let mut commands = p.receive_iter(bytes.iter().cloned());
+        assert_eq!(commands.next_command(), Some(Ok(Command::Ping)));
+        assert_eq!(commands.next_command(), Some(Ok(Command::Info)));
+        // The unknown command byte is skipped over, then a bad frame
+        assert_eq!(commands.next_command(), Some(Err(Error::BadArguments)));
+        assert_eq!(commands.next_command(), Some(Ok(Command::Ping)));
+        // Out of bytes part way through the next frame
+        assert_eq!(commands.next_command(), None);
+        assert_eq!(commands.next_command(), None);
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_drain() {
+        let mut bytes = [0u8; 4 + INT_PAGE_SIZE + 6 + 2];
+        bytes[0] = ESCAPE_CHAR;
+        bytes[1] = CMD_PING;
+        // A page of zeros at address zero
+        bytes[2 + 4 + INT_PAGE_SIZE] = ESCAPE_CHAR;
+        bytes[2 + 4 + INT_PAGE_SIZE + 1] = CMD_WPAGE;
+        let len = bytes.len();
+        bytes[len - 4] = ESCAPE_CHAR;
+        bytes[len - 3] = CMD_INFO;
+        // The start of a frame we don't finish
+        bytes[len - 2] = 0x01;
+        bytes[len - 1] = 0x02;
+        let mut p = CommandDecoder::new();
+        {
+            let mut commands = p.drain(&bytes);
+            assert_eq!(commands.next_command(), Some(Ok(Command::Ping)));
+            match commands.next_command() {
+                Some(Ok(Command::WritePage { address, data })) => {
+                    assert_eq!(address, 0);
+                    assert_eq!(data, &[0u8; INT_PAGE_SIZE][..]);
+                }
+                e => panic!("Did not expect: {:?}", e),
+            }
+            assert_eq!(commands.next_command(), Some(Ok(Command::Info)));
+            assert_eq!(commands.next_command(), None);
         }
-        assert_eq!(e.next(), None);
-        assert_eq!(e.next(), None);
+        assert!(p.is_mid_frame());
     }
 
+    #[cfg(all(feature = "std", feature = "write"))]
     #[test]
-    fn check_response_write() {
-        let r = Response::Pong;
-        // More than large enough
-        let mut e = ResponseEncoder::new(&r).unwrap();
-        let mut buffer = [0xFFu8; 4];
-        assert_eq!(e.write(&mut buffer), 2);
-        assert_eq!(&buffer[0..4], &[ESCAPE_CHAR, RES_PONG, 0xFF, 0xFF]);
+    fn check_drain_into_owned() {
+        let mut bytes = std::vec::Vec::new();
+        let page = [0x42u8; INT_PAGE_SIZE];
+        let cmds = [
+            Command::Ping,
+            Command::WritePage {
+                address: 0x0003_0000,
+                data: &page,
+            },
+            Command::Info,
+        ];
+        for cmd in &cmds {
+            cmd.write_to(&mut bytes).unwrap();
+        }
+        let mut p = CommandDecoder::new();
+        let owned: std::vec::Vec<CommandOwned> =
+            p.drain(&bytes).into_owned().collect::<Result<_, _>>().unwrap();
+        assert_eq!(owned.len(), 3);
+        for (o, cmd) in owned.iter().zip(cmds.iter()) {
+            assert_eq!(*o, CommandOwned::from(cmd));
+        }
+    }
 
-        // Too small - do it in pieces
-        e.reset();
-        buffer[0] = 0xFF;
-        assert_eq!(e.write(&mut buffer[0..1]), 1);
-        assert_eq!(buffer[0], ESCAPE_CHAR);
-        buffer[0] = 0xFF;
-        assert_eq!(e.write(&mut buffer[0..1]), 1);
-        assert_eq!(buffer[0], RES_PONG);
-        assert_eq!(e.write(&mut buffer[0..1]), 0);
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_encode_into_vec() {
+        let mut buf = std::vec::Vec::new();
+        let mut p = CommandDecoder::new();
+        let cmd = Command::SetStartAddress {
+            address: 0x0003_00FC,
+        };
+        cmd.encode_into_vec(&mut buf).unwrap();
+        // The address has an escape in it
+        assert_eq!(buf.len(), 7);
+        let capacity = buf.capacity();
+        p.read(&buf, |c| assert_eq!(*c, cmd)).unwrap();
+        assert!(p.just_completed());
+        Command::Ping.encode_into_vec(&mut buf).unwrap();
+        assert_eq!(buf, [ESCAPE_CHAR, CMD_PING]);
+        assert_eq!(buf.capacity(), capacity);
+        p.read(&buf, |c| assert_eq!(*c, Command::Ping)).unwrap();
+        assert!(p.just_completed());
+        // A bad command leaves the buffer alone
+        let bad = Command::ReadRange {
+            address: 0,
+            length: 0,
+        };
+        assert_eq!(bad.encode_into_vec(&mut buf), Err(Error::BadArguments));
+        assert_eq!(buf, [ESCAPE_CHAR, CMD_PING]);
+    }
 
-        // perfectly sized
-        e.reset();
-        for b in buffer.iter_mut() {
-            *b = 0xFF
+    #[cfg(all(feature = "std", feature = "crc"))]
+    #[test]
+    fn check_flash_image() {
+        let image: std::vec::Vec<u8> = (0..700).map(|i| i as u8).collect();
+        let mut padded = image.clone();
+        padded.resize(2 * INT_PAGE_SIZE, 0);
+        // The device says OK to both pages, then gives the right CRC
+        let mut replies = std::vec::Vec::new();
+        Response::Ok.write_to(&mut replies).unwrap();
+        Response::Ok.write_to(&mut replies).unwrap();
+        let crc = Response::CrcIntFlash {
+            crc: crc32(&padded),
+        };
+        crc.write_to(&mut replies).unwrap();
+        let mut sent = std::vec::Vec::new();
+        let mut source = std::io::Cursor::new(&replies[..]);
+        flash_image(&image, 0x30000, &mut sent, &mut source).unwrap();
+        let (commands, _) = CommandDecoder::new().parse_all(&sent).unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(
+            commands[1],
+            CommandOwned::WritePage {
+                address: 0x30200,
+                data: padded[512..].to_vec(),
+            }
+        );
+        assert_eq!(
+            commands[2],
+            CommandOwned::CrcIntFlash {
+                address: 0x30000,
+                length: 1024,
+            }
+        );
+        // A bad CRC
+        let mut replies = std::vec::Vec::new();
+        Response::Ok.write_to(&mut replies).unwrap();
+        Response::Ok.write_to(&mut replies).unwrap();
+        Response::CrcIntFlash { crc: 0 }.write_to(&mut replies).unwrap();
+        let mut source = std::io::Cursor::new(&replies[..]);
+        match flash_image(&image, 0x30000, &mut std::io::sink(), &mut source) {
+            Err(FlashError::Verify(e)) => assert_eq!(e.remote, Some(0)),
+            e => panic!("Did not expect: {:?}", e),
+        }
+        // A page rejected, and a device which goes quiet
+        let mut replies = std::vec::Vec::new();
+        Response::Ok.write_to(&mut replies).unwrap();
+        Response::BadAddress.write_to(&mut replies).unwrap();
+        let mut source = std::io::Cursor::new(&replies[..]);
+        match flash_image(&image, 0x30000, &mut std::io::sink(), &mut source) {
+            Err(FlashError::Rejected { address: 0x30200 }) => {}
+            e => panic!("Did not expect: {:?}", e),
+        }
+        match flash_image(&image, 0x30000, &mut std::io::sink(), &mut source) {
+            Err(FlashError::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {}
+            e => panic!("Did not expect: {:?}", e),
         }
-        assert_eq!(e.write(&mut buffer[0..2]), 2);
-        assert_eq!(&buffer[0..2], &[ESCAPE_CHAR, RES_PONG]);
     }
 
+    #[cfg(feature = "std")]
     #[test]
-    fn check_command_write() {
-        let r = Command::Ping;
-        let mut e = CommandEncoder::new(&r).unwrap();
-        let mut buffer = [0xFFu8; 4];
-        // More than large enough
-        assert_eq!(e.write(&mut buffer), 2);
-        assert_eq!(&buffer[0..4], &[ESCAPE_CHAR, CMD_PING, 0xFF, 0xFF]);
-
-        // Too small - do it in pieces
-        e.reset();
-        buffer[0] = 0xFF;
-        assert_eq!(e.write(&mut buffer[0..1]), 1);
-        assert_eq!(buffer[0], ESCAPE_CHAR);
-        buffer[0] = 0xFF;
-        assert_eq!(e.write(&mut buffer[0..1]), 1);
-        assert_eq!(buffer[0], CMD_PING);
-        assert_eq!(e.write(&mut buffer[0..1]), 0);
+    fn check_pages() {
+        let image: std::vec::Vec<u8> = (0..1000).map(|i| (i % 251) as u8 + 1).collect();
+        let cmds: std::vec::Vec<CommandOwned> = pages(&image, 0x10000, INT_PAGE_SIZE)
+            .unwrap()
+            .collect();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(
+            cmds[0],
+            CommandOwned::WritePage {
+                address: 0x10000,
+                data: image[0..512].to_vec(),
+            }
+        );
+        match cmds[1] {
+            CommandOwned::WritePage {
+                address,
+                ref data,
+            } => {
+                assert_eq!(address, 0x10200);
+                assert_eq!(data.len(), INT_PAGE_SIZE);
+                assert_eq!(&data[0..488], &image[512..]);
+                assert!(data[488..].iter().all(|&b| b == 0));
+            }
+            ref e => panic!("Did not expect: {:?}", e),
+        }
+        assert_eq!(pages(&[], 0x10000, INT_PAGE_SIZE).unwrap().count(), 0);
+    }
 
-        // perfectly sized
-        e.reset();
-        for b in buffer.iter_mut() {
-            *b = 0xFF
+    #[cfg(feature = "std")]
+    #[test]
+    fn check_pages_bad() {
+        let image = [0xAAu8; 1000];
+        assert!(matches!(pages(&image, 0x10000, 0), Err(Error::BadArguments)));
+        // Pages we couldn't encode
+        assert!(matches!(pages(&image, 0x10000, 256), Err(Error::BadArguments)));
+        assert!(matches!(pages(&image, 0x10000, 1024), Err(Error::BadArguments)));
+        // The second page would start at 0x1_0000_0000
+        assert!(matches!(
+            pages(&image, 0xFFFF_FE00, INT_PAGE_SIZE),
+            Err(Error::BadAddress)
+        ));
+        // But a last page right at the top is fine
+        let cmds: std::vec::Vec<CommandOwned> = pages(&image, 0xFFFF_FC00, INT_PAGE_SIZE)
+            .unwrap()
+            .collect();
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[1].as_command().address(), Some(0xFFFF_FE00));
+        // Whatever we do give out can be sent
+        for cmd in &cmds {
+            assert!(CommandEncoder::new(&cmd.as_command()).is_ok());
         }
-        assert_eq!(e.write(&mut buffer[0..2]), 2);
-        assert_eq!(&buffer[0..2], &[ESCAPE_CHAR, CMD_PING]);
     }
 
+    #[cfg(all(feature = "std", feature = "write"))]
     #[test]
-    fn check_command_decode_buffer() {
-        let mut p = CommandDecoder::new();
-        let buffer = [
-            0xEFu8,
+    fn check_parse_all() {
+        let bytes = [
+            ESCAPE_CHAR,
+            CMD_PING,
+            0xEF,
             0xBE,
             0xAD,
             0xDE,
-            0x78,
-            0x56,
-            0x34,
-            0x12,
             ESCAPE_CHAR,
-            CMD_CRCIF,
+            CMD_EPAGE,
+            ESCAPE_CHAR,
+            CMD_INFO,
+            // Partial
+            0x03,
+            ESCAPE_CHAR,
         ];
-        let callback = |x: &Command| match x {
-            &Command::CrcIntFlash { address, length } => {
-                assert_eq!(address, 0xDEADBEEF);
-                assert_eq!(length, 0x12345678);
-            }
-            _ => panic!("Bad command {:?}", x),
-        };
-        match p.read(&buffer, callback) {
-            Ok(_) => {}
-            Err(e) => panic!("Did not expect: {:?}", e),
-        }
+        let mut p = CommandDecoder::new();
+        let (commands, used) = p.parse_all(&bytes).unwrap();
+        assert_eq!(
+            commands,
+            [
+                CommandOwned::Ping,
+                CommandOwned::ErasePage { address: 0xDEADBEEF },
+                CommandOwned::Info,
+            ]
+        );
+        assert_eq!(used, 10);
+        // Carry the remainder over
+        let mut next = bytes[used..].to_vec();
+        next.push(CMD_GATTR);
+        let (commands, used) = p.parse_all(&next).unwrap();
+        assert_eq!(commands, [CommandOwned::GetAttr { index: 3 }]);
+        assert_eq!(used, next.len());
     }
 
+    #[cfg(feature = "write")]
     #[test]
-    fn check_response_decode_buffer() {
-        let mut p = ResponseDecoder::new();
-        let buffer = [ESCAPE_CHAR, RES_CRCXF, 0xEF, 0xBE, 0xAD, 0xDE];
-        let callback = |x: &Response| match x {
-            &Response::CrcExtFlash { crc } => {
-                assert_eq!(crc, 0xDEADBEEF);
-            }
-            _ => panic!("Bad command {:?}", x),
-        };
-        match p.read(&buffer, callback) {
-            Ok(_) => {}
-            Err(e) => panic!("Did not expect: {:?}", e),
-        }
+    fn check_command_try_from() {
+        let frame = [ESCAPE_CHAR, CMD_PING];
+        assert_eq!(Command::try_from(&frame[..]), Ok(Command::Ping));
+        let frame = [0xEF, 0xBE, 0xAD, 0xDE, ESCAPE_CHAR, CMD_EPAGE];
+        assert_eq!(
+            Command::try_from(&frame[..]),
+            Ok(Command::ErasePage { address: 0xDEADBEEF })
+        );
+    }
+
+    #[cfg(feature = "write")]
+    #[test]
+    fn check_command_try_from_malformed() {
+        // Address too short
+        let frame = [0xEF, 0xBE, ESCAPE_CHAR, CMD_EPAGE];
+        assert_eq!(Command::try_from(&frame[..]), Err(Error::BadArguments));
+        // No terminator
+        let frame = [0xEF, 0xBE, 0xAD, 0xDE];
+        assert_eq!(Command::try_from(&frame[..]), Err(Error::Incomplete));
+        // Unknown command byte
+        let frame = [ESCAPE_CHAR, 0x99];
+        assert_eq!(Command::try_from(&frame[..]), Err(Error::UnknownCommand));
     }
 
 }
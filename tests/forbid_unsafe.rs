@@ -0,0 +1,17 @@
+//! The crate promises to contain no `unsafe` code. `#![forbid(unsafe_code)]`
+//! enforces that at build time, so make sure nobody quietly drops it.
+
+const LIB_RS: &str = include_str!("../src/lib.rs");
+
+#[test]
+fn unsafe_code_is_forbidden() {
+    // Only the inner attributes at the top of the file count - the lint
+    // means nothing if it's in a comment or on a single item.
+    let forbidden = LIB_RS
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .take_while(|line| line.starts_with("#!["))
+        .any(|line| line == "#![forbid(unsafe_code)]");
+    assert!(forbidden, "src/lib.rs must start with #![forbid(unsafe_code)]");
+}